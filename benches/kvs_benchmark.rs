@@ -1,9 +1,9 @@
 use core::time;
 use std::{sync::{atomic::{AtomicU32, Ordering}, Arc}, thread};
 
-use criterion::{Criterion, criterion_group, criterion_main, BenchmarkId};
+use criterion::{Criterion, criterion_group, criterion_main, BenchmarkId, Throughput};
 use crossbeam::channel::unbounded;
-use kvs::{KvStore, SledKvsEngine, KvsEngine, thread_pool::{SharedQueueThreadPool, ThreadPool}, server::KvsServer, client::KvsClient};
+use kvs::{KvStore, KvStoreOptions, ReadCachePolicy, SyncPolicy, SledKvsEngine, MemoryKvsEngine, KvsEngine, thread_pool::{SharedQueueThreadPool, ThreadPool}, server::KvsServer, client::KvsClient};
 use rand::prelude::*;
 use tempfile::TempDir;
 use rand_chacha::ChaCha8Rng;
@@ -14,7 +14,7 @@ static MAX_STR_LEN: usize = 1000;
 static READ_LEN: usize = 10000;
 
 fn get_inputs(rng: &mut ChaCha8Rng) -> Vec<String> {
-    let inputs = (0..INPUT_LEN)
+    (0..INPUT_LEN)
         .map(|_| {
             let str_len = rng.gen_range(1..=MAX_STR_LEN);
             let rand_str: String = (0..str_len)
@@ -22,8 +22,7 @@ fn get_inputs(rng: &mut ChaCha8Rng) -> Vec<String> {
                 .collect();
             rand_str
         })
-        .collect();
-    inputs
+        .collect()
 }
 
 
@@ -38,12 +37,12 @@ fn kvs_bench(c: &mut Criterion) {
     let values = get_inputs(&mut rng);
     let key_values: Vec<(String, String)> = keys.clone().into_iter().zip(values).collect();
     let cur_dir = TempDir::new().unwrap();
-    let kvs_store = KvStore::open(&cur_dir.path()).expect(&format!("can not open {:?} with KvStore", cur_dir));
+    let kvs_store = KvStore::open(cur_dir.path()).unwrap_or_else(|_| panic!("can not open {:?} with KvStore", cur_dir));
     let mut group = c.benchmark_group("KvStoreBench");
     group.bench_with_input(BenchmarkId::new("kvs_write", 100), &key_values, |b, kvs| {
         b.iter(|| {
-            kvs.into_iter().for_each(
-                |(k, v)| kvs_store.set(k.clone(), v.clone()).expect(&format!("failed to write ({}, {}) to sled", k, v))
+            kvs.iter().for_each(
+                |(k, v)| kvs_store.set(k.clone(), v.clone()).unwrap_or_else(|_| panic!("failed to write ({}, {}) to sled", k, v))
             )
         });
     });
@@ -54,12 +53,33 @@ fn kvs_bench(c: &mut Criterion) {
 
     group.bench_with_input(BenchmarkId::new("kvs_read", 10000), &read_keys, |b, keys| {
         b.iter(|| {
-            keys.into_iter().for_each(
+            keys.iter().for_each(
                 |k| {
                     kvs_store
                         .get(k.clone())
-                        .expect(&format!("failed to read some key from KvStore"))
-                        .expect(&format!("the value of some key in KvStore is empty"));
+                        .expect("failed to read some key from KvStore")
+                        .expect("the value of some key in KvStore is empty");
+                }
+            )
+        });
+    });
+
+    // the same reads, served from the read cache once warm.
+    let cached_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions::new().read_cache(ReadCachePolicy::Lru(64 * 1024 * 1024));
+    let cached_store = KvStore::open_with_options(cached_dir.path(), options)
+        .unwrap_or_else(|_| panic!("can not open {:?} with KvStore", cached_dir));
+    for (k, v) in key_values.iter() {
+        cached_store.set(k.clone(), v.clone()).unwrap_or_else(|_| panic!("failed to write ({}, {}) to KvStore", k, v));
+    }
+    group.bench_with_input(BenchmarkId::new("kvs_read_cached", 10000), &read_keys, |b, keys| {
+        b.iter(|| {
+            keys.iter().for_each(
+                |k| {
+                    cached_store
+                        .get(k.clone())
+                        .expect("failed to read some key from KvStore")
+                        .expect("the value of some key in KvStore is empty");
                 }
             )
         });
@@ -74,12 +94,12 @@ fn sled_bench(c: &mut Criterion) {
     let values = get_inputs(&mut rng);
     let key_values: Vec<(String, String)> = keys.clone().into_iter().zip(values).collect();
     let cur_dir = TempDir::new().unwrap();
-    let sled_store = SledKvsEngine::open(&cur_dir.path()).expect(&format!("can not open {:?} with sled store", cur_dir));
+    let sled_store = SledKvsEngine::open(cur_dir.path()).unwrap_or_else(|_| panic!("can not open {:?} with sled store", cur_dir));
     let mut group = c.benchmark_group("SledStoreBench");
     group.bench_with_input(BenchmarkId::new("sled_write", 100), &key_values, |b, kvs| {
         b.iter(|| {
-            kvs.into_iter().for_each(
-                |(k, v)| sled_store.set(k.clone(), v.clone()).expect(&format!("failed to write ({}, {}) to sled", k, v))
+            kvs.iter().for_each(
+                |(k, v)| sled_store.set(k.clone(), v.clone()).unwrap_or_else(|_| panic!("failed to write ({}, {}) to sled", k, v))
             )
         });
     });
@@ -90,12 +110,12 @@ fn sled_bench(c: &mut Criterion) {
 
     group.bench_with_input(BenchmarkId::new("sled_read", 10000), &read_keys, |b, keys| {
         b.iter(|| {
-            keys.into_iter().for_each(
+            keys.iter().for_each(
                 |k| {
                     sled_store
                         .get(k.clone())
-                        .expect(&format!("failed to read some key from sled"))
-                        .expect(&format!("the value of some key in sled is empty"));
+                        .expect("failed to read some key from sled")
+                        .expect("the value of some key in sled is empty");
                 }
             )
         });
@@ -104,6 +124,41 @@ fn sled_bench(c: &mut Criterion) {
 }
 
 
+// the in-memory engine, as a baseline for the ones on disk.
+fn memory_bench(c: &mut Criterion) {
+    let mut rng = ChaCha8Rng::seed_from_u64(RNG_SEED);
+    let keys = get_inputs(&mut rng);
+    let values = get_inputs(&mut rng);
+    let key_values: Vec<(String, String)> = keys.clone().into_iter().zip(values).collect();
+    let memory_store = MemoryKvsEngine::new();
+    let mut group = c.benchmark_group("MemoryStoreBench");
+    group.bench_with_input(BenchmarkId::new("memory_write", 100), &key_values, |b, kvs| {
+        b.iter(|| {
+            kvs.iter().for_each(
+                |(k, v)| memory_store.set(k.clone(), v.clone()).unwrap_or_else(|_| panic!("failed to write ({}, {}) to memory", k, v))
+            )
+        });
+    });
+
+    let read_keys: Vec<String> = (0..READ_LEN)
+        .map(|_| keys[rng.gen_range(0..INPUT_LEN)].clone())
+        .collect();
+
+    group.bench_with_input(BenchmarkId::new("memory_read", 10000), &read_keys, |b, keys| {
+        b.iter(|| {
+            keys.iter().for_each(
+                |k| {
+                    memory_store
+                        .get(k.clone())
+                        .expect("failed to read some key from memory")
+                        .expect("the value of some key in memory is empty");
+                }
+            )
+        });
+    });
+    group.finish();
+}
+
 enum CliMessage {
     Normal(String, String),
     Stop(Arc<AtomicU32>),
@@ -192,5 +247,40 @@ fn write_queued_kvstore(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, kvs_bench, sled_bench, write_queued_kvstore);
+fn write_concurrent_kvstore(c: &mut Criterion) {
+    let keys_to_write = Arc::new(get_multithread_inputs());
+    let thread_nums: Vec<usize> = vec![1, 2, 4, 8, 16, 32];
+    let mut group = c.benchmark_group("GroupCommitKvsStore");
+    group.throughput(Throughput::Elements(keys_to_write.len() as u64));
+    for thread_num in thread_nums {
+        group.bench_with_input(
+            BenchmarkId::new("synced_kvs_write", format!("threads({})", thread_num)),
+            &thread_num,
+            |b, &threads| {
+                let tmp_dir = TempDir::new().unwrap();
+                let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite);
+                let engine = KvStore::open_with_options(tmp_dir.path(), options).unwrap();
+                b.iter(|| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|thread_id| {
+                            let engine = engine.clone();
+                            let keys = keys_to_write.clone();
+                            thread::spawn(move || {
+                                for key in keys.iter().skip(thread_id).step_by(threads) {
+                                    engine.set(key.clone(), "test".to_owned()).unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                });
+            }
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, kvs_bench, sled_bench, memory_bench, write_queued_kvstore, write_concurrent_kvstore);
 criterion_main!(benches);
\ No newline at end of file
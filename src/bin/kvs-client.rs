@@ -1,6 +1,11 @@
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::process;
+use std::time::Duration;
+
 use clap::Parser;
 use kvs::{client::KvsClient, Result, Command};
-use {slog, slog::{Drain, o, info}, slog_term};
+use slog::{Drain, o, info};
 
 
 #[derive(Parser)]
@@ -22,28 +27,96 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Command::Set { key, value, addr } => {
+        Command::Set { key, value, ttl, addr } => {
             let mut kvs_cli = KvsClient::connect(addr)?;
-            kvs_cli.set(key.clone(), value.clone())?;
+            match ttl {
+                Some(ttl) => kvs_cli.set_with_ttl(key.clone(), value.clone(), Duration::from_secs(*ttl))?,
+                None => kvs_cli.set(key.clone(), value.clone())?,
+            }
             info!(root, "successfully set {key} with {value} in kvs-store proxied via server at {addr}", key=key, value=value, addr=addr);
         }
-        Command::Get { key, addr } => {
+        Command::Get { key, at, addr } => {
             let mut kvs_cli = KvsClient::connect(addr)?;
-            match kvs_cli.get(key.clone())? {
+            let value = match at {
+                Some(seq) => kvs_cli.get_at_bytes(key.clone().into_bytes(), *seq)?,
+                None => kvs_cli.get_bytes(key.clone().into_bytes())?,
+            };
+            match value {
                 Some(value) => {
-                    println!("{value}");
-                    info!(root, "successfully get {value} from {key} in kvs-store proxied via server at {addr}", key=key, value=&value, addr=addr);
+                    // values are written out as is, they need not be UTF-8.
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
+                    info!(root, "successfully get {len} bytes from {key} in kvs-store proxied via server at {addr}", key=key, len=value.len(), addr=addr);
                 },
                 None => {
                     println!("Key not found for {key}");
                 },
             };
         }
+        Command::Ttl { key, addr } => {
+            let mut kvs_cli = KvsClient::connect(addr)?;
+            match kvs_cli.ttl(key.clone())? {
+                // rounded up, so that a key about to expire does not show 0.
+                Some(ttl) => println!("{}", ttl.as_millis().div_ceil(1000)),
+                None => println!("No expiry for {key}"),
+            }
+            info!(root, "successfully get ttl of {key} in kvs-store proxied via server at {addr}", key=key, addr=addr);
+        }
+        Command::History { key, addr } => {
+            let mut kvs_cli = KvsClient::connect(addr)?;
+            let versions = kvs_cli.history_bytes(key.clone().into_bytes())?;
+            let mut stdout = io::stdout().lock();
+            for (seq, value) in &versions {
+                write!(stdout, "{}", seq)?;
+                if let Some(value) = value {
+                    stdout.write_all(b"\t")?;
+                    stdout.write_all(value)?;
+                }
+                stdout.write_all(b"\n")?;
+            }
+            info!(root, "successfully get {count} versions of {key} in kvs-store proxied via server at {addr}", count=versions.len(), key=key, addr=addr);
+        }
         Command::Rm { key, addr } => {
             let mut kvs_cli = KvsClient::connect(addr)?;
             kvs_cli.remove(key.clone())?;
             info!(root, "successfully remove {key} in kvs-store proxied via server at {addr}", key=key, addr=addr);
         }
+        Command::Scan { start, end, prefix, limit, addr } => {
+            let mut kvs_cli = KvsClient::connect(addr)?;
+            let pairs = match prefix {
+                Some(prefix) => kvs_cli.scan_prefix_bytes(prefix.clone().into_bytes(), *limit)?,
+                None => {
+                    let start = start.clone().map_or(Bound::Unbounded, |key| Bound::Included(key.into_bytes()));
+                    let end = end.clone().map_or(Bound::Unbounded, |key| Bound::Excluded(key.into_bytes()));
+                    kvs_cli.scan_bytes((start, end), *limit)?
+                }
+            };
+            let mut stdout = io::stdout().lock();
+            let mut count = 0;
+            for pair in pairs {
+                let (key, value) = pair?;
+                stdout.write_all(&key)?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
+                count += 1;
+            }
+            info!(root, "successfully scan {count} pairs in kvs-store proxied via server at {addr}", count=count, addr=addr);
+        }
+        Command::Cas { key, expected, new, addr } => {
+            let mut kvs_cli = KvsClient::connect(addr)?;
+            if !kvs_cli.compare_and_swap(key.clone(), expected.clone(), new.clone())? {
+                eprintln!("Value of {key} is not the expected one");
+                process::exit(1);
+            }
+            info!(root, "successfully swap {key} in kvs-store proxied via server at {addr}", key=key, addr=addr);
+        }
+        Command::Compact { addr } => {
+            let mut kvs_cli = KvsClient::connect(addr)?;
+            kvs_cli.compact()?;
+            info!(root, "successfully compact kvs-store proxied via server at {addr}", addr=addr);
+        }
     };
 
     Ok(())
@@ -1,9 +1,9 @@
 use clap::{Parser, ValueEnum};
-use kvs::{KvStore, Result, server::KvsServer, KvsError, thread_pool::*, SledKvsEngine};
+use kvs::{Cipher, CompactionPolicy, Compression, EncryptionKey, IndexMode, KvStore, KvStoreOptions, LsmKvsEngine, MemoryKvsEngine, ReadCachePolicy, RecoveryMode, Result, RetentionPolicy, server::KvsServer, KvsError, thread_pool::*, SledKvsEngine, SyncPolicy};
 use std::env::current_dir;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use slog::{Drain, o, info, warn, Logger};
-use slog_term;
-use num_cpus;
 
 
 static ENGINE_FILE: &str = "engine";
@@ -16,6 +16,12 @@ enum Engine {
     
     /// sled
     Sled,
+
+    /// log-structured merge tree
+    Lsm,
+
+    /// in memory only, lost on exit
+    Memory,
 }
 
 
@@ -25,6 +31,8 @@ impl std::str::FromStr for Engine {
         match input {
             "KvStore" => Ok(Engine::Kvs),
             "SledKvsEngine" => Ok(Engine::Sled),
+            "LsmKvsEngine" => Ok(Engine::Lsm),
+            "MemoryKvsEngine" => Ok(Engine::Memory),
             _ => Err(KvsError::StringError(format!("unable to parse {input}"))),
         }
     }
@@ -36,11 +44,103 @@ impl std::fmt::Display for Engine {
         match self {
             Engine::Kvs => write!(f, "KvStore"),
             Engine::Sled => write!(f, "SledKvsEngine"),
+            Engine::Lsm => write!(f, "LsmKvsEngine"),
+            Engine::Memory => write!(f, "MemoryKvsEngine"),
         }
     }
 }
 
 
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum SyncMode {
+    /// leave syncing to the operating system
+    Never,
+
+    /// sync before acknowledging every write
+    EveryWrite,
+
+    /// sync every `--sync-interval-ms` milliseconds
+    Interval,
+
+    /// sync once every `--sync-batch` writes
+    Batch,
+}
+
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum CompactionMode {
+    /// compact once `--compaction-stale-bytes` bytes are stale
+    StaleBytes,
+
+    /// compact once stale bytes exceed `--compaction-stale-ratio` times the live bytes
+    StaleRatio,
+
+    /// compact once there are more than `--compaction-max-logs` log files
+    MaxLogs,
+
+    /// only compact on `kvs-client compact`
+    Disabled,
+}
+
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum IndexKind {
+    /// keep every key in memory
+    Full,
+
+    /// keep at most `--index-max-memory-keys` keys in memory, the rest in sorted index files
+    Bounded,
+}
+
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum CacheKind {
+    /// read every value from the logs
+    Disabled,
+
+    /// cache values, evicting the least recently read first
+    Lru,
+
+    /// cache values, evicting them with a CLOCK sweep
+    Clock,
+}
+
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum RecoveryKind {
+    /// refuse to open a store with a damaged record
+    Strict,
+
+    /// truncate a log right before its damaged record
+    TruncateTail,
+
+    /// skip damaged records, resuming at the next valid one
+    SkipCorrupted,
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum CodecKind {
+    /// store values as they are
+    None,
+
+    /// compress values with LZ4
+    Lz4,
+
+    /// compress values with zstd at `--zstd-level`
+    Zstd,
+}
+
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum CipherKind {
+    /// AES-256-GCM
+    AesGcm,
+
+    /// ChaCha20-Poly1305
+    Chacha20Poly1305,
+}
+
+
 #[derive(Parser)]
 #[command(name=env!("CARGO_PKG_NAME"))]
 #[command(version=env!("CARGO_PKG_VERSION"))]
@@ -51,9 +151,190 @@ struct Cli {
     #[arg(long, default_value_t = String::from("127.0.0.1:4000"))]
     addr: String,
     
-    /// the key value engine to use, supported `kvs`, `sled`, default as `kvs`
+    /// the key value engine to use, supported `kvs`, `sled`, `lsm`, `memory`, default as `kvs`
     #[arg(long, value_enum)]
-    engine: Option<Engine>
+    engine: Option<Engine>,
+
+    /// when the `kvs` engine syncs its log to disk
+    #[arg(long, value_enum, default_value_t = SyncMode::Never)]
+    sync: SyncMode,
+
+    /// milliseconds between syncs with `--sync interval`
+    #[arg(long, default_value_t = 1000)]
+    sync_interval_ms: u64,
+
+    /// number of writes between syncs with `--sync batch`
+    #[arg(long, default_value_t = 128)]
+    sync_batch: usize,
+
+    /// when the `kvs` engine compacts its log on its own
+    #[arg(long, value_enum, default_value_t = CompactionMode::StaleBytes)]
+    compaction: CompactionMode,
+
+    /// stale bytes that trigger a compaction with `--compaction stale-bytes`
+    #[arg(long, default_value_t = 1024 * 1024)]
+    compaction_stale_bytes: u64,
+
+    /// stale to live bytes ratio that triggers a compaction with `--compaction stale-ratio`
+    #[arg(long, default_value_t = 1.0)]
+    compaction_stale_ratio: f64,
+
+    /// number of log files that triggers a compaction with `--compaction max-logs`
+    #[arg(long, default_value_t = 16)]
+    compaction_max_logs: usize,
+
+    /// number of most recent versions of every key the `kvs` engine keeps, only the current one by default
+    #[arg(long, conflicts_with = "keep_versions_secs")]
+    keep_versions: Option<usize>,
+
+    /// seconds for which the `kvs` engine keeps the versions of every key
+    #[arg(long)]
+    keep_versions_secs: Option<u64>,
+
+    /// how the `kvs` engine indexes its keys
+    #[arg(long, value_enum, default_value_t = IndexKind::Full)]
+    index: IndexKind,
+
+    /// number of sorted index entries cached in memory with `--index bounded`
+    #[arg(long, default_value_t = 4096)]
+    index_cache_entries: usize,
+
+    /// number of keys written since the last compaction that trigger one with `--index bounded`
+    #[arg(long, default_value_t = 1 << 20)]
+    index_max_memory_keys: usize,
+
+    /// how the `kvs` engine caches the values it reads
+    #[arg(long, value_enum, default_value_t = CacheKind::Disabled)]
+    read_cache: CacheKind,
+
+    /// bytes of keys and values the read cache holds with `--read-cache lru` or `--read-cache clock`
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    read_cache_bytes: u64,
+
+    /// how the `kvs` engine compresses the values it writes
+    #[arg(long, value_enum, default_value_t = CodecKind::None)]
+    compression: CodecKind,
+
+    /// compression level with `--compression zstd`
+    #[arg(long, default_value_t = 3)]
+    zstd_level: i32,
+
+    /// whether `kvs` engine compactions rewrite the values of other codecs with `--compression`
+    #[arg(long)]
+    recompress: bool,
+
+    /// file of the key the `kvs` engine encrypts values with: 32 bytes, or 64 hex digits
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+
+    /// file of a key values written before a rotation are read with, may be repeated
+    #[arg(long)]
+    old_key_file: Vec<PathBuf>,
+
+    /// cipher of the keys of `--key-file` and `--old-key-file`
+    #[arg(long, value_enum, default_value_t = CipherKind::AesGcm)]
+    cipher: CipherKind,
+
+    /// length in bytes from which the `kvs` engine stores values in value logs apart from their keys
+    #[arg(long)]
+    value_separation: Option<usize>,
+
+    /// what the `kvs` engine does with damaged records found on open
+    #[arg(long, value_enum, default_value_t = RecoveryKind::Strict)]
+    recovery_mode: RecoveryKind,
+
+    /// bytes of keys and values past which the `memory` engine evicts the least recently used keys, unbounded by default
+    #[arg(long)]
+    memory_max_bytes: Option<u64>,
+}
+
+
+impl Cli {
+    fn sync_policy(&self) -> SyncPolicy {
+        match self.sync {
+            SyncMode::Never => SyncPolicy::Never,
+            SyncMode::EveryWrite => SyncPolicy::EveryWrite,
+            SyncMode::Interval => SyncPolicy::Interval(Duration::from_millis(self.sync_interval_ms)),
+            SyncMode::Batch => SyncPolicy::Batch(self.sync_batch),
+        }
+    }
+
+    fn compaction_policy(&self) -> CompactionPolicy {
+        match self.compaction {
+            CompactionMode::StaleBytes => CompactionPolicy::StaleBytes(self.compaction_stale_bytes),
+            CompactionMode::StaleRatio => CompactionPolicy::StaleRatio(self.compaction_stale_ratio),
+            CompactionMode::MaxLogs => CompactionPolicy::MaxGenerations(self.compaction_max_logs),
+            CompactionMode::Disabled => CompactionPolicy::Disabled,
+        }
+    }
+
+    fn retention(&self) -> RetentionPolicy {
+        match (self.keep_versions, self.keep_versions_secs) {
+            (Some(versions), _) => RetentionPolicy::LastVersions(versions),
+            (None, Some(secs)) => RetentionPolicy::NewerThan(Duration::from_secs(secs)),
+            (None, None) => RetentionPolicy::Latest,
+        }
+    }
+
+    fn index_mode(&self) -> IndexMode {
+        match self.index {
+            IndexKind::Full => IndexMode::Full,
+            IndexKind::Bounded => IndexMode::Bounded {
+                cache_entries: self.index_cache_entries,
+                max_memory_keys: self.index_max_memory_keys,
+            },
+        }
+    }
+
+    fn read_cache_policy(&self) -> ReadCachePolicy {
+        match self.read_cache {
+            CacheKind::Disabled => ReadCachePolicy::Disabled,
+            CacheKind::Lru => ReadCachePolicy::Lru(self.read_cache_bytes),
+            CacheKind::Clock => ReadCachePolicy::Clock(self.read_cache_bytes),
+        }
+    }
+
+    fn compression(&self) -> Compression {
+        match self.compression {
+            CodecKind::None => Compression::None,
+            CodecKind::Lz4 => Compression::Lz4,
+            CodecKind::Zstd => Compression::Zstd(self.zstd_level),
+        }
+    }
+
+    fn recovery_mode(&self) -> RecoveryMode {
+        match self.recovery_mode {
+            RecoveryKind::Strict => RecoveryMode::Strict,
+            RecoveryKind::TruncateTail => RecoveryMode::TruncateTail,
+            RecoveryKind::SkipCorrupted => RecoveryMode::SkipCorrupted,
+        }
+    }
+
+    fn kvs_options(&self) -> Result<KvStoreOptions> {
+        let cipher = match self.cipher {
+            CipherKind::AesGcm => Cipher::Aes256Gcm,
+            CipherKind::Chacha20Poly1305 => Cipher::ChaCha20Poly1305,
+        };
+        let mut options = KvStoreOptions::new()
+            .sync_policy(self.sync_policy())
+            .compaction_policy(self.compaction_policy())
+            .retention(self.retention())
+            .index_mode(self.index_mode())
+            .read_cache(self.read_cache_policy())
+            .compression(self.compression())
+            .recompress(self.recompress)
+            .recovery_mode(self.recovery_mode());
+        if let Some(path) = &self.key_file {
+            options = options.encryption_key(read_key_file(cipher, path)?);
+        }
+        for path in &self.old_key_file {
+            options = options.old_key(read_key_file(cipher, path)?);
+        }
+        if let Some(min_len) = self.value_separation {
+            options = options.value_separation(min_len);
+        }
+        Ok(options)
+    }
 }
 
 
@@ -65,7 +346,9 @@ fn main() -> Result<()> {
     let mut cli = Cli::parse();
 
     info!(root_log, "starting kvs server...");
-    if let Some(engine) = current_engine(&root_log)? {
+    // the `memory` engine keeps nothing in the directory, so it may run in any.
+    let on_disk = cli.engine != Some(Engine::Memory);
+    if let Some(engine) = current_engine(&root_log)?.filter(|_| on_disk) {
         if cli.engine.is_none() {
             cli.engine = Some(engine.clone());
         } 
@@ -77,8 +360,10 @@ fn main() -> Result<()> {
         }
     }
     
-    let engine = cli.engine.unwrap_or(Engine::Kvs);
-    std::fs::write(current_dir()?.join(ENGINE_FILE), format!("{}", engine))?;
+    let engine = cli.engine.clone().unwrap_or(Engine::Kvs);
+    if on_disk {
+        std::fs::write(current_dir()?.join(ENGINE_FILE), format!("{}", engine))?;
+    }
 
     let server_log = root_log.new(
         o!("addr" => cli.addr.clone(), "engine" => engine.to_string())
@@ -88,13 +373,30 @@ fn main() -> Result<()> {
     let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
 
     match engine {
-        Engine::Kvs => KvsServer::new(
-            KvStore::open(current_dir()?)?,
-            pool
-        ).run(&cli.addr),
+        Engine::Kvs => {
+            let store = KvStore::open_with_options(current_dir()?, cli.kvs_options()?)?;
+            for (gen, ranges) in &store.recovery_report().skipped {
+                for range in ranges {
+                    warn!(server_log, "left out bytes {start} to {end} of log {gen} on open",
+                        start = range.start, end = range.end, gen = gen);
+                }
+            }
+            KvsServer::new(store, pool).run(&cli.addr)
+        }
         Engine::Sled => KvsServer::new(
             SledKvsEngine::open(current_dir()?)?,
             pool
+        ).run(&cli.addr),
+        Engine::Lsm => KvsServer::new(
+            LsmKvsEngine::open(current_dir()?)?,
+            pool
+        ).run(&cli.addr),
+        Engine::Memory => KvsServer::new(
+            match cli.memory_max_bytes {
+                Some(max_bytes) => MemoryKvsEngine::with_max_bytes(max_bytes),
+                None => MemoryKvsEngine::new(),
+            },
+            pool
         ).run(&cli.addr)
     }
 }
@@ -113,4 +415,19 @@ fn current_engine(log: &Logger) -> Result<Option<Engine>> {
             Ok(None)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Reads a key for `cipher` from the file at `path`, which holds either the 32
+/// bytes of the key or their 64 hex digits.
+fn read_key_file(cipher: Cipher, path: &Path) -> Result<EncryptionKey> {
+    let bytes = std::fs::read(path)?;
+    let invalid = || KvsError::StringError(format!("{} holds neither 32 bytes nor 64 hex digits", path.display()));
+    let key = match std::str::from_utf8(&bytes).map(str::trim) {
+        Ok(hex) if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => (0..32)
+            .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?,
+        _ => bytes,
+    };
+    Ok(EncryptionKey::new(cipher, key.try_into().map_err(|_| invalid())?))
+}
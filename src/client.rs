@@ -1,17 +1,23 @@
-use std::io::Write;
+use std::io::{BufReader, BufWriter};
 use std::net::{TcpStream,ToSocketAddrs};
-use serde::Deserialize;
-use serde_json::{Deserializer, de::IoRead};
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+use std::vec;
+use serde::de::DeserializeOwned;
 use crate::protocols::*;
 use crate::Result;
 use crate::KvsError;
-use std::io::BufWriter;
+use crate::{ScanBytesIter, ScanIter, WriteBatch};
+use crate::engines::TransactionLog;
 
 
 /// kvs client to connect to kvs server and request commands as get, set, rm, etc.
+///
+/// Keys and values are bytes on the wire; the `String` methods are wrappers over
+/// the byte ones.
 pub struct KvsClient {
     writer: BufWriter<TcpStream>,
-    reader: Deserializer<IoRead<TcpStream>>
+    reader: BufReader<TcpStream>
 }
 
 impl KvsClient {
@@ -20,48 +26,348 @@ impl KvsClient {
         let write_connection = TcpStream::connect(addr)?;
         let read_connection = write_connection.try_clone()?;
         let writer = BufWriter::new( write_connection);
-        let reader = Deserializer::from_reader(read_connection);
+        let reader = BufReader::new(read_connection);
         Ok(
             KvsClient { writer, reader }
         )
     }
-    
+
     /// set key-value pair to kvs-store via kvs-server
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Request::Set { key, value };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
-        
-        let response = SetResponse::deserialize(&mut self.reader)?;
-        match response {
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.request(&Request::Set { key, value })? {
             SetResponse::Ok(_) => Ok(()),
             SetResponse::Err(err) => Err(KvsError::StringError(err)),
         }
     }
 
     /// get value of key from kvs-store via kvs-server
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get { key };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
-        
-        let response = GetResponse::deserialize(&mut self.reader)?;
-        match response {
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get { key })? {
             GetResponse::Ok(result) => Ok(result),
             GetResponse::Err(err) => Err(KvsError::StringError(err)),
         }
     }
 
     /// remove key in kvs-store via kvs-server
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let request = Request::Remove { key };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
-        
-        let response = RemoveResponse::deserialize(&mut self.reader)?;
-        match response {
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.request(&Request::Remove { key })? {
             RemoveResponse::Ok(_) => Ok(()),
             RemoveResponse::Err(err) => Err(KvsError::StringError(err)),
         }
     }
-}
\ No newline at end of file
+
+    /// set key-value pair expiring after ttl to kvs-store via kvs-server
+    pub fn set_with_ttl_bytes(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        match self.request(&Request::SetWithTtl { key, value, ttl })? {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(KvsError::StringError(err)),
+        }
+    }
+
+    /// get time left before key expires from kvs-store via kvs-server, `None` if it does not expire
+    pub fn ttl_bytes(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.request(&Request::Ttl { key })? {
+            TtlResponse::Ok(ttl) => Ok(ttl),
+            TtlResponse::Err(err) => Err(KvsError::StringError(err)),
+        }
+    }
+
+    /// get value key had right after the write with sequence number seq from kvs-store via kvs-server
+    pub fn get_at_bytes(&mut self, key: Vec<u8>, seq: u64) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::GetAt { key, seq })? {
+            GetResponse::Ok(result) => Ok(result),
+            GetResponse::Err(err) => Err(KvsError::StringError(err)),
+        }
+    }
+
+    /// get kept versions of key from kvs-store via kvs-server, oldest first, as the sequence
+    /// number of each write and the value it set, `None` for a removal
+    pub fn history_bytes(&mut self, key: Vec<u8>) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        match self.request(&Request::History { key })? {
+            HistoryResponse::Ok(versions) => Ok(versions),
+            HistoryResponse::Err(err) => Err(KvsError::StringError(err)),
+        }
+    }
+
+    /// apply all the writes of batch to kvs-store via kvs-server, or none of them
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        match self.request(&Request::Batch { batch })? {
+            BatchResponse::Ok(_) => Ok(()),
+            BatchResponse::Err(err) => Err(KvsError::StringError(err)),
+        }
+    }
+
+    /// set key to new value, or remove it if new is `None`, in kvs-store via kvs-server,
+    /// only if its current value is expected, `None` meaning absent; returns whether it did
+    pub fn compare_and_swap_bytes(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        match self.request(&Request::CompareAndSwap { key, expected, new })? {
+            CompareAndSwapResponse::Ok(swapped) => Ok(swapped),
+            CompareAndSwapResponse::Err(err) => Err(KvsError::StringError(err)),
+        }
+    }
+
+    /// set key-value pair to kvs-store via kvs-server only if key does not exist; returns whether it did
+    pub fn set_if_absent_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// remove key in kvs-store via kvs-server only if its value is expected; returns whether it did
+    pub fn remove_if_equals_bytes(&mut self, key: Vec<u8>, expected: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, Some(expected), None)
+    }
+
+    /// begin an optimistic transaction in kvs-store via kvs-server, see `Transaction`
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction { client: self, log: TransactionLog::default() }
+    }
+
+    /// compact kvs-store via kvs-server
+    pub fn compact(&mut self) -> Result<()> {
+        match self.request(&Request::Compact)? {
+            CompactResponse::Ok(_) => Ok(()),
+            CompactResponse::Err(err) => Err(KvsError::StringError(err)),
+        }
+    }
+
+    /// scan the key/value pairs with keys in range from kvs-store via kvs-server, in key order
+    ///
+    /// The server answers a page at a time; the next page is only asked for once the
+    /// pairs of the current one have been taken.
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(&mut self, range: R, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let request = Request::Scan {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            limit,
+        };
+        Ok(Box::new(ScanPages::new(self, request)?))
+    }
+
+    /// scan the key/value pairs with keys starting with prefix from kvs-store via kvs-server, in key order
+    pub fn scan_prefix_bytes(&mut self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        Ok(Box::new(ScanPages::new(self, Request::ScanPrefix { prefix, limit })?))
+    }
+
+    /// set string key-value pair to kvs-store via kvs-server
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// get string value of string key from kvs-store via kvs-server
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// remove string key in kvs-store via kvs-server
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// set string key-value pair expiring after ttl to kvs-store via kvs-server
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// get time left before string key expires from kvs-store via kvs-server, `None` if it does not expire
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    /// get string value string key had right after the write with sequence number seq from
+    /// kvs-store via kvs-server
+    pub fn get_at(&mut self, key: String, seq: u64) -> Result<Option<String>> {
+        match self.get_at_bytes(key.into_bytes(), seq)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// get kept versions of string key from kvs-store via kvs-server, oldest first
+    pub fn history(&mut self, key: String) -> Result<Vec<(u64, Option<String>)>> {
+        self.history_bytes(key.into_bytes())?
+            .into_iter()
+            .map(|(seq, value)| Ok((seq, value.map(String::from_utf8).transpose()?)))
+            .collect()
+    }
+
+    /// set string key to new string value, or remove it if new is `None`, in kvs-store via
+    /// kvs-server, only if its current value is expected; returns whether it did
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        self.compare_and_swap_bytes(key.into_bytes(), expected.map(String::into_bytes), new.map(String::into_bytes))
+    }
+
+    /// set string key-value pair to kvs-store via kvs-server only if key does not exist; returns whether it did
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// remove string key in kvs-store via kvs-server only if its value is expected; returns whether it did
+    pub fn remove_if_equals(&mut self, key: String, expected: String) -> Result<bool> {
+        self.remove_if_equals_bytes(key.into_bytes(), expected.into_bytes())
+    }
+
+    /// scan the string key/value pairs with keys in range from kvs-store via kvs-server, in key order
+    pub fn scan<R: RangeBounds<String>>(&mut self, range: R, limit: Option<usize>) -> Result<ScanIter<'_>> {
+        let to_bytes = |bound: Bound<&String>| bound.map(|key| key.as_bytes().to_vec());
+        let range = (to_bytes(range.start_bound()), to_bytes(range.end_bound()));
+        Ok(into_strings(self.scan_bytes(range, limit)?))
+    }
+
+    /// scan the string key/value pairs with keys starting with prefix from kvs-store via kvs-server, in key order
+    pub fn scan_prefix(&mut self, prefix: String, limit: Option<usize>) -> Result<ScanIter<'_>> {
+        Ok(into_strings(self.scan_prefix_bytes(prefix.into_bytes(), limit)?))
+    }
+
+    fn request<T: DeserializeOwned>(&mut self, request: &Request) -> Result<T> {
+        write_message(&mut self.writer, request)?;
+        read_message(&mut self.reader)?
+            .ok_or_else(|| KvsError::StringError("connection closed by the server".to_owned()))
+    }
+}
+
+/// An optimistic transaction through a `KvsClient`.
+///
+/// Reads are sent to the server right away and remember the version of what
+/// they read; writes are kept in the transaction until `commit`, which the server
+/// applies only if none of the keys read has changed in the meantime, like
+/// `kvs::Transaction`. The server keeps no state for the transaction.
+pub struct Transaction<'a> {
+    client: &'a mut KvsClient,
+    log: TransactionLog,
+}
+
+impl Transaction<'_> {
+    /// get value of key as the transaction sees it
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.log.written(&key) {
+            return Ok(value);
+        }
+        let versioned = match self.client.request(&Request::GetVersioned { key: key.clone() })? {
+            GetVersionedResponse::Ok(versioned) => versioned,
+            GetVersionedResponse::Err(err) => return Err(KvsError::StringError(err)),
+        };
+        Ok(self.log.read(key, versioned))
+    }
+
+    /// set key-value pair when the transaction commits
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.log.set(key, value);
+    }
+
+    /// remove key when the transaction commits, doing nothing if it does not exist
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.log.remove(key);
+    }
+
+    /// get string value of string key as the transaction sees it
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// set string key-value pair when the transaction commits
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// remove string key when the transaction commits
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// apply the writes of the transaction if none of the keys it read has changed,
+    /// failing with `KvsError::TransactionConflict` otherwise
+    pub fn commit(self) -> Result<()> {
+        let (reads, batch) = self.log.into_parts();
+        match self.client.request(&Request::Commit { reads, batch })? {
+            CommitResponse::Ok(_) => Ok(()),
+            CommitResponse::Conflict => Err(KvsError::TransactionConflict),
+            CommitResponse::Err(err) => Err(KvsError::StringError(err)),
+        }
+    }
+}
+
+/// The pairs of a scan through a `KvsClient`, asking the server for the rest of the
+/// scan whenever a page runs out.
+struct ScanPages<'a> {
+    client: &'a mut KvsClient,
+    page: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    /// request for the pairs after `page`, `None` once the server has sent them all
+    rest: Option<Request>,
+}
+
+impl<'a> ScanPages<'a> {
+    /// Sends `request` and holds on to its first page.
+    fn new(client: &'a mut KvsClient, request: Request) -> Result<Self> {
+        let mut pages = ScanPages { client, page: Vec::new().into_iter(), rest: Some(request) };
+        pages.next_page()?;
+        Ok(pages)
+    }
+
+    /// Asks for the page after the current one, and makes the request for the one after it.
+    fn next_page(&mut self) -> Result<()> {
+        let request = match self.rest.take() {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        let (page, more) = match self.client.request(&request)? {
+            ScanResponse::Ok(page, more) => (page, more),
+            ScanResponse::Err(err) => return Err(KvsError::StringError(err)),
+        };
+        let page_len = page.len();
+        if let (true, Some((last_key, _))) = (more, page.last()) {
+            let start = Bound::Excluded(last_key.clone());
+            self.rest = Some(match request {
+                Request::Scan { end, limit, .. } => Request::Scan { start, end, limit: limit.map(|limit| limit - page_len) },
+                Request::ScanPrefix { prefix, limit } => Request::Scan {
+                    start,
+                    end: prefix_end(&prefix),
+                    limit: limit.map(|limit| limit - page_len),
+                },
+                _ => unreachable!("only scans are paged"),
+            });
+        }
+        self.page = page.into_iter();
+        Ok(())
+    }
+}
+
+impl Iterator for ScanPages<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pair) = self.page.next() {
+                return Some(Ok(pair));
+            }
+            self.rest.as_ref()?;
+            if let Err(err) = self.next_page() {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+fn into_strings(pairs: ScanBytesIter<'_>) -> ScanIter<'_> {
+    Box::new(pairs.map(|pair| {
+        let (key, value) = pair?;
+        Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+    }))
+}
+
+/// The end bound of the keys starting with `prefix`: the least key past all of them,
+/// if there is one.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    match prefix.iter().rposition(|&byte| byte != 0xff) {
+        Some(last) => {
+            let mut end = prefix[..=last].to_vec();
+            end[last] += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    }
+}
@@ -1,27 +1,272 @@
+mod dir;
 mod kv;
+mod lsm;
+mod memory;
 mod sled_engine;
+mod transaction;
+mod write_batch;
 
-use crate::Result;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
 
-pub use kv::KvStore;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+pub use kv::{
+    Cipher, CompactionPolicy, Compression, EncryptionKey, IndexMode, KvStore, KvStoreOptions, KvStoreStats, ReadCachePolicy, RecoveryMode, RecoveryReport, RetentionPolicy, Snapshot,
+    SyncPolicy, ValueLogGcPolicy,
+};
 pub use kv::Command;
+pub use lsm::{LsmKvsEngine, LsmOptions};
+pub use memory::MemoryKvsEngine;
 pub use sled_engine::SledKvsEngine;
+pub use transaction::Transaction;
+pub(crate) use transaction::TransactionLog;
+pub use write_batch::WriteBatch;
+pub(crate) use write_batch::BatchOp;
+
+/// Key/value pairs returned by a scan, in key order.
+pub type ScanIter<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
 
+/// Binary key/value pairs returned by a scan, in key order.
+pub type ScanBytesIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Identifies the write that set the current value of a key, so that an optimistic
+/// transaction can tell whether the key changed since it read it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Version {
+    gen: u64,
+    pos: u64,
+}
+
+/// The keys a transaction read and the versions it read them at, `None` for the
+/// keys that were absent.
+pub type ReadVersions = Vec<(Vec<u8>, Option<Version>)>;
 
 /// trait for general kv store engine
+///
+/// Keys and values are arbitrary bytes. The `String` methods are wrappers over the
+/// byte ones, which fail with `KvsError::FromUtf8Error` on values that are not UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+
+    /// Removes a given key.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+
+    /// Sets the value of a key, which expires after `ttl` and then reads as absent.
+    ///
+    /// Engines without expiry return `KvsError::Unsupported`.
+    fn set_with_ttl_bytes(&self, _key: Vec<u8>, _value: Vec<u8>, _ttl: Duration) -> Result<()> {
+        Err(KvsError::Unsupported("expiry".to_owned()))
+    }
+
+
+    /// Returns how long a key has left before it expires, `None` if it does not.
+    ///
+    /// Engines without expiry return `KvsError::Unsupported`.
+    fn ttl_bytes(&self, _key: Vec<u8>) -> Result<Option<Duration>> {
+        Err(KvsError::Unsupported("expiry".to_owned()))
+    }
+
+
+    /// Applies all the writes of `batch`, or none of them if it fails.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, provided its current
+    /// value is `expected`, `None` meaning absent.
+    ///
+    /// Returns whether the swap happened. No other write to the key can slip in
+    /// between the comparison and the swap.
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool>;
+
+
+    /// Sets the value of a key only if the key does not exist.
+    ///
+    /// Returns whether the value was set.
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+
+    /// Removes a key only if its value is `expected`.
+    ///
+    /// Returns whether the key was removed.
+    fn remove_if_equals_bytes(&self, key: Vec<u8>, expected: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, Some(expected), None)
+    }
+
+
+    /// Reclaims the space taken by overwritten and removed values.
+    fn compact(&self) -> Result<()>;
+
+
+    /// Gets the value of a given key together with its version.
+    ///
+    /// Engines without versions return `KvsError::Unsupported`.
+    fn get_versioned(&self, _key: Vec<u8>) -> Result<Option<(Vec<u8>, Version)>> {
+        Err(KvsError::Unsupported("transactions".to_owned()))
+    }
+
+
+    /// Applies all the writes of `batch` if every key of `reads` is still at the
+    /// version it was read at, `None` meaning absent.
+    ///
+    /// Fails with `KvsError::TransactionConflict` otherwise, without writing anything.
+    /// Engines without versions return `KvsError::Unsupported`.
+    fn commit(&self, _reads: ReadVersions, _batch: WriteBatch) -> Result<()> {
+        Err(KvsError::Unsupported("transactions".to_owned()))
+    }
+
+
+    /// Gets the value a key had right after the write with sequence number `seq`.
+    ///
+    /// Returns `None` if the key was absent then, or if that version of the key is
+    /// no longer kept. Engines without history return `KvsError::Unsupported`.
+    fn get_at_bytes(&self, _key: Vec<u8>, _seq: u64) -> Result<Option<Vec<u8>>> {
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+
+    /// Returns the versions of a key that are kept, oldest first: the sequence
+    /// number of every write, and the value it set, `None` for a removal.
+    ///
+    /// Engines without history return `KvsError::Unsupported`.
+    fn history_bytes(&self, _key: Vec<u8>) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+
+    /// Starts an optimistic transaction, see `Transaction`.
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+
+    /// Returns the key/value pairs whose keys are in `range`, in key order.
+    ///
+    /// Returns at most `limit` pairs if a limit is given.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter<'_>>;
+
+
+    /// Returns the key/value pairs whose keys start with `prefix`, in key order.
+    ///
+    /// Returns at most `limit` pairs if a limit is given.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ScanBytesIter<'_>>;
+
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>>;
-    
-    
-    /// Removes a given key.
-    fn remove(&self, key: String) -> Result<()>;
-}
\ No newline at end of file
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+
+    /// Removes a given string key.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+
+    /// Sets the value of a string key to a string, which expires after `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+
+    /// Returns how long a string key has left before it expires, `None` if it does not.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+
+    /// Sets a string key to `new`, or removes it if `new` is `None`, provided its
+    /// current value is `expected`, see `KvsEngine::compare_and_swap_bytes`.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        self.compare_and_swap_bytes(key.into_bytes(), expected.map(String::into_bytes), new.map(String::into_bytes))
+    }
+
+
+    /// Sets the value of a string key to a string only if the key does not exist.
+    ///
+    /// Returns whether the value was set.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+
+    /// Removes a string key only if its value is `expected`.
+    ///
+    /// Returns whether the key was removed.
+    fn remove_if_equals(&self, key: String, expected: String) -> Result<bool> {
+        self.remove_if_equals_bytes(key.into_bytes(), expected.into_bytes())
+    }
+
+
+    /// Gets the string value a string key had right after the write with sequence
+    /// number `seq`, see `KvsEngine::get_at_bytes`.
+    fn get_at(&self, key: String, seq: u64) -> Result<Option<String>> {
+        match self.get_at_bytes(key.into_bytes(), seq)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+
+    /// Returns the versions of a string key that are kept, see `KvsEngine::history_bytes`.
+    fn history(&self, key: String) -> Result<Vec<(u64, Option<String>)>> {
+        self.history_bytes(key.into_bytes())?
+            .into_iter()
+            .map(|(seq, value)| Ok((seq, value.map(String::from_utf8).transpose()?)))
+            .collect()
+    }
+
+
+    /// Returns the string key/value pairs whose keys are in `range`, in key order.
+    ///
+    /// Returns at most `limit` pairs if a limit is given.
+    fn scan<R: RangeBounds<String>>(&self, range: R, limit: Option<usize>) -> Result<ScanIter<'_>> {
+        let to_bytes = |bound: Bound<&String>| bound.map(|key| key.as_bytes().to_vec());
+        let range = (to_bytes(range.start_bound()), to_bytes(range.end_bound()));
+        Ok(into_strings(self.scan_bytes(range, limit)?))
+    }
+
+
+    /// Returns the string key/value pairs whose keys start with `prefix`, in key order.
+    ///
+    /// Returns at most `limit` pairs if a limit is given.
+    fn scan_prefix(&self, prefix: String, limit: Option<usize>) -> Result<ScanIter<'_>> {
+        Ok(into_strings(self.scan_prefix_bytes(prefix.into_bytes(), limit)?))
+    }
+}
+
+fn into_strings(pairs: ScanBytesIter<'_>) -> ScanIter<'_> {
+    Box::new(pairs.map(|pair| {
+        let (key, value) = pair?;
+        Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+    }))
+}
@@ -0,0 +1,68 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use crate::{KvsError, Result};
+
+/// Name of the lock file in the directory of a store.
+const LOCK_FILE: &str = "LOCK";
+
+/// Exclusive lock of the directory of a store open for writing, a `KvStore` or a
+/// `LsmKvsEngine`, so that no two stores write to the same files.
+///
+/// The lock is an advisory `flock` of the `LOCK` file, which holds the id of the
+/// process that took it. It is released when dropped, or when the process exits
+/// however it does; the file itself is left behind.
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks the directory `path`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StoreLocked` if another store holds the lock, and
+    /// propagates I/O errors.
+    pub fn acquire(path: &Path) -> Result<DirLock> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(KvsError::StoreLocked { pid: read_holder(&mut file)? }),
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+        file.set_len(0)?;
+        file.write_all(process::id().to_string().as_bytes())?;
+        Ok(DirLock { _file: file })
+    }
+}
+
+/// Reads the id of the process holding the lock from the lock `file`, giving it
+/// a moment to write it if it has just taken the lock; `None` if it does not.
+fn read_holder(file: &mut File) -> Result<Option<u32>> {
+    for _ in 0..10 {
+        let mut holder = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut holder)?;
+        if let Ok(pid) = holder.parse() {
+            return Ok(Some(pid));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(None)
+}
+
+/// Syncs the directory of the file `path`, so that the file survives a crash.
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path.parent().unwrap_or(path))?.sync_all()
+}
+
+/// Windows cannot open a directory as a file to sync it.
+#[cfg(windows)]
+pub fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -1,28 +1,74 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, btree_map::Entry};
 use std::fs::{self, File, OpenOptions};
+use std::mem;
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::sync::{Arc, Weak, atomic::{AtomicU64, Ordering}};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::Subcommand;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use crossbeam_skiplist::SkipMap;
+use memmap2::Mmap;
+use crossbeam::channel::{bounded, unbounded, RecvTimeoutError, Sender};
 
-use crate::{KvsError, Result, KvsEngine};
+use crate::{KvsError, Result, KvsEngine, ReadVersions, ScanBytesIter, Version, WriteBatch};
+use crate::engines::BatchOp;
+use crate::engines::dir::{sync_dir, DirLock};
 use std::ffi::OsStr;
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+use self::cache::ReadCache;
+use self::compaction::{CompactionRequest, Compactor, MovedEntry};
+use self::compression::Compressor;
+use self::crypto::Keyring;
+use self::group_commit::{GroupCommit, WriteOp};
+use self::history::{History, VersionPos};
+use self::index::{Index, Slot, SortedIndex};
+use self::log_files::LogFiles;
+use self::snapshot::SnapshotPins;
+use self::record::{Decoded, Record, Stamp, ValueCodec, BATCH_HEADER_LEN, LOG_HEADER_LEN, LOG_MAGIC, LOG_VERSION};
+use self::value_log::{ValueEntry, ValueLogCollector, ValueLogWriter, ValuePointer};
+
+pub use self::options::{
+    CompactionPolicy, Compression, IndexMode, KvStoreOptions, ReadCachePolicy, RecoveryMode, RetentionPolicy, SyncPolicy,
+    ValueLogGcPolicy,
+};
+pub use self::crypto::{Cipher, EncryptionKey};
+pub use self::snapshot::Snapshot;
+
+mod cache;
+mod compaction;
+mod compression;
+mod crypto;
+mod group_commit;
+mod hint;
+mod history;
+mod index;
+mod log_files;
+mod options;
+mod record;
+mod snapshot;
+mod value_log;
+
 pub const DEFAULT_ADDR: &str = "127.0.0.1:4000";
 
+/// Number of times a read-only store lists and reads the logs before giving up,
+/// if the writer of the directory keeps compacting them away meanwhile.
+const READ_ONLY_OPEN_ATTEMPTS: usize = 5;
+
 
-/// The `KvStore` stores string key/value pairs.
+/// The `KvStore` stores binary key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
-/// monotonically increasing generation numbers with a `log` extension name.
-/// A `BTreeMap` in memory stores the keys and the value locations for fast query.
+/// monotonically increasing generation numbers with a `log` extension name, and
+/// hold length-prefixed binary records (see `record::Record`).
+/// A `SkipMap` in memory stores the keys and the value locations for fast query.
+///
+/// The logs are compacted in the background. How the store syncs, indexes,
+/// caches, compresses and encrypts its records is set with `KvStoreOptions`.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
@@ -41,10 +87,107 @@ pub struct KvStore {
     path: Arc<PathBuf>,
     // kvs reader
     kvs_reader: KvReader,
-    // kvs writer
-    kvs_writer: Arc<Mutex<KvWriter>>,
+    // what writes to the directory, `None` if the store is opened read-only
+    writable: Option<Writable>,
+    // sequence number of the last write replayed, which a read-only store
+    // stays at
+    opened_seq: u64,
+    // logs kept for the live snapshots
+    pins: Arc<SnapshotPins>,
     // index
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
+    // past versions of the keys
+    history: Arc<History>,
+    // values read recently
+    cache: Arc<ReadCache>,
+    // what the open left out of the logs
+    recovery: Arc<RecoveryReport>,
+}
+
+/// Counters of a `KvStore`, see `KvStore::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KvStoreStats {
+    /// Number of reads served by the read cache.
+    pub cache_hits: u64,
+    /// Number of reads the read cache missed, which went to the logs.
+    pub cache_misses: u64,
+    /// Number of values in the read cache.
+    pub cache_entries: usize,
+    /// Bytes of the keys and values in the read cache.
+    pub cache_bytes: u64,
+    /// Bytes of the values written since the store was opened, compactions that
+    /// recompress included, before compression.
+    pub uncompressed_bytes: u64,
+    /// Bytes of the same values as stored in the logs.
+    pub compressed_bytes: u64,
+    /// Number of times the logs were synced to disk since the store was opened.
+    pub syncs: u64,
+    /// Number of writes to the current log not synced to disk yet.
+    pub unsynced_writes: usize,
+}
+
+impl KvStoreStats {
+    /// Returns how many times smaller the values written since the store was
+    /// opened are in the logs, 1 if none was written.
+    pub fn compression_ratio(&self) -> f64 {
+        match self.compressed_bytes {
+            0 => 1.0,
+            compressed => self.uncompressed_bytes as f64 / compressed as f64,
+        }
+    }
+}
+
+/// What `KvStore::open` left out of the logs to open them, see `RecoveryMode`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// The byte ranges left out of each log generation that had any, in order:
+    /// the damaged records skipped, and the tail truncated away.
+    pub skipped: BTreeMap<u64, Vec<Range<u64>>>,
+}
+
+impl RecoveryReport {
+    /// Whether the logs were opened whole.
+    pub fn is_clean(&self) -> bool {
+        self.skipped.is_empty()
+    }
+
+    /// Returns the number of bytes left out of the logs.
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped.values().flatten().map(|range| range.end - range.start).sum()
+    }
+
+    fn skip(&mut self, gen: u64, range: Range<u64>) {
+        self.skipped.entry(gen).or_default().push(range);
+    }
+}
+
+/// How `load` deals with damaged records, and what it left out so far.
+struct Recovery {
+    mode: RecoveryMode,
+    // whether the logs are to be left as they are
+    read_only: bool,
+    report: RecoveryReport,
+}
+
+/// The parts of a `KvStore` that write to its directory, shared by all its
+/// clones. The fields are dropped in order, so the lock is released once the
+/// background threads are done.
+#[derive(Clone)]
+struct Writable {
+    // group commit in front of the kvs writer
+    committer: Arc<GroupCommit>,
+    // background compaction, stopped once the last clone is dropped
+    compactor: Arc<Compactor>,
+    // background garbage collection of the value logs, stopped once the last
+    // clone is dropped
+    collector: Arc<ValueLogCollector>,
+    // background sync of `SyncPolicy::Interval`, stopped once the last clone is
+    // dropped
+    _interval_sync: Option<Arc<Periodic>>,
+    // background expiry sweeper, stopped once the last clone is dropped
+    _expiry_sweeper: Arc<Periodic>,
+    // exclusive lock of the directory
+    _lock: Arc<DirLock>,
 }
 
 struct KvWriter {
@@ -53,21 +196,59 @@ struct KvWriter {
     // writer of the current log.
     writer: BufWriterWithPos<File>,
     current_gen: u64,
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
+    history: Arc<History>,
+    // invalidated for every key written.
+    cache: Arc<ReadCache>,
+    // reads the current values for compare-and-swap.
+    reader: KvReader,
+    // the sequence number of the last write.
+    seq: u64,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction.
     uncompacted: u64,
-    // reader
-    kvs_reader: KvReader,
+    // oldest generation still referenced by the index, shared with the readers.
+    safe_point: Arc<AtomicU64>,
+    // the number of bytes of the records the index points at.
+    live: u64,
+    // the number of log files, including the current one.
+    log_count: usize,
+    // wakes up the compaction thread.
+    compaction_trigger: Sender<CompactionRequest>,
+    compaction_policy: CompactionPolicy,
+    compaction: CompactionState,
+    // where the values stored apart from their keys go, and from which length.
+    value_log: ValueLogWriter,
+    value_threshold: Option<usize>,
+    // when to sync the current log to disk.
+    sync_policy: SyncPolicy,
+    // the number of writes since the current log was last synced.
+    unsynced: usize,
+    // the number of syncs since the store was opened.
+    syncs: u64,
 }
 
+#[derive(Debug, PartialEq)]
+enum CompactionState {
+    Idle,
+    // the compaction thread has been asked to compact.
+    Requested,
+    // the logs before generation `gen` are being compacted into it.
+    Running { gen: u64 },
+}
+
+/// Reads records from the logs and the value logs, through the `LogFiles` shared
+/// by all the clones.
 struct KvReader {
-    // map generation number to the file reader.
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
-    // latest generation
-    safe_point: Arc<AtomicU64>,
-    // dir path of logs
-    path: Arc<PathBuf>
+    logs: Arc<LogFiles>,
+    value_logs: Arc<LogFiles>,
+    // what the values go through on their way to the logs and back.
+    values: Arc<ValueCodec>,
+    // the logs before the safe point this clone mapped itself, which only a
+    // snapshot pinning them, or a read racing a compaction, reads.
+    pinned: RefCell<BTreeMap<u64, Arc<Mmap>>>,
+    // the same for the value logs and their garbage collection.
+    pinned_values: RefCell<BTreeMap<u64, Arc<Mmap>>>,
 }
 
 impl Clone for KvStore {
@@ -75,8 +256,13 @@ impl Clone for KvStore {
         KvStore {
             path: self.path.clone(),
             kvs_reader: self.kvs_reader.clone(),
-            kvs_writer: self.kvs_writer.clone(),
+            writable: self.writable.clone(),
+            opened_seq: self.opened_seq,
+            pins: self.pins.clone(),
             index: self.index.clone(),
+            history: self.history.clone(),
+            cache: self.cache.clone(),
+            recovery: self.recovery.clone(),
         }
     }
 }
@@ -84,223 +270,1183 @@ impl Clone for KvStore {
 impl Clone for KvReader {
     fn clone(&self) -> Self {
         KvReader {
-            readers: RefCell::new(BTreeMap::new()),
-            safe_point: self.safe_point.clone(),
-            path: self.path.clone()
+            logs: self.logs.clone(),
+            value_logs: self.value_logs.clone(),
+            values: self.values.clone(),
+            pinned: RefCell::new(BTreeMap::new()),
+            pinned_values: RefCell::new(BTreeMap::new()),
         }
     }
 }
 
 impl KvReader {
+    /// Calls `f` with the bytes of the record at `cmd_pos`: a slice of the mapping
+    /// of a sealed log, or a copy read from the active one.
+    ///
+    /// Fails with `KvsError::Corruption` if the log ends before the record does.
     fn read_and<F, R>(&self, cmd_pos: &CommandPos, f: F) -> Result<R>
-    where F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>
+    where F: FnOnce(&[u8]) -> Result<R>
     {
-        let mut readers  = self.readers.borrow_mut();
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(
-                File::open(log_path(&self.path, cmd_pos.gen))?
-            )?;
-            readers.insert(cmd_pos.gen, reader);
-        }
-        let reader = readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
+        let truncated = || KvsError::Corruption { gen: cmd_pos.gen, offset: cmd_pos.pos };
+        let range = cmd_pos.pos..cmd_pos.pos + cmd_pos.len;
+        read_file_and(&self.logs, &self.pinned, cmd_pos.gen, range, truncated, f)
     }
-    
+
+    /// Reads the set record at `cmd_pos`.
+    fn read_record(&self, cmd_pos: &CommandPos) -> Result<Record> {
+        self.read_and(cmd_pos, |mut record| {
+            match Record::decode_from(&mut record, &self.values)? {
+                Decoded::Record(record @ (Record::Set { .. } | Record::Pointer { .. }), _) => Ok(record),
+                Decoded::Record(..) | Decoded::Batch(..) => Err(KvsError::UnexpectedCommandType),
+                Decoded::Unauthenticated => Err(
+                    KvsError::AuthenticationFailed { gen: cmd_pos.gen, offset: cmd_pos.pos }
+                ),
+                Decoded::Eof | Decoded::Torn | Decoded::Corrupted(_) => Err(
+                    KvsError::Corruption { gen: cmd_pos.gen, offset: cmd_pos.pos }
+                ),
+            }
+        })
+    }
+
+    /// Reads the value of the set record at `cmd_pos`.
+    fn read_value(&self, cmd_pos: &CommandPos) -> Result<Vec<u8>> {
+        self.record_value(self.read_record(cmd_pos)?)
+    }
+
+    /// Returns the value of a set record, read from its value log if it points
+    /// into one.
+    fn record_value(&self, record: Record) -> Result<Vec<u8>> {
+        match record {
+            Record::Set { value, .. } => Ok(value),
+            Record::Pointer { key, pointer, stamp, .. } => self.read_pointed(&key, stamp, &pointer),
+            Record::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Reads the value `pointer` points at, which was written to `key` with `stamp`.
+    ///
+    /// Fails with `KvsError::ValueLogCorruption` if the record there is damaged,
+    /// or not the one of that write.
+    fn read_pointed(&self, key: &[u8], stamp: Stamp, pointer: &ValuePointer) -> Result<Vec<u8>> {
+        let corrupted = || KvsError::ValueLogCorruption { id: pointer.id, offset: pointer.pos };
+        let range = pointer.pos..pointer.pos + pointer.len;
+        read_file_and(&self.value_logs, &self.pinned_values, pointer.id, range, corrupted, |mut record| {
+            match Record::decode_from(&mut record, &self.values)? {
+                Decoded::Record(Record::Set { key: written_key, value, stamp: written, .. }, _)
+                    if written_key == key && written.seq == stamp.seq => Ok(value),
+                _ => Err(corrupted()),
+            }
+        })
+    }
+
+    /// Returns where the value of the set record at `cmd_pos` is, if it is in a
+    /// value log.
+    fn read_pointer(&self, cmd_pos: &CommandPos) -> Result<Option<ValuePointer>> {
+        match self.read_record(cmd_pos)? {
+            Record::Pointer { pointer, .. } => Ok(Some(pointer)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Unmaps the logs and the value logs before their safe point this clone
+    /// mapped itself.
     fn close_stale_readers(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let first_gen = *readers.keys().next().unwrap();
-            if first_gen >= self.safe_point.load(Ordering::SeqCst) {
-                break;
+        for pinned in [&self.pinned, &self.pinned_values] {
+            let mut pinned = pinned.borrow_mut();
+            if !pinned.is_empty() {
+                pinned.clear();
             }
-            readers.remove(&first_gen);
         }
     }
 }
 
+/// Calls `f` with the bytes at `range` of the file `gen` of `files`: a slice of
+/// its mapping if it is sealed, mapped into `pinned` if it is before the safe
+/// point, or a copy read from it if it is active or the store is read-only.
+///
+/// Fails with the error of `truncated` if the file ends before the range does.
+fn read_file_and<F, R>(
+    files: &LogFiles,
+    pinned: &RefCell<BTreeMap<u64, Arc<Mmap>>>,
+    gen: u64,
+    range: Range<u64>,
+    truncated: impl Fn() -> KvsError,
+    f: F,
+) -> Result<R>
+where F: FnOnce(&[u8]) -> Result<R>
+{
+    if !files.is_mapped(gen) {
+        let mut record = vec![0; (range.end - range.start) as usize];
+        return match files.read_at(gen, range.start, &mut record) {
+            Err(KvsError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => Err(truncated()),
+            result => result.and_then(|()| f(&record)),
+        };
+    }
+    let map = match files.mapped(gen, range.end)? {
+        Some(map) => map,
+        None => match pinned.borrow_mut().entry(gen) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => entry.insert(Arc::new(files.map(gen)?)).clone(),
+        },
+    };
+    match map.get(range.start as usize..range.end as usize) {
+        Some(record) => f(record),
+        None => Err(truncated()),
+    }
+}
+
 impl KvWriter {
-    /// Clears stale entries in the log.
-    fn compact(&mut self) -> Result<()> {
+    /// Seals the current log for a compaction and moves writes on to a new one.
+    /// The past versions the retention policy no longer keeps are dropped first,
+    /// so that the compaction reclaims them.
+    ///
+    /// Returns the generation reserved for the compacted log, the stale bytes
+    /// counted so far, which the compaction is about to reclaim, and the sequence
+    /// number of the last write.
+    fn seal_for_compaction(&mut self) -> Result<(u64, u64, u64)> {
+        if self.sync_policy != SyncPolicy::Never && self.unsynced > 0 {
+            self.sync()?;
+        }
+        let pruned = self.history.prune_all(&self.index);
+        self.drop_versions(pruned);
         // increase current gen by 2. current_gen + 1 is for the compaction file.
         let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+        self.writer = new_log_file(&self.path, compaction_gen + 1, self.seq)?;
+        self.current_gen = compaction_gen + 1;
+        self.reader.logs.set_active(self.current_gen);
+        self.unsynced = 0;
+        self.log_count += 2;
+        self.compaction = CompactionState::Running { gen: compaction_gen };
+        Ok((compaction_gen, mem::take(&mut self.uncompacted), self.seq))
+    }
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+    /// Points the index and the history at the compacted copies of the versions
+    /// that did not change while they were copied, drops the entries that expired
+    /// instead, and ends the compaction.
+    ///
+    /// A bounded index is given the `sorted` index of the compacted log instead of
+    /// the moves of the current versions.
+    fn finish_compaction(&mut self, compaction_gen: u64, moved: Vec<MovedEntry>, sorted: Option<SortedIndex>) {
+        for (key, old_pos, new_version) in moved {
+            // a key that fails to be looked up is taken as changed, which leaves
+            // its copy to the next compaction.
+            let unchanged = self
+                .index
+                .get(&key)
+                .is_ok_and(|current| current.is_some_and(|current| current.same_record(&old_pos)));
+            match new_version {
+                Some(VersionPos { cmd_pos, .. }) if unchanged => {
+                    self.index.insert(key, cmd_pos);
+                }
+                // the version was overwritten while being copied and is a past
+                // one now, unless it has been dropped since.
+                Some(VersionPos { cmd_pos, .. }) if !self.history.relocate(&key, &old_pos, cmd_pos) => {
+                    self.uncompacted += cmd_pos.len;
+                }
+                Some(_) => {}
+                None if unchanged => {
+                    // a bounded index drops it with the sorted index it is in.
+                    if sorted.is_none() {
+                        self.index.remove(&key, compaction_gen);
+                    }
+                    self.live -= old_pos.len;
+                }
+                None => {}
+            }
+        }
+        if let Some(sorted) = sorted {
+            // the versions left in the sealed logs were current ones when they were
+            // copied, and have been overwritten since.
+            for (key, past) in self.history.versions_before(compaction_gen) {
+                match sorted.get(&key) {
+                    Ok(Some(copy)) if copy.stamp.seq == past.cmd_pos.stamp.seq => {
+                        self.history.relocate(&key, &past.cmd_pos, copy);
+                    }
+                    // its record is about to be deleted.
+                    _ => {
+                        self.history.forget(&key, &past.cmd_pos);
+                        self.live -= past.cmd_pos.len;
+                    }
+                }
+            }
+            self.index.install(sorted);
+        }
+        self.safe_point.store(compaction_gen, Ordering::SeqCst);
+        // the logs from the compaction one up to the current one are left.
+        self.log_count = (self.current_gen - compaction_gen + 1) as usize;
+        self.compaction = CompactionState::Idle;
+    }
 
-        let mut new_pos = 0; // pos in the new log file.
-        for entry in self.index.iter() {
-            let key = entry.key();
-            let cmd_pos = entry.value();
+    /// Ends a compaction that failed, giving back the stale bytes it was to reclaim.
+    fn abort_compaction(&mut self, sealed_stale: u64) {
+        self.uncompacted += sealed_stale;
+        // the compaction log has been deleted.
+        self.log_count -= 1;
+        self.compaction = CompactionState::Idle;
+    }
 
-            // let mut entry_reader = self.kvs_reader.get_reader(&cmd_pos)?;
-            let len = self.kvs_reader.read_and(
-                &cmd_pos,
-                |mut reader| { Ok(io::copy(&mut reader, &mut compaction_writer)?) }
-            )?;
-            self.index.insert(key.clone(), (compaction_gen, new_pos..new_pos + len).into());
-            new_pos += len;
+    /// Counts a record that was overwritten or removed as stale, unless it is in a
+    /// log the running compaction is about to drop anyway.
+    fn mark_stale(&mut self, cmd_pos: &CommandPos) {
+        match self.compaction {
+            CompactionState::Running { gen } if cmd_pos.gen < gen => {}
+            _ => self.uncompacted += cmd_pos.len,
         }
-        compaction_writer.flush()?;
-        
-        self.kvs_reader.safe_point.store(compaction_gen, Ordering::SeqCst);
+    }
 
-        // remove stale log files.
-        let stale_gens: Vec<_> = sorted_gen_list(&self.path)?
-            .iter()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
+    /// Returns where the record of `key` is, unless the key is absent or expired.
+    fn live_entry(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        Ok(self.index.get(key)?.filter(|cmd_pos| !cmd_pos.is_expired()))
+    }
 
-        for stale_gen in stale_gens {
-            self.kvs_reader.readers.borrow_mut().remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+    /// Drops the entries of the given keys that have expired from the index, and
+    /// counts their records as stale, or moves them to the history if it is kept.
+    fn remove_expired(&mut self, keys: Vec<Vec<u8>>) {
+        for key in keys {
+            // the key may have been set again since it was found expired.
+            let old_cmd = match self.index.get(&key) {
+                Ok(Some(cmd_pos)) if cmd_pos.is_expired() => cmd_pos,
+                _ => continue,
+            };
+            self.cache.invalidate(&key);
+            if self.history.is_enabled() {
+                self.history.insert(&key, VersionPos { cmd_pos: old_cmd, removed: false });
+                self.index.remove(&key, self.current_gen);
+                self.prune_history(&key, false);
+            } else {
+                self.index.remove(&key, self.current_gen);
+                self.live -= old_cmd.len;
+                self.mark_stale(&old_cmd);
+            }
         }
-        
-        self.uncompacted = 0;
+    }
 
-        Ok(())
+    /// Drops the past versions of `key` the retention policy no longer keeps,
+    /// `has_current` telling whether the key is present.
+    fn prune_history(&mut self, key: &[u8], has_current: bool) {
+        let pruned = self.history.prune(key, has_current);
+        self.drop_versions(pruned);
     }
-    
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
 
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+    /// Counts the records of the past versions dropped from the history as stale.
+    fn drop_versions(&mut self, pruned: Vec<CommandPos>) {
+        for cmd_pos in pruned {
+            self.live -= cmd_pos.len;
+            self.mark_stale(&cmd_pos);
+        }
+    }
+
+    /// Numbers a write of the group being committed at `timestamp`.
+    fn next_stamp(&mut self, timestamp: u64) -> Stamp {
+        self.seq += 1;
+        Stamp { seq: self.seq, timestamp }
+    }
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+    /// Whether a key of `reads` is no longer at the version it was read at, taking
+    /// into account the keys `written` earlier in the group being committed.
+    fn has_conflict(&self, reads: &[(Vec<u8>, Option<Version>)], written: &GroupWrites) -> Result<bool> {
+        for (key, version) in reads {
+            if written.contains_key(key) || self.live_entry(key)?.map(|cmd_pos| cmd_pos.version) != *version {
+                return Ok(true);
             }
+        }
+        Ok(false)
+    }
 
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
+    /// Numbers the writes of a batch and turns them into records, leaving out the
+    /// removals of absent keys, provided no key of `reads` has changed since it was
+    /// read, taking into account the keys `written` earlier in the group.
+    fn batch_records(
+        &mut self,
+        ops: Vec<BatchOp>,
+        reads: &[(Vec<u8>, Option<Version>)],
+        written: &GroupWrites,
+        timestamp: u64,
+    ) -> Result<Vec<Record>> {
+        if self.has_conflict(reads, written)? {
+            return Err(KvsError::TransactionConflict);
+        }
+        // whether the keys written earlier in the batch are present.
+        let mut in_batch = HashMap::new();
+        let mut records = Vec::with_capacity(ops.len());
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    in_batch.insert(key.clone(), true);
+                    let stamp = self.next_stamp(timestamp);
+                    records.push(Record::Set { key, value, expires_at: None, stamp });
+                }
+                BatchOp::Remove { key } => {
+                    let present = match in_batch.get(&key).copied().or_else(|| written.get(&key).map(Option::is_some)) {
+                        Some(present) => present,
+                        None => self.live_entry(&key)?.is_some(),
+                    };
+                    // removing an absent key does nothing in a batch.
+                    if present {
+                        in_batch.insert(key.clone(), false);
+                        let stamp = self.next_stamp(timestamp);
+                        records.push(Record::Remove { key, stamp });
+                    }
+                }
+            }
         }
+        Ok(records)
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// Returns the value of `key` as the group being committed leaves it so far:
+    /// from the records in `buf` if it was `written` earlier in the group, from the
+    /// log otherwise.
+    fn current_value(&self, key: &[u8], written: &GroupWrites, buf: &[u8]) -> Result<Option<Vec<u8>>> {
+        match written.get(key) {
+            Some(Some(range)) => match Record::decode_from(&mut &buf[range.start as usize..range.end as usize], &self.reader.values)? {
+                Decoded::Record(
+                    Record::Set { expires_at: Some(deadline), .. } | Record::Pointer { expires_at: Some(deadline), .. },
+                    _,
+                ) if deadline <= now_millis() => Ok(None),
+                // the values of the group are written to their value log right away.
+                Decoded::Record(record @ (Record::Set { .. } | Record::Pointer { .. }), _) => {
+                    Ok(Some(self.reader.record_value(record)?))
+                }
+                _ => unreachable!("only set records are recorded with their position"),
+            },
+            Some(None) => Ok(None),
+            None => {
+                let cmd_pos = match self.live_entry(key)? {
+                    Some(cmd_pos) => cmd_pos,
+                    None => return Ok(None),
+                };
+                self.reader.close_stale_readers();
+                Ok(Some(self.reader.read_value(&cmd_pos)?))
+            }
         }
-        Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Rm { key, .. } = cmd {
-                let old_entry = self.index.remove(&key).expect("key not found");
-                let old_cmd = old_entry.value();
-                self.uncompacted += old_cmd.len;
+    /// Writes a group of operations to the log with a single flush and, depending
+    /// on the sync policy, at most one sync, then applies them to the index.
+    ///
+    /// Returns the result of every operation, in order.
+    fn commit(&mut self, ops: Vec<WriteOp>) -> Vec<Result<bool>> {
+        let mut buf = Vec::new();
+        let mut results = Vec::with_capacity(ops.len());
+        // index changes to apply once the group is written: the key, whether it
+        // is set and until when, and the offset and length of its record in `buf`.
+        let mut updates = Vec::with_capacity(ops.len());
+        let mut written = GroupWrites::new();
+        // the bytes of the batch record headers, which nothing points at.
+        let mut batch_headers = 0;
+        let timestamp = now_millis();
+
+        for op in ops {
+            let offset = buf.len() as u64;
+            let result = match op {
+                WriteOp::Set { key, value, expires_at } => {
+                    let stamp = self.next_stamp(timestamp);
+                    let record = self.separate(Record::Set { key: key.clone(), value, expires_at, stamp });
+                    record.and_then(|record| record.encode_to(&mut buf, &self.reader.values)).map(|len| {
+                        written.insert(key.clone(), Some(offset..offset + len));
+                        updates.push((key, true, expires_at, stamp, offset, len));
+                        true
+                    })
+                }
+                WriteOp::Remove { key } => {
+                    let present = match written.get(&key) {
+                        Some(range) => Ok(range.is_some()),
+                        None => self.live_entry(&key).map(|cmd_pos| cmd_pos.is_some()),
+                    };
+                    match present {
+                        Ok(true) => {
+                            let stamp = self.next_stamp(timestamp);
+                            let record = Record::Remove { key: key.clone(), stamp };
+                            record.encode_to(&mut buf, &self.reader.values).map(|len| {
+                                written.insert(key.clone(), None);
+                                updates.push((key, false, None, stamp, offset, len));
+                                true
+                            })
+                        }
+                        Ok(false) => Err(KvsError::KeyNotFound),
+                        Err(err) => Err(err),
+                    }
+                }
+                WriteOp::Batch { ops: batch_ops, reads } => match self
+                    .batch_records(batch_ops, &reads, &written, timestamp)
+                    .and_then(|records| records.into_iter().map(|record| self.separate(record)).collect::<Result<Vec<_>>>())
+                {
+                    Ok(records) if records.is_empty() => Ok(true),
+                    Ok(records) => {
+                        Record::encode_batch(&records, &mut buf, &self.reader.values).map(|lens| {
+                            let mut inner_offset = offset + BATCH_HEADER_LEN;
+                            for (record, len) in records.into_iter().zip(lens) {
+                                let (key, is_set, expires_at, stamp) = match record {
+                                    Record::Set { key, expires_at, stamp, .. }
+                                    | Record::Pointer { key, expires_at, stamp, .. } => (key, true, expires_at, stamp),
+                                    Record::Remove { key, stamp } => (key, false, None, stamp),
+                                };
+                                written.insert(key.clone(), is_set.then(|| inner_offset..inner_offset + len));
+                                updates.push((key, is_set, expires_at, stamp, inner_offset, len));
+                                inner_offset += len;
+                            }
+                            batch_headers += BATCH_HEADER_LEN;
+                            true
+                        })
+                    }
+                    Err(err) => Err(err),
+                },
+                WriteOp::CompareAndSwap { key, expected, new } => match self.current_value(&key, &written, &buf) {
+                    Ok(current) if current != expected => Ok(false),
+                    // the key is absent and is to stay so.
+                    Ok(None) if new.is_none() => Ok(true),
+                    Ok(_) => {
+                        let is_set = new.is_some();
+                        let stamp = self.next_stamp(timestamp);
+                        let record = match new {
+                            Some(value) => self.separate(Record::Set { key: key.clone(), value, expires_at: None, stamp }),
+                            None => Ok(Record::Remove { key: key.clone(), stamp }),
+                        };
+                        record.and_then(|record| record.encode_to(&mut buf, &self.reader.values)).map(|len| {
+                            written.insert(key.clone(), is_set.then(|| offset..offset + len));
+                            updates.push((key, is_set, None, stamp, offset, len));
+                            true
+                        })
+                    }
+                    Err(err) => Err(err),
+                },
+            };
+            results.push(result);
+        }
+
+        if updates.is_empty() {
+            return results;
+        }
+
+        let base = self.writer.pos;
+        if let Err(err) = self.write_group(&buf, updates.len()) {
+            return results
+                .into_iter()
+                .map(|result| result.and_then(|_| Err(share_error(&err))))
+                .collect();
+        }
+
+        self.uncompacted += batch_headers;
+        let keep_history = self.history.is_enabled();
+        for (key, is_set, expires_at, stamp, offset, len) in updates {
+            let pos = base + offset;
+            let cmd_pos = CommandPos { expires_at, stamp, ..(self.current_gen, pos..pos + len).into() };
+            // the records are written whatever happens, so a version a bounded index
+            // fails to look up is left out of the history and of the stale bytes,
+            // and merely dropped by the next compaction.
+            let old_cmd = self.index.get(&key).unwrap_or(None);
+            self.cache.invalidate(&key);
+            // the version replaced goes to the history before it leaves the index,
+            // so that readers always find it in one or the other.
+            if let (Some(old_cmd), true) = (old_cmd, keep_history) {
+                self.history.insert(&key, VersionPos { cmd_pos: old_cmd, removed: false });
+            }
+            if is_set {
+                self.index.insert(key.clone(), cmd_pos);
+                self.live += len;
+            } else if keep_history {
+                self.history.insert(&key, VersionPos { cmd_pos, removed: true });
+                self.index.remove(&key, self.current_gen);
+                self.live += len;
+            } else {
+                self.index.remove(&key, self.current_gen);
+                // the remove record itself is stale as well.
+                self.uncompacted += len;
+            }
+            match old_cmd {
+                Some(old_cmd) if !keep_history => {
+                    self.live -= old_cmd.len;
+                    self.mark_stale(&old_cmd);
+                }
+                _ if keep_history => self.prune_history(&key, is_set),
+                _ => {}
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+        }
+        results
+    }
+
+    /// Writes the value of a set record long enough to be stored apart from its key
+    /// to the active value log, and returns the record pointing at it instead.
+    /// Other records are returned as they are.
+    fn separate(&mut self, record: Record) -> Result<Record> {
+        match record {
+            Record::Set { key, value, expires_at, stamp } if self.value_threshold.is_some_and(|min_len| value.len() >= min_len) => {
+                // the deadline stays with the pointer.
+                let mut encoded = Vec::new();
+                Record::Set { key: key.clone(), value, expires_at: None, stamp }.encode_to(&mut encoded, &self.reader.values)?;
+                let pointer = self.value_log.append(&encoded, self.seq)?;
+                Ok(Record::Pointer { key, pointer, expires_at, stamp })
+            }
+            record => Ok(record),
+        }
+    }
+
+    /// Copies the values of `entries` of the value log `id`, mapped as `map`, that
+    /// the index or the history still points at to the active value log, and
+    /// points them at the copies with new pointer records, which keep the stamp
+    /// and the version of the write.
+    ///
+    /// The values overwritten or removed since they were listed are left out, as
+    /// the writer is locked. The records whose values are compressed or encrypted
+    /// otherwise than they are written now are rewritten, like by a compaction.
+    fn relocate_values(&mut self, id: u64, map: &[u8], entries: &[ValueEntry]) -> Result<()> {
+        self.reader.close_stale_readers();
+        let values = self.reader.values.clone();
+        let mut buf = Vec::new();
+        // the key, the version moved, whether it is the current one, and the
+        // offset and length of its new record in `buf`.
+        let mut moves = Vec::new();
+        for entry in entries {
+            let (cmd_pos, is_current) = match value_log::find_version(&self.index, &self.history, &entry.key, entry.seq)? {
+                Some(version) => version,
+                None => continue,
+            };
+            // a write lost in a crash may have left a value whose sequence number
+            // was given again since.
+            let pointer = ValuePointer { id, pos: entry.pos, len: entry.len };
+            if self.reader.read_pointer(&cmd_pos)? != Some(pointer) {
+                continue;
+            }
+            let record = &map[entry.pos as usize..(entry.pos + entry.len) as usize];
+            let copy = if values.is_current(record) {
+                self.value_log.append(record, self.seq)?
+            } else {
+                let mut encoded = Vec::new();
+                match Record::decode_from(&mut &record[..], &values)? {
+                    Decoded::Record(record, _) => record.encode_to(&mut encoded, &values)?,
+                    _ => return Err(KvsError::ValueLogCorruption { id, offset: entry.pos }),
+                };
+                self.value_log.append(&encoded, self.seq)?
+            };
+            let record = Record::Pointer {
+                key: entry.key.clone(),
+                pointer: copy,
+                expires_at: cmd_pos.expires_at,
+                stamp: cmd_pos.stamp,
+            };
+            let offset = buf.len() as u64;
+            let len = record.encode_to(&mut buf, &values)?;
+            moves.push((entry.key.clone(), cmd_pos, is_current, offset, len));
+        }
+        if moves.is_empty() {
+            return Ok(());
+        }
+
+        let base = self.writer.pos;
+        self.write_group(&buf, moves.len())?;
+        for (key, old_pos, is_current, offset, len) in moves {
+            let pos = base + offset;
+            let new_pos = CommandPos { gen: self.current_gen, pos, len, ..old_pos };
+            if is_current {
+                self.index.insert(key, new_pos);
+            } else {
+                self.history.relocate(&key, &old_pos, new_pos);
+            }
+            self.live += len;
+            self.live -= old_pos.len;
+            self.mark_stale(&old_pos);
+        }
+        Ok(())
+    }
+
+    /// Syncs the values copied out of the value log `id` and the records pointing
+    /// at them, whatever the sync policy, before the value log is deleted.
+    fn finish_value_log_gc(&mut self, id: u64) -> Result<()> {
+        self.sync()?;
+        self.value_log.release(id);
+        Ok(())
+    }
+
+    /// Appends the encoded records of a group to the current log.
+    fn write_group(&mut self, buf: &[u8], records: usize) -> Result<()> {
+        self.writer.write_all(buf)?;
+        self.writer.flush()?;
+        self.after_write(records)
+    }
+
+    /// Asks the compaction thread for a compaction if the compaction policy says so,
+    /// or if a bounded index holds too many keys in memory.
+    fn compact_if_needed(&mut self) {
+        if self.compaction != CompactionState::Idle {
+            return;
+        }
+        let needed = self.index.over_budget() || match self.compaction_policy {
+            CompactionPolicy::StaleBytes(threshold) => self.uncompacted > threshold,
+            CompactionPolicy::StaleRatio(ratio) => self.uncompacted as f64 > self.live as f64 * ratio,
+            CompactionPolicy::MaxGenerations(max) => self.log_count > max.max(2),
+            CompactionPolicy::Disabled => false,
+        };
+        if needed {
+            self.compaction = CompactionState::Requested;
+            // the thread only stops when the store is dropped.
+            let _ = self.compaction_trigger.send(None);
+        }
+    }
+
+    /// Syncs the records written since the last sync to disk, and the values
+    /// they point at first.
+    fn sync(&mut self) -> Result<()> {
+        self.value_log.sync()?;
+        self.writer.sync_data()?;
+        self.unsynced = 0;
+        self.syncs += 1;
+        Ok(())
+    }
+
+    /// Syncs the current log if the sync policy asks for it after `records` more writes.
+    fn after_write(&mut self, records: usize) -> Result<()> {
+        self.unsynced += records;
+        match self.sync_policy {
+            SyncPolicy::EveryWrite => self.sync(),
+            SyncPolicy::Batch(writes) if self.unsynced >= writes => self.sync(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for KvWriter {
+    fn drop(&mut self) {
+        if self.sync_policy != SyncPolicy::Never && self.unsynced > 0 {
+            let _ = self.sync();
         }
     }
 }
 
 impl KvStore {
-    /// Opens a `KvStore` with the given path.
+    /// Opens a `KvStore` with the given path and the default options.
     ///
     /// This will create a new directory if the given one does not exist.
+    /// Legacy JSON log files found in the directory are rewritten in the binary
+    /// record format before they are replayed.
     ///
     /// # Errors
     ///
-    /// It propagates I/O or deserialization errors during the log replay.
+    /// It propagates I/O or deserialization errors during the log replay, and
+    /// returns `KvsError::Corruption` if a record other than the last one of a log
+    /// fails its checksum, and `KvsError::StoreLocked` if another store has the
+    /// directory open for writing.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::new())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::open`. A read-only store does not create the directory.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        // a read-only store lists the logs while the writer of the directory may
+        // compact them away, so that one is gone by the time it is read: the logs
+        // are listed again.
+        let mut attempts = 1;
+        loop {
+            match KvStore::open_dir(&path, &options) {
+                Err(KvsError::Io(err)) if options.read_only && err.kind() == io::ErrorKind::NotFound && attempts < READ_ONLY_OPEN_ATTEMPTS => {
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
 
-        let mut readers = BTreeMap::new();
-        let index = SkipMap::new();
+    fn open_dir(path: &Path, options: &KvStoreOptions) -> Result<KvStore> {
+        let path = path.to_owned();
+        // the lock is taken before anything is read, so that the logs read are
+        // the ones written to.
+        let lock = match options.read_only {
+            true => None,
+            false => {
+                fs::create_dir_all(&path)?;
+                Some(DirLock::acquire(&path)?)
+            }
+        };
+
+        let index = Index::new(options.index_mode);
+        let history = Arc::new(History::new(options.retention));
+        let cache = Arc::new(ReadCache::new(options.read_cache));
+        let values = Arc::new(ValueCodec {
+            compressor: Compressor::new(options.compression, options.recompress),
+            keyring: Keyring::new(options.encryption_key.as_ref(), &options.old_keys),
+        });
 
         let gen_list = sorted_gen_list(&path)?;
+        let mut recovery = Recovery {
+            mode: options.recovery_mode,
+            read_only: options.read_only,
+            report: RecoveryReport::default(),
+        };
         let mut uncompacted = 0;
+        let mut last_seq = 0;
+        // a bounded index starts from the sorted index of the last compaction,
+        // which holds everything the logs before it do.
+        let base_gen = match index.is_bounded() {
+            true => install_last_sorted_index(&path, &gen_list, &index)?,
+            false => None,
+        };
 
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &index)?;
-            readers.insert(gen, reader);
+            match base_gen {
+                Some(base_gen) if gen < base_gen => {
+                    // left behind by a crash, the next compaction deletes it.
+                    uncompacted += fs::metadata(log_path(&path, gen))?.len();
+                    continue;
+                }
+                Some(base_gen) if gen == base_gen => {
+                    let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
+                    let live = index.live_bytes()?;
+                    load_compacted_history(gen, &mut reader, live, &history, &values, &mut last_seq)?;
+                }
+                _ if options.read_only && !check_log_format(&path, gen)? => continue,
+                _ => {
+                    if !options.read_only {
+                        upgrade_log_if_legacy(&path, gen, &mut last_seq)?;
+                    }
+                    uncompacted += load(&path, gen, &index, &history, &values, &mut recovery, &mut last_seq)?;
+                }
+            }
         }
-
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
+        // versions are only dropped once all the logs are loaded, as a log found
+        // later may still hold an older copy of their record.
+        uncompacted += history.prune_all(&index).iter().map(|cmd_pos| cmd_pos.len).sum::<u64>();
 
         let index = Arc::new(index);
-
-        let safe_point = Arc::new(AtomicU64::new(gen_list.first().unwrap_or(&current_gen).clone()));
+        let value_log_ids = sorted_ids(&path, "vlog")?;
+        // writes go on to a new log and a new value log, while a read-only store
+        // reads the last ones as active, as the writer of the directory may
+        // still append to them.
+        let (current_gen, active_value_log) = match options.read_only {
+            true => (*gen_list.last().unwrap_or(&1), *value_log_ids.last().unwrap_or(&1)),
+            false => (gen_list.last().unwrap_or(&0) + 1, value_log_ids.last().unwrap_or(&0) + 1),
+        };
+        let safe_point = Arc::new(AtomicU64::new(*gen_list.first().unwrap_or(&current_gen)));
+        let value_safe_point = Arc::new(AtomicU64::new(*value_log_ids.first().unwrap_or(&active_value_log)));
 
         let path = Arc::new(path);
         let kvs_reader = KvReader {
-            readers: RefCell::new(readers),
-            safe_point,
-            path: path.clone(),
+            logs: Arc::new(LogFiles::new(path.clone(), log_path, current_gen, safe_point.clone(), options.read_only)),
+            value_logs: Arc::new(LogFiles::new(
+                path.clone(),
+                value_log::value_log_path,
+                active_value_log,
+                value_safe_point.clone(),
+                options.read_only,
+            )),
+            values,
+            pinned: RefCell::new(BTreeMap::new()),
+            pinned_values: RefCell::new(BTreeMap::new()),
+        };
+        let pins = Arc::new(SnapshotPins::default());
+
+        let lock = match lock {
+            Some(lock) => lock,
+            None => {
+                kvs_reader.logs.open_all(&gen_list)?;
+                kvs_reader.value_logs.open_all(&value_log_ids)?;
+                return Ok(KvStore {
+                    path,
+                    index,
+                    history,
+                    cache,
+                    kvs_reader,
+                    writable: None,
+                    opened_seq: last_seq,
+                    pins,
+                    recovery: Arc::new(recovery.report),
+                });
+            }
         };
 
-        let kvs_writer = Arc::new(
-            Mutex::new(
+        let writer = new_log_file(&path, current_gen, last_seq)?;
+        let live = index.live_bytes()? + history.len_bytes();
+        let (compaction_trigger, compaction_requests) = unbounded();
+        let (gc_trigger, gc_requests) = unbounded();
+        let committer = Arc::new(
+            GroupCommit::new(
                 KvWriter {
                     writer,
                     current_gen,
                     index: index.clone(),
+                    history: history.clone(),
+                    cache: cache.clone(),
+                    reader: kvs_reader.clone(),
+                    seq: last_seq,
                     uncompacted,
-                    kvs_reader: kvs_reader.clone(),
-                    path: path.clone()
+                    live,
+                    log_count: gen_list.len() + 1,
+                    safe_point,
+                    compaction_trigger: compaction_trigger.clone(),
+                    compaction_policy: options.compaction_policy,
+                    compaction: CompactionState::Idle,
+                    path: path.clone(),
+                    value_log: ValueLogWriter::new(
+                        path.clone(),
+                        kvs_reader.value_logs.clone(),
+                        active_value_log,
+                        options.value_log_size,
+                        value_safe_point,
+                        gc_trigger.clone(),
+                    ),
+                    value_threshold: options.value_threshold,
+                    sync_policy: options.sync_policy,
+                    unsynced: 0,
+                    syncs: 0,
                 }
             )
         );
 
+        let interval_sync = match options.sync_policy {
+            SyncPolicy::Interval(interval) => Some(Arc::new(spawn_interval_sync(Arc::downgrade(&committer), interval))),
+            _ => None,
+        };
+        let expiry_sweeper = Arc::new(spawn_expiry_sweeper(Arc::downgrade(&committer), index.clone(), options.expiry_sweep_interval));
+
+        let compactor = Arc::new(Compactor::spawn(
+            (compaction_trigger, compaction_requests),
+            Arc::downgrade(&committer),
+            index.clone(),
+            history.clone(),
+            kvs_reader.clone(),
+            pins.clone(),
+            path.clone(),
+            options.compaction_barrier.clone(),
+        ));
+        let collector = Arc::new(ValueLogCollector::spawn(
+            (gc_trigger, gc_requests),
+            Arc::downgrade(&committer),
+            index.clone(),
+            history.clone(),
+            kvs_reader.clone(),
+            pins.clone(),
+            path.clone(),
+            options.value_log_gc,
+        ));
+        // the logs found may already call for a compaction.
+        committer.writer()?.compact_if_needed();
+
         Ok(KvStore {
             path,
             index,
+            history,
+            cache,
             kvs_reader,
-            kvs_writer
+            writable: Some(Writable { committer, compactor, collector, _interval_sync: interval_sync, _expiry_sweeper: expiry_sweeper, _lock: Arc::new(lock) }),
+            opened_seq: last_seq,
+            pins,
+            recovery: Arc::new(recovery.report),
         })
     }
+
+    /// Takes a read-only snapshot of the store as it is now, see `Snapshot`.
+    ///
+    /// Writes are held up while the index is copied for the snapshot; only the
+    /// keys written since the last compaction are with `IndexMode::Bounded`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::PoisonError` if a writer panicked.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let writer = match &self.writable {
+            Some(writable) => Some(writable.committer.writer()?),
+            None => None,
+        };
+        let pin = self.pins.pin(self.path.clone())?;
+        let index = self.index.freeze();
+        let seq = writer.as_ref().map_or(self.opened_seq, |writer| writer.seq);
+        drop(writer);
+        Ok(Snapshot::new(index, seq, now_millis(), self.kvs_reader.clone(), pin))
+    }
+
+    /// Compacts the logs, whatever the compaction policy, and waits for it to finish.
+    ///
+    /// Everything written before the call is compacted; writes made in the meantime
+    /// go on without waiting for it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the compaction, and returns
+    /// `KvsError::ReadOnly` if the store is opened read-only.
+    pub fn compact(&self) -> Result<()> {
+        self.writable()?.compactor.compact()
+    }
+
+    /// Collects the garbage of the value logs, whatever the `ValueLogGcPolicy`, and
+    /// waits for it to finish.
+    ///
+    /// The active value log is sealed first, so that every value written before
+    /// the call is collected: the values still read are copied to a new value log,
+    /// and the value logs are deleted, or left for the snapshots that may still
+    /// read them until they are dropped.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the collection, and returns
+    /// `KvsError::ReadOnly` if the store is opened read-only.
+    pub fn collect_value_logs(&self) -> Result<()> {
+        self.writable()?.collector.collect()
+    }
+
+    /// Returns what writes to the directory.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ReadOnly` if the store is opened read-only.
+    fn writable(&self) -> Result<&Writable> {
+        self.writable.as_ref().ok_or(KvsError::ReadOnly)
+    }
+
+    /// Returns the version `key` was at right after the write with sequence number
+    /// `seq`, if it is kept.
+    fn version_at(&self, key: &[u8], seq: u64) -> Result<Option<VersionPos>> {
+        // the index is looked at first, as versions only ever leave it for the
+        // history.
+        match self.index.get(key)? {
+            Some(cmd_pos) if cmd_pos.stamp.seq <= seq => Ok(Some(VersionPos { cmd_pos, removed: false })),
+            _ => Ok(self.history.find_at(key, seq)),
+        }
+    }
+
+    /// Returns the versions of `key` that are kept, oldest first.
+    fn versions(&self, key: &[u8]) -> Result<Vec<VersionPos>> {
+        let current = self.index.get(key)?;
+        let mut versions = self.history.versions(key);
+        if let Some(cmd_pos) = current {
+            // it may have moved to the history since it was looked up.
+            if versions.last().is_none_or(|past| past.cmd_pos.stamp.seq < cmd_pos.stamp.seq) {
+                versions.push(VersionPos { cmd_pos, removed: false });
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Reads the values of the index `entries` lazily, skipping the keys expired
+    /// or removed in the meantime.
+    fn read_pairs<'a, I>(&'a self, entries: I, limit: Option<usize>) -> ScanBytesIter<'a>
+    where I: Iterator<Item = Result<(Vec<u8>, CommandPos)>> + 'a
+    {
+        let pairs = entries.filter_map(move |entry| {
+            let (key, cmd_pos) = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            if cmd_pos.is_expired() {
+                return None;
+            }
+            self.kvs_reader.close_stale_readers();
+            let value = match self.kvs_reader.read_value(&cmd_pos) {
+                // moved by a compaction since it was listed, like in
+                // `KvStore::get_versioned`.
+                Err(KvsError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => self.get_bytes(key.clone()),
+                result => result.map(Some),
+            };
+            value.transpose().map(|value| value.map(|value| (key, value)))
+        });
+        Box::new(pairs.take(limit.unwrap_or(usize::MAX)))
+    }
+
+    /// Returns the counters of the store so far.
+    pub fn stats(&self) -> KvStoreStats {
+        let (cache_hits, cache_misses) = self.cache.counters();
+        let (cache_entries, cache_bytes) = self.cache.usage();
+        let (uncompressed_bytes, compressed_bytes) = self.kvs_reader.values.compressor.counters();
+        let (syncs, unsynced_writes) = match &self.writable {
+            Some(writable) => writable.committer.writer().map(|writer| (writer.syncs, writer.unsynced)).unwrap_or_default(),
+            None => (0, 0),
+        };
+        KvStoreStats {
+            cache_hits,
+            cache_misses,
+            cache_entries,
+            cache_bytes,
+            uncompressed_bytes,
+            compressed_bytes,
+            syncs,
+            unsynced_writes,
+        }
+    }
+
+    /// Returns what the open left out of the logs, see `RecoveryMode`.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+
+    /// Syncs every record written so far to disk, whatever the sync policy; a
+    /// read-only store has none to sync.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the sync.
+    pub fn sync(&self) -> Result<()> {
+        match &self.writable {
+            Some(writable) => writable.committer.writer()?.sync(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A background thread running a task every interval. Dropping the handle stops
+/// the thread and waits for it to exit.
+struct Periodic {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    fn spawn(interval: Duration, mut task: impl FnMut() + Send + 'static) -> Periodic {
+        let (stop, stopped) = bounded::<()>(0);
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                task();
+            }
+        });
+        Periodic { stop: Some(stop), handle: Some(handle) }
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        // disconnecting the channel wakes the thread up.
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Syncs the log of `committer` every `interval` until the handle is dropped.
+fn spawn_interval_sync(committer: Weak<GroupCommit>, interval: Duration) -> Periodic {
+    Periodic::spawn(interval, move || {
+        if let Some(committer) = committer.upgrade() {
+            if let Ok(mut writer) = committer.writer() {
+                if writer.unsynced > 0 {
+                    // a failed sync leaves `unsynced` as is, so it is retried next
+                    // round and surfaces from an explicit `KvStore::sync`.
+                    let _ = writer.sync();
+                }
+            }
+        }
+    })
+}
+
+/// Drops the expired keys from `index` every `interval` until the handle is
+/// dropped, so that their memory is reclaimed and a compaction drops their records.
+///
+/// The index is searched without holding up writes; the writer is only locked to
+/// drop the keys found, if any. Only the keys in memory are searched: the ones
+/// only in the sorted index of a bounded index take no memory, and the next
+/// compaction drops them.
+fn spawn_expiry_sweeper(committer: Weak<GroupCommit>, index: Arc<Index>, interval: Duration) -> Periodic {
+    Periodic::spawn(interval, move || {
+        let committer = match committer.upgrade() {
+            Some(committer) => committer,
+            None => return,
+        };
+        let expired: Vec<_> = index
+            .memory_entries()
+            .filter(|(_, slot)| matches!(slot, Slot::Live(cmd_pos) if cmd_pos.is_expired()))
+            .map(|(key, _)| key)
+            .collect();
+        if !expired.is_empty() {
+            if let Ok(mut writer) = committer.writer() {
+                writer.remove_expired(expired);
+                writer.compact_if_needed();
+            }
+        }
+    })
+}
+
+/// Makes a copy of `err` for each of the writers of a group that failed as a whole.
+fn share_error(err: &KvsError) -> KvsError {
+    match err {
+        KvsError::Io(err) => KvsError::Io(io::Error::new(err.kind(), err.to_string())),
+        err => KvsError::StringError(err.to_string()),
+    }
 }
 
 impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
     ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let mut kvs_writer = self.kvs_writer.lock()?;
-        kvs_writer.set(key, value)
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writable()?.committer.submit(WriteOp::Set { key, value, expires_at: None }).map(|_| ())
     }
 
-    /// Gets the string value of a given string key.
+    /// Sets the value of a key, which expires after `ttl`.
+    ///
+    /// The deadline is logged with the value, so it holds across a restart.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX));
+        self.writable()?.committer.submit(WriteOp::Set { key, value, expires_at: Some(expires_at) }).map(|_| ())
+    }
+
+    /// Returns how long a key has left before it expires, `None` if it does not.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let cmd_pos = match self.index.get(&key)? {
+            Some(cmd_pos) => cmd_pos,
+            None => return Err(KvsError::KeyNotFound),
+        };
+        match cmd_pos.expires_at {
+            _ if cmd_pos.is_expired() => Err(KvsError::KeyNotFound),
+            Some(deadline) => Ok(Some(Duration::from_millis(deadline.saturating_sub(now_millis())))),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
     ///
     /// # Errors
     ///
-    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
-    fn get(&self, key: String) -> Result<Option<String>> {
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected,
+    /// and `KvsError::Corruption` if the stored record fails its checksum.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+
+    /// Gets the value of a given key together with its version.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get_bytes`.
+    fn get_versioned(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Version)>> {
         self.kvs_reader.close_stale_readers();
 
-        if let Some(entry) = self.index.get(&key) {
-            let cmd_pos = entry.value();
-            self.kvs_reader.read_and(
-                cmd_pos,
-                |cmd_reader| {
-                    if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                        Ok(Some(value))
-                    } else {
-                        Err(KvsError::UnexpectedCommandType)
-                    }
+        loop {
+            let cmd_pos = match self.index.get(&key)? {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(None),
+            };
+            if cmd_pos.is_expired() {
+                return Ok(None);
+            }
+            if let Some(value) = self.cache.get(&key, cmd_pos.version) {
+                return Ok(Some((value, cmd_pos.version)));
+            }
+            let result = self.kvs_reader.read_value(&cmd_pos);
+            match result {
+                // a compaction finished and deleted the log between the index
+                // lookup and the read, so the entry has moved since.
+                Err(KvsError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound
+                    && self.index.get(&key)?.is_some_and(|moved| !moved.same_record(&cmd_pos)) => continue,
+                result => {
+                    let value = result?;
+                    self.cache.insert(&key, cmd_pos.version, &value);
+                    return Ok(Some((value, cmd_pos.version)));
                 }
-            )
-        } else {
-            Ok(None)
+            }
         }
     }
 
@@ -311,89 +1457,526 @@ impl KvsEngine for KvStore {
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Result<()> {
-        let mut kvs_writer = self.kvs_writer.lock()?;
-        kvs_writer.remove(key)
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.writable()?.committer.submit(WriteOp::Remove { key }).map(|_| ())
+    }
+
+    /// Applies all the writes of `batch` with a single log record, which is
+    /// replayed completely or not at all.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.writable()?.committer.submit(WriteOp::Batch { ops: batch.into_ops(), reads: Vec::new() }).map(|_| ())
+    }
+
+    /// Applies all the writes of `batch` like `KvStore::write_batch`, if every key of
+    /// `reads` is still at the version it was read at.
+    ///
+    /// The versions are checked by the writer of the group commit, so no other
+    /// write can slip in between the check and the batch.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if a key has changed, and
+    /// propagates I/O or serialization errors during writing the log.
+    fn commit(&self, reads: ReadVersions, batch: WriteBatch) -> Result<()> {
+        self.writable()?.committer.submit(WriteOp::Batch { ops: batch.into_ops(), reads }).map(|_| ())
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is `None`, provided its current
+    /// value is `expected`.
+    ///
+    /// The value is compared by the writer of the group commit, taking into account
+    /// the writes committed with it, so no other write can slip in between the
+    /// comparison and the swap.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading the current value
+    /// or writing the log.
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        self.writable()?.committer.submit(WriteOp::CompareAndSwap { key, expected, new })
+    }
+
+    /// Gets the value a key had right after the write with sequence number `seq`.
+    ///
+    /// Returns `None` if the key was absent then, or if that version is not kept by
+    /// the `RetentionPolicy`. Values set with a TTL are returned whether or not they
+    /// have expired since.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get_bytes`.
+    fn get_at_bytes(&self, key: Vec<u8>, seq: u64) -> Result<Option<Vec<u8>>> {
+        self.kvs_reader.close_stale_readers();
+
+        loop {
+            let version = match self.version_at(&key, seq)? {
+                Some(version) if !version.removed => version,
+                _ => return Ok(None),
+            };
+            match self.kvs_reader.read_value(&version.cmd_pos) {
+                // moved by a compaction, like in `KvStore::get_versioned`.
+                Err(KvsError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound
+                    && self.version_at(&key, seq)?.is_some_and(|moved| !moved.cmd_pos.same_record(&version.cmd_pos)) => continue,
+                result => return result.map(Some),
+            }
+        }
+    }
+
+    /// Returns the versions of a key kept by the `RetentionPolicy`, oldest first,
+    /// the current one included.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get_bytes`.
+    fn history_bytes(&self, key: Vec<u8>) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        self.kvs_reader.close_stale_readers();
+
+        'retry: loop {
+            let versions = self.versions(&key)?;
+            let mut values = Vec::with_capacity(versions.len());
+            for version in &versions {
+                let seq = version.cmd_pos.stamp.seq;
+                if version.removed {
+                    values.push((seq, None));
+                    continue;
+                }
+                match self.kvs_reader.read_value(&version.cmd_pos) {
+                    // moved by a compaction, like in `KvStore::get_versioned`.
+                    Err(KvsError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound
+                        && self.versions(&key)?.iter().any(|moved| {
+                            moved.cmd_pos.stamp.seq == seq && !moved.cmd_pos.same_record(&version.cmd_pos)
+                        }) => continue 'retry,
+                    result => values.push((seq, Some(result?))),
+                }
+            }
+            return Ok(values);
+        }
+    }
+
+    /// Compacts the logs, see `KvStore::compact`.
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    /// Returns the key/value pairs whose keys are in `range`, in key order.
+    ///
+    /// The pairs are read as the iterator advances, so writes made in the
+    /// meantime may or may not show up.
+    ///
+    /// # Errors
+    ///
+    /// Items propagate the errors of reading the values, like `KvStore::get_bytes`.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(self.read_pairs(self.index.range(range), limit))
+    }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, in key order.
+    ///
+    /// Same as `KvStore::scan_bytes` otherwise.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let entries = self
+            .index
+            .range((Bound::Included(prefix.clone()), Bound::Unbounded))
+            .take_while(move |entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix)));
+        Ok(self.read_pairs(entries, limit))
     }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Create a new log file with given generation number and write the file header,
+/// with `last_seq` the sequence number of the last write so far.
 ///
-/// Returns the writer to the log.
+/// Returns the writer to the log, positioned right after the header.
 fn new_log_file(
     path: &Path,
-    gen: u64
+    gen: u64,
+    last_seq: u64,
 ) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
+    let path = log_path(path, gen);
+    let mut writer = BufWriterWithPos::new(
         OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
             .open(&path)?,
     )?;
+    record::write_log_header(&mut writer, last_seq)?;
+    writer.flush()?;
+    // the log is only found again after a crash once its directory entry is on
+    // disk too.
+    sync_dir(&path)?;
     Ok(writer)
 }
 
+/// Returns the record format version in the header of the `log`, `None` if it
+/// was written as JSON or has no header.
+///
+/// # Errors
+///
+/// It returns an error for a version other than the current one.
+fn read_log_version(log: &Path) -> Result<Option<u8>> {
+    let mut file = File::open(log)?;
+    let mut header = [0u8; LOG_HEADER_LEN as usize];
+    let header_len = file.read(&mut header)?;
+    match header[LOG_MAGIC.len()] {
+        _ if header_len <= LOG_MAGIC.len() || header[..LOG_MAGIC.len()] != LOG_MAGIC => Ok(None),
+        version if version != LOG_VERSION => Err(KvsError::StringError(
+            format!("unsupported log version {} in {}", version, log.display())
+        )),
+        version => Ok(Some(version)),
+    }
+}
+
+/// Checks that the log of the given generation is in the current format, for a
+/// read-only store, which does not upgrade it.
+///
+/// Returns `false` for an empty log, which the writer of the directory has just
+/// created and not written the header of yet.
+fn check_log_format(path: &Path, gen: u64) -> Result<bool> {
+    let log = log_path(path, gen);
+    if fs::metadata(&log)?.len() == 0 {
+        return Ok(false);
+    }
+    match read_log_version(&log)? {
+        Some(LOG_VERSION) => Ok(true),
+        _ => Err(KvsError::StringError(
+            format!("{} must be upgraded by a store open for writing", log.display())
+        )),
+    }
+}
+
+/// Rewrites the log of the given generation in the binary record format if it
+/// was written as JSON, or is an empty file without header.
+///
+/// The records of the old log have no sequence number; they are numbered in log
+/// order after `last_seq`, which is left at the last number given.
+///
+/// The new log is written next to the old one and renamed over it once it is
+/// complete, so an interrupted upgrade leaves the original log untouched.
+fn upgrade_log_if_legacy(path: &Path, gen: u64, last_seq: &mut u64) -> Result<()> {
+    let log = log_path(path, gen);
+    if read_log_version(&log)? == Some(LOG_VERSION) {
+        return Ok(());
+    }
+    let file = File::open(&log)?;
+
+    let upgrade_path = path.join(format!("{}.upgrade", gen));
+    let mut seq = *last_seq;
+    // JSON logs have neither compression nor encryption, and are upgraded as
+    // they are.
+    let plain = ValueCodec::default();
+    let result = (|| -> Result<()> {
+        let mut writer = BufWriter::new(File::create(&upgrade_path)?);
+        record::write_log_header(&mut writer, seq)?;
+        let stream = Deserializer::from_reader(BufReader::new(file)).into_iter::<Command>();
+        for cmd in stream {
+            let stamp = Stamp { seq: seq + 1, ..Stamp::default() };
+            let record = match cmd? {
+                Command::Set { key, value, .. } => Record::Set { key: key.into_bytes(), value: value.into_bytes(), expires_at: None, stamp },
+                Command::Rm { key, .. } => Record::Remove { key: key.into_bytes(), stamp },
+                _ => continue,
+            };
+            record.encode_to(&mut writer, &plain)?;
+            seq += 1;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&upgrade_path, &log)?;
+        // offsets into the old log are meaningless now.
+        hint::remove_hint(path, gen)?;
+        index::remove_index(path, gen)?;
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => *last_seq = seq,
+        Err(_) => {
+            let _ = fs::remove_file(&upgrade_path);
+        }
+    }
+    result
+}
+
 /// Returns sorted generation numbers in the given directory.
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
+    sorted_ids(path, "log")
+}
+
+/// Returns the sorted numbers the files with the given extension in the given
+/// directory are named after.
+fn sorted_ids(path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
         .flat_map(|path| {
-            path.file_name()
+            path.file_stem()
                 .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
                 .map(str::parse::<u64>)
         })
         .flatten()
         .collect();
-    gen_list.sort_unstable();
-    Ok(gen_list)
+    ids.sort_unstable();
+    Ok(ids)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// Load the log file and store value locations in the index map, and the past
+/// versions the retention policy may keep in `history`.
+///
+/// The positions are taken from the hint file of the log if it has a valid one;
+/// otherwise the whole log is replayed. A torn record at the end of the log,
+/// left by a crash in the middle of a write, is truncated away. A damaged record
+/// anywhere else is reported as
+/// `KvsError::Corruption`, or skipped or truncated away depending on the
+/// recovery mode, and a value that fails to decrypt as
+/// `KvsError::AuthenticationFailed`. What is left out goes to the report of
+/// `recovery`.
+///
+/// `last_seq` is raised to the highest sequence number found.
 ///
 /// Returns how many bytes can be saved after a compaction.
 fn load(
+    path: &Path,
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
+    index: &Index,
+    history: &History,
+    values: &ValueCodec,
+    recovery: &mut Recovery,
+    last_seq: &mut u64,
 ) -> Result<u64> {
-    // To make sure we read from the beginning of the file.
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let reader = &mut BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
+    reader.seek(SeekFrom::Start(0))?;
+    *last_seq = (*last_seq).max(record::read_log_header(reader)?);
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if let Some(entries) = hint::read_hint(path, gen, file_len)? {
+        let mut uncompacted = 0;
+        for (key, version) in entries {
+            *last_seq = (*last_seq).max(version.cmd_pos.stamp.seq);
+            uncompacted += index_version(index, history, key, version)?;
+        }
+        return Ok(uncompacted);
+    }
+
+    // To make sure we read from the first record right after the file header.
+    let mut pos = reader.seek(SeekFrom::Start(LOG_HEADER_LEN))?;
     let mut uncompacted = 0; // number of bytes that can be saved after a compaction.
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+    // the rest of the log from the first damaged record on, read once for every
+    // record to resync after.
+    let mut rest: Option<(u64, Vec<u8>)> = None;
+    loop {
+        let (record, len) = match Record::decode_from(reader, values)? {
+            Decoded::Record(record, len) => (record, len),
+            Decoded::Batch(records, len) => {
+                // nothing points at the batch header, so it is stale right away.
+                uncompacted += BATCH_HEADER_LEN;
+                let mut inner_pos = pos + BATCH_HEADER_LEN;
+                for (record, inner_len) in records {
+                    uncompacted += index_record(index, history, record, (gen, inner_pos..inner_pos + inner_len).into(), last_seq)?;
+                    inner_pos += inner_len;
                 }
-                let new_len = new_pos - pos;
-                index.insert(key, CommandPos{gen, pos, len: new_len});
+                pos += len;
+                continue;
             }
-            Command::Rm { key, .. } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+            Decoded::Eof => break,
+            // authenticated records are complete, and never taken for a torn tail.
+            Decoded::Unauthenticated => return Err(KvsError::AuthenticationFailed { gen, offset: pos }),
+            damaged @ (Decoded::Torn | Decoded::Corrupted(_)) => {
+                let len = match damaged {
+                    Decoded::Corrupted(len) if pos + len < file_len => Some(len),
+                    _ => None,
+                };
+                // a record whose length is damaged runs past the end of the log
+                // like one a crash cut short, so only a damaged record that
+                // neither ends before the end of the log nor has a valid record
+                // after it is taken for a torn tail.
+                if rest.is_none() {
+                    let mut bytes = Vec::new();
+                    reader.seek(SeekFrom::Start(pos))?;
+                    reader.read_to_end(&mut bytes)?;
+                    rest = Some((pos, bytes));
                 }
-                // the "remove" command itself can be deleted in the next compaction.
-                // so we add its length to `uncompacted`.
-                uncompacted += new_pos - pos;
+                let (rest_pos, bytes) = rest.as_ref().expect("the rest of the log was just read");
+                let next = resync_after(&bytes[(pos - rest_pos) as usize..], pos, len, values);
+                match (next, recovery.mode) {
+                    (None, _) if len.is_none() => {}
+                    (_, RecoveryMode::Strict) => return Err(KvsError::Corruption { gen, offset: pos }),
+                    (Some(next), RecoveryMode::SkipCorrupted) => {
+                        recovery.report.skip(gen, pos..next);
+                        uncompacted += next - pos;
+                        pos = reader.seek(SeekFrom::Start(next))?;
+                        continue;
+                    }
+                    _ => {}
+                }
+                truncate_log(path, gen, pos, file_len, recovery)?;
+                break;
             }
-            _ => ()
-        }
-        pos = new_pos;
+        };
+        uncompacted += index_record(index, history, record, (gen, pos..pos + len).into(), last_seq)?;
+        pos += len;
     }
     Ok(uncompacted)
 }
 
+/// Returns the position of the next record whose checksum matches after the
+/// damaged one at `pos`, `bytes` being the log from there on, trying first right
+/// after it if its length `len` is known.
+fn resync_after(bytes: &[u8], pos: u64, len: Option<u64>, values: &ValueCodec) -> Option<u64> {
+    // a damaged batch holds records of its own, which are not to be replayed
+    // apart from it unless its length is damaged too.
+    if let Some(len) = len.filter(|&len| record::is_record_at(&bytes[len as usize..], values)) {
+        return Some(pos + len);
+    }
+    record::resync(bytes, values).map(|offset| pos + offset as u64)
+}
+
+/// Truncates the log `gen`, `file_len` bytes long, right before `pos`, and
+/// reports it to `recovery`. A read-only store only stops reading there, its tail
+/// possibly being a write in progress.
+fn truncate_log(path: &Path, gen: u64, pos: u64, file_len: u64, recovery: &mut Recovery) -> Result<()> {
+    if !recovery.read_only {
+        OpenOptions::new().write(true).open(log_path(path, gen))?.set_len(pos)?;
+    }
+    recovery.report.skip(gen, pos..file_len);
+    Ok(())
+}
+
+/// Installs in `index` the sorted index of the newest log that has a valid one.
+///
+/// Returns the generation of that log.
+fn install_last_sorted_index(path: &Path, gen_list: &[u64], index: &Index) -> Result<Option<u64>> {
+    for &gen in gen_list.iter().rev() {
+        let log_len = fs::metadata(log_path(path, gen))?.len();
+        if let Some(sorted) = index.open_sorted(path, gen, log_len)? {
+            index.install(sorted);
+            return Ok(Some(gen));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the compacted log `gen`, whose sorted index is in place, for the
+/// sequence number it was created at and the past versions it holds, which go to
+/// `history` if it is kept.
+///
+/// A compaction copies the current versions first, in the order of the sorted
+/// index, then the past ones, which thus start `live` bytes after the header,
+/// `live` being the total length of the records the sorted index points at.
+fn load_compacted_history(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    live: u64,
+    history: &History,
+    values: &ValueCodec,
+    last_seq: &mut u64,
+) -> Result<()> {
+    reader.seek(SeekFrom::Start(0))?;
+    *last_seq = (*last_seq).max(record::read_log_header(reader)?);
+    if !history.is_enabled() {
+        return Ok(());
+    }
+
+    let mut pos = reader.seek(SeekFrom::Start(LOG_HEADER_LEN + live))?;
+    loop {
+        let (record, len) = match Record::decode_from(reader, values)? {
+            Decoded::Record(record, len) => (record, len),
+            Decoded::Eof => return Ok(()),
+            Decoded::Unauthenticated => return Err(KvsError::AuthenticationFailed { gen, offset: pos }),
+            // compacted logs are synced before their sorted index is written.
+            _ => return Err(KvsError::Corruption { gen, offset: pos }),
+        };
+        let (key, version) = record_version(record, (gen, pos..pos + len).into());
+        *last_seq = (*last_seq).max(version.cmd_pos.stamp.seq);
+        history.insert(&key, version);
+        pos += len;
+    }
+}
+
+/// Applies a set or remove record found at `cmd_pos` like `index_version`, and
+/// raises `last_seq` to its sequence number.
+///
+/// Returns how many bytes it made stale.
+fn index_record(
+    index: &Index,
+    history: &History,
+    record: Record,
+    cmd_pos: CommandPos,
+    last_seq: &mut u64,
+) -> Result<u64> {
+    let (key, version) = record_version(record, cmd_pos);
+    *last_seq = (*last_seq).max(version.cmd_pos.stamp.seq);
+    index_version(index, history, key, version)
+}
+
+/// Returns the key of a set or remove record found at `cmd_pos`, and the version
+/// it is.
+fn record_version(record: Record, cmd_pos: CommandPos) -> (Vec<u8>, VersionPos) {
+    match record {
+        Record::Set { key, expires_at, stamp, .. } | Record::Pointer { key, expires_at, stamp, .. } => {
+            (key, VersionPos { cmd_pos: CommandPos { expires_at, stamp, ..cmd_pos }, removed: false })
+        }
+        Record::Remove { key, stamp } => (key, VersionPos { cmd_pos: CommandPos { stamp, ..cmd_pos }, removed: true }),
+    }
+}
+
+/// Applies a version of `key` found in a log to the index, or to the history if
+/// it is kept.
+///
+/// Logs are loaded oldest first, but a log that a compaction retired and a crash
+/// left behind holds older copies of records of the compacted log, so versions
+/// are ordered by their sequence number rather than by where they were found:
+/// the latest one is the current version, unless it is a removal or has expired.
+/// A copy of a version already found replaces it.
+///
+/// Returns how many bytes it made stale.
+fn index_version(index: &Index, history: &History, key: Vec<u8>, version: VersionPos) -> Result<u64> {
+    let VersionPos { cmd_pos, removed } = version;
+    let seq = cmd_pos.stamp.seq;
+    let current = index.get(&key)?;
+    if let Some(current) = current.filter(|current| current.stamp.seq == seq) {
+        index.insert(key, cmd_pos);
+        return Ok(current.len);
+    }
+
+    let newest = current.map(|current| current.stamp.seq).max(history.newest_seq(&key));
+    if newest.is_some_and(|newest| seq <= newest) {
+        if !history.is_enabled() {
+            return Ok(cmd_pos.len);
+        }
+        return Ok(history.insert(&key, version).map_or(0, |copy| copy.len));
+    }
+
+    let mut stale = 0;
+    if let Some(current) = current {
+        if history.is_enabled() {
+            history.insert(&key, VersionPos { cmd_pos: current, removed: false });
+        } else {
+            stale += current.len;
+        }
+    }
+    if removed || cmd_pos.is_expired() {
+        index.remove(&key, cmd_pos.gen);
+        if history.is_enabled() {
+            history.insert(&key, version);
+        } else {
+            // the "remove" record itself can be deleted in the next compaction.
+            stale += cmd_pos.len;
+        }
+    } else {
+        index.insert(key, cmd_pos);
+    }
+    Ok(stale)
+}
+
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
 /// Struct representing a command.
+///
+/// Besides being the `kvs-client` subcommands, it is the shape of the records in
+/// legacy JSON log files, which are upgraded on open.
 #[derive(Serialize, Deserialize, Debug, Subcommand)]
 pub enum Command {
     /// set the key with supplied value in database
@@ -403,6 +1986,11 @@ pub enum Command {
 
         /// value to set
         value: String,
+
+        /// number of seconds after which the key expires
+        #[serde(skip)]
+        #[arg(long)]
+        ttl: Option<u64>,
         
         /// address:port of kvs server
         #[serde(skip)]
@@ -425,29 +2013,126 @@ pub enum Command {
     Get {
         /// key to get
         key: String,
+
+        /// sequence number of the write to get the value right after
+        #[serde(skip)]
+        #[arg(long)]
+        at: Option<u64>,
         
         /// address:port of kvs server
         #[serde(skip)]
         #[arg(long, default_value_t = DEFAULT_ADDR.to_string())]
         addr: String
     },
-}
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value, addr: String::from("") }
-    }
+    /// get the number of seconds left before the key expires
+    Ttl {
+        /// key to query
+        key: String,
 
-    fn remove(key: String) -> Command {
-        Command::Rm { key, addr: String::from("") }
-    }
+        /// address:port of kvs server
+        #[serde(skip)]
+        #[arg(long, default_value_t = DEFAULT_ADDR.to_string())]
+        addr: String
+    },
+
+    /// list the kept versions of the key, oldest first, one per line: the sequence
+    /// number of the write, then the value after a tab unless the key was removed
+    History {
+        /// key to query
+        key: String,
+
+        /// address:port of kvs server
+        #[serde(skip)]
+        #[arg(long, default_value_t = DEFAULT_ADDR.to_string())]
+        addr: String
+    },
+
+    /// list the key/value pairs in key order, one pair per line
+    Scan {
+        /// first key to list
+        #[arg(long, conflicts_with = "prefix")]
+        start: Option<String>,
+
+        /// key to stop listing at, excluded
+        #[arg(long, conflicts_with = "prefix")]
+        end: Option<String>,
+
+        /// only list the keys starting with this prefix
+        #[arg(long)]
+        prefix: Option<String>,
+
+        /// maximum number of pairs to list
+        #[arg(long)]
+        limit: Option<usize>,
+
+        /// address:port of kvs server
+        #[serde(skip)]
+        #[arg(long, default_value_t = DEFAULT_ADDR.to_string())]
+        addr: String
+    },
+
+    /// set the key to a new value, or remove it, only if it holds the expected value
+    Cas {
+        /// key to swap
+        key: String,
+
+        /// value the key must hold, the key must not exist if not given
+        #[arg(long)]
+        expected: Option<String>,
+
+        /// value to set, the key is removed if not given
+        #[arg(long)]
+        new: Option<String>,
+
+        /// address:port of kvs server
+        #[serde(skip)]
+        #[arg(long, default_value_t = DEFAULT_ADDR.to_string())]
+        addr: String
+    },
+
+    /// compact the database to reclaim space of overwritten and removed values
+    Compact {
+        /// address:port of kvs server
+        #[serde(skip)]
+        #[arg(long, default_value_t = DEFAULT_ADDR.to_string())]
+        addr: String
+    },
 }
 
-/// Represents the position and length of a json-serialized command in the log.
+/// Represents the position and length of a record in the log, the version of
+/// the write it holds, when the key expires, and the stamp of the write.
+///
+/// The version is where the record was first written, or where it was found on
+/// open; a compaction moves the record but keeps its version.
+#[derive(Clone, Copy, Debug)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    version: Version,
+    // deadline in milliseconds since the UNIX epoch.
+    expires_at: Option<u64>,
+    stamp: Stamp,
+}
+
+impl CommandPos {
+    /// Whether both point at the same record.
+    fn same_record(&self, other: &CommandPos) -> bool {
+        self.gen == other.gen && self.pos == other.pos
+    }
+
+    /// Whether the key has expired, in which case it reads as absent.
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|deadline| deadline <= now_millis())
+    }
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -456,10 +2141,17 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            version: Version { gen, pos: range.start },
+            expires_at: None,
+            stamp: Stamp::default(),
         }
     }
 }
 
+/// The keys written so far by the group being committed, and where their set
+/// record is in the group's buffer, `None` if they were removed.
+type GroupWrites = HashMap<Vec<u8>, Option<Range<u64>>>;
+
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -467,7 +2159,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
@@ -497,7 +2189,7 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
@@ -505,6 +2197,14 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
     }
 }
 
+impl BufWriterWithPos<File> {
+    /// Flushes the buffer and syncs the file data to disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
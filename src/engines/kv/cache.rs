@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock};
+
+use super::ReadCachePolicy;
+use crate::Version;
+
+/// Caches the values `KvStore::get` reads from the logs, so that hot keys are
+/// served without a seek and a decode.
+///
+/// An entry is looked up by key, and only hits if it holds the version the index
+/// points at, so a value overwritten, removed or expired since it was cached is
+/// never returned. Writes invalidate the keys they touch all the same, so that
+/// the space goes to values that can still hit. A compaction moves records but
+/// keeps their versions, so the entries outlive it.
+///
+/// A poisoned lock is taken over: an entry is inserted or evicted as a whole, and
+/// checked against the index on every hit, so a panic elsewhere cannot make the
+/// cache return a wrong value.
+pub struct ReadCache {
+    store: CacheStore,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+enum CacheStore {
+    Disabled,
+    Lru(Mutex<Lru>),
+    Clock(RwLock<Clock>),
+}
+
+/// Evicts the least recently read or inserted entry first. Every hit moves its
+/// entry to the back of the order, so hits take the lock exclusively.
+struct Lru {
+    capacity: u64,
+    size: u64,
+    clock: u64,
+    entries: HashMap<Vec<u8>, LruEntry>,
+    // the key of every entry by its last use.
+    order: BTreeMap<u64, Vec<u8>>,
+}
+
+struct LruEntry {
+    version: Version,
+    value: Vec<u8>,
+    used: u64,
+}
+
+/// Approximates `Lru` with the second chance algorithm: a hit only sets the
+/// reference bit of its entry, so hits share the lock, and the hand sweeping the
+/// slots for an entry to evict clears the bits it passes, evicting the first entry
+/// not referenced since the last sweep.
+struct Clock {
+    capacity: u64,
+    size: u64,
+    slots: Vec<Option<ClockEntry>>,
+    // the slot of every key.
+    keys: HashMap<Vec<u8>, usize>,
+    free: Vec<usize>,
+    hand: usize,
+}
+
+struct ClockEntry {
+    key: Vec<u8>,
+    version: Version,
+    value: Vec<u8>,
+    referenced: AtomicBool,
+}
+
+impl ReadCache {
+    pub fn new(policy: ReadCachePolicy) -> ReadCache {
+        let store = match policy {
+            ReadCachePolicy::Disabled => CacheStore::Disabled,
+            ReadCachePolicy::Lru(capacity) => CacheStore::Lru(Mutex::new(Lru {
+                capacity,
+                size: 0,
+                clock: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            })),
+            ReadCachePolicy::Clock(capacity) => CacheStore::Clock(RwLock::new(Clock {
+                capacity,
+                size: 0,
+                slots: Vec::new(),
+                keys: HashMap::new(),
+                free: Vec::new(),
+                hand: 0,
+            })),
+        };
+        ReadCache {
+            store,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the value of `key` at `version` if it is cached.
+    pub fn get(&self, key: &[u8], version: Version) -> Option<Vec<u8>> {
+        let value = match &self.store {
+            CacheStore::Disabled => return None,
+            CacheStore::Lru(lru) => lru.lock().unwrap_or_else(PoisonError::into_inner).get(key, version),
+            CacheStore::Clock(clock) => clock.read().unwrap_or_else(PoisonError::into_inner).get(key, version),
+        };
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Caches `value` as the value of `key` at `version`, unless it is larger
+    /// than the whole cache.
+    pub fn insert(&self, key: &[u8], version: Version, value: &[u8]) {
+        match &self.store {
+            CacheStore::Disabled => {}
+            CacheStore::Lru(lru) => lru.lock().unwrap_or_else(PoisonError::into_inner).insert(key, version, value),
+            CacheStore::Clock(clock) => clock.write().unwrap_or_else(PoisonError::into_inner).insert(key, version, value),
+        }
+    }
+
+    /// Drops the entry of `key`, if any.
+    pub fn invalidate(&self, key: &[u8]) {
+        match &self.store {
+            CacheStore::Disabled => {}
+            CacheStore::Lru(lru) => lru.lock().unwrap_or_else(PoisonError::into_inner).remove(key),
+            CacheStore::Clock(clock) => clock.write().unwrap_or_else(PoisonError::into_inner).remove(key),
+        }
+    }
+
+    /// Returns the number of hits and misses so far.
+    pub fn counters(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of entries cached and the bytes of their keys and values.
+    pub fn usage(&self) -> (usize, u64) {
+        match &self.store {
+            CacheStore::Disabled => (0, 0),
+            CacheStore::Lru(lru) => {
+                let lru = lru.lock().unwrap_or_else(PoisonError::into_inner);
+                (lru.entries.len(), lru.size)
+            }
+            CacheStore::Clock(clock) => {
+                let clock = clock.read().unwrap_or_else(PoisonError::into_inner);
+                (clock.keys.len(), clock.size)
+            }
+        }
+    }
+}
+
+impl Lru {
+    fn get(&mut self, key: &[u8], version: Version) -> Option<Vec<u8>> {
+        let entry = self.entries.get_mut(key).filter(|entry| entry.version == version)?;
+        let key = self.order.remove(&entry.used).expect("a cached key is in the order");
+        self.clock += 1;
+        entry.used = self.clock;
+        self.order.insert(self.clock, key);
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: &[u8], version: Version, value: &[u8]) {
+        let len = entry_len(key, value);
+        if len > self.capacity {
+            return;
+        }
+        self.remove(key);
+        while self.size + len > self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("a cache over capacity has entries");
+            let evicted = self.entries.remove(&oldest).expect("a key in the order is cached");
+            self.size -= entry_len(&oldest, &evicted.value);
+        }
+        self.clock += 1;
+        self.size += len;
+        self.order.insert(self.clock, key.to_vec());
+        self.entries.insert(key.to_vec(), LruEntry { version, value: value.to_vec(), used: self.clock });
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.size -= entry_len(key, &entry.value);
+        }
+    }
+}
+
+impl Clock {
+    fn get(&self, key: &[u8], version: Version) -> Option<Vec<u8>> {
+        let entry = self.slots[*self.keys.get(key)?]
+            .as_ref()
+            .filter(|entry| entry.version == version)?;
+        entry.referenced.store(true, Ordering::Relaxed);
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: &[u8], version: Version, value: &[u8]) {
+        let len = entry_len(key, value);
+        if len > self.capacity {
+            return;
+        }
+        self.remove(key);
+        while self.size + len > self.capacity {
+            self.evict_one();
+        }
+        let entry = ClockEntry {
+            key: key.to_vec(),
+            version,
+            value: value.to_vec(),
+            // a new entry has to be hit once to outlive the next sweep.
+            referenced: AtomicBool::new(false),
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(entry);
+                slot
+            }
+            None => {
+                self.slots.push(Some(entry));
+                self.slots.len() - 1
+            }
+        };
+        self.size += len;
+        self.keys.insert(key.to_vec(), slot);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(slot) = self.keys.remove(key) {
+            let entry = self.slots[slot].take().expect("the slot of a cached key is taken");
+            self.size -= entry_len(key, &entry.value);
+            self.free.push(slot);
+        }
+    }
+
+    /// Moves the hand on to the first entry not referenced since it last passed,
+    /// and evicts it. The cache must not be empty.
+    fn evict_one(&mut self) {
+        loop {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+            let slot = self.hand;
+            self.hand += 1;
+            match &self.slots[slot] {
+                Some(entry) if entry.referenced.swap(false, Ordering::Relaxed) => {}
+                Some(entry) => {
+                    let key = entry.key.clone();
+                    self.remove(&key);
+                    return;
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// The bytes an entry is counted for.
+fn entry_len(key: &[u8], value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::io::Write;
+use std::iter;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Weak};
+use std::thread::{self, JoinHandle};
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+
+use super::group_commit::GroupCommit;
+use super::hint;
+use super::history::{History, VersionPos};
+use super::index::{self, Index, SortedIndex, SortedIndexWriter};
+use super::record::{Decoded, Record};
+use super::snapshot::SnapshotPins;
+use super::{log_path, new_log_file, sorted_gen_list, CommandPos, KvReader};
+use crate::{KvsError, Result};
+
+/// A version copied by a compaction: its key, where it was and where it is now,
+/// `None` if it had expired and was dropped instead.
+pub type MovedEntry = (Vec<u8>, CommandPos, Option<VersionPos>);
+
+/// Asks the compaction thread for a compaction. Requests from `KvStore::compact`
+/// carry a channel to send the outcome back on.
+pub type CompactionRequest = Option<Sender<Result<()>>>;
+
+/// Handle to the background thread that compacts a `KvStore`.
+///
+/// A compaction runs in three steps so that writes are only held up for the
+/// first and the last one:
+///
+/// 1. with the writer locked, the current log is sealed and writes move on to a
+///    new one;
+/// 2. without any lock, the live entries and the past versions kept of the sealed
+///    logs are copied into the compaction log, and the live ones listed in its
+///    sorted index if the index is bounded;
+/// 3. with the writer locked again, the index and the history are pointed at the
+///    copies of the versions that did not change in the meantime. A bounded index
+///    puts the sorted index in place instead.
+///
+/// The sealed logs are deleted after that, or once the last `Snapshot` taken
+/// before is dropped. Dropping the handle cancels a compaction in progress and
+/// waits for the thread to exit.
+pub struct Compactor {
+    trigger: Sender<CompactionRequest>,
+    cancelled: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Spawns the compaction thread, which compacts once per message sent on the
+    /// `trigger` end of the channel.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn(
+        (trigger, requests): (Sender<CompactionRequest>, Receiver<CompactionRequest>),
+        committer: Weak<GroupCommit>,
+        index: Arc<Index>,
+        history: Arc<History>,
+        kvs_reader: KvReader,
+        pins: Arc<SnapshotPins>,
+        path: Arc<PathBuf>,
+        barrier: Option<Arc<Barrier>>,
+    ) -> Compactor {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let worker = CompactionWorker {
+            committer,
+            index,
+            history,
+            kvs_reader,
+            pins,
+            path,
+            cancelled: cancelled.clone(),
+            barrier,
+        };
+        let handle = thread::spawn(move || {
+            for request in requests {
+                if worker.cancelled.load(Ordering::SeqCst) {
+                    break;
+                }
+                // a failed compaction leaves the sealed logs in place; they are
+                // picked up again by the next one.
+                let result = worker.compact();
+                if let Some(done) = request {
+                    let _ = done.send(result);
+                }
+            }
+        });
+        Compactor {
+            trigger,
+            cancelled,
+            handle: Some(handle),
+        }
+    }
+
+    /// Compacts everything written so far, waiting for the compaction to finish.
+    pub fn compact(&self) -> Result<()> {
+        let (done, outcome) = bounded(1);
+        self.trigger
+            .send(Some(done))
+            .map_err(|_| KvsError::StringError("compaction thread has exited".to_owned()))?;
+        outcome
+            .recv()
+            .map_err(|_| KvsError::StringError("compaction thread has exited".to_owned()))?
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        let _ = self.trigger.send(None);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct CompactionWorker {
+    committer: Weak<GroupCommit>,
+    index: Arc<Index>,
+    history: Arc<History>,
+    kvs_reader: KvReader,
+    pins: Arc<SnapshotPins>,
+    path: Arc<PathBuf>,
+    cancelled: Arc<AtomicBool>,
+    // where compactions wait after their first copy, see
+    // `KvStoreOptions::compaction_barrier`.
+    barrier: Option<Arc<Barrier>>,
+}
+
+impl CompactionWorker {
+    fn compact(&self) -> Result<()> {
+        let (compaction_gen, sealed_stale, last_seq) = match self.committer.upgrade() {
+            Some(committer) => committer.writer()?.seal_for_compaction()?,
+            None => return Ok(()),
+        };
+
+        let (moved, sorted) = match self.copy_live_entries(compaction_gen, last_seq) {
+            Ok(Some(copied)) => copied,
+            result => {
+                let _ = fs::remove_file(log_path(&self.path, compaction_gen));
+                let _ = hint::remove_hint(&self.path, compaction_gen);
+                let _ = index::remove_index(&self.path, compaction_gen);
+                if let Some(committer) = self.committer.upgrade() {
+                    committer.writer()?.abort_compaction(sealed_stale);
+                }
+                return result.map(|_| ());
+            }
+        };
+
+        match self.committer.upgrade() {
+            Some(committer) => committer.writer()?.finish_compaction(compaction_gen, moved, sorted),
+            None => return Ok(()),
+        }
+
+        // remove stale log files, unless a snapshot may still read them.
+        let stale_gens: Vec<u64> = sorted_gen_list(&self.path)?
+            .into_iter()
+            .take_while(|&gen| gen < compaction_gen)
+            .collect();
+        self.kvs_reader.logs.forget(&stale_gens);
+        self.pins.retire(&self.path, stale_gens)
+    }
+
+    /// Copies every entry still living in the sealed logs, and every past version
+    /// kept, into the compaction log. Expired entries are not copied, unless the
+    /// history is kept and they are to become part of it.
+    ///
+    /// The moves of the entries are returned for a full index, which also gets a
+    /// hint file. A bounded index, which does not hold every key in memory, gets
+    /// a sorted index instead, and only the moves of the past versions.
+    ///
+    /// The records are copied as they are, unless their values are compressed or
+    /// encrypted otherwise than they are written now, in which case they are
+    /// rewritten (see `record::ValueCodec::is_current`).
+    ///
+    /// Returns `None` if the store was dropped in the meantime.
+    fn copy_live_entries(
+        &self,
+        compaction_gen: u64,
+        last_seq: u64,
+    ) -> Result<Option<(Vec<MovedEntry>, Option<SortedIndex>)>> {
+        let bounded = self.index.is_bounded();
+        let mut compaction_writer = new_log_file(&self.path, compaction_gen, last_seq)?;
+        let mut sorted_writer = match bounded {
+            true => Some(SortedIndexWriter::create(&self.path, compaction_gen)?),
+            false => None,
+        };
+        let keep_expired = self.history.is_enabled();
+        // the index is gone through before the history: a version leaves the index
+        // for the history, so it cannot be missed in between.
+        let current = self
+            .index
+            .range((Bound::Unbounded, Bound::Unbounded))
+            .map(|entry| entry.map(|(key, cmd_pos)| (key, VersionPos { cmd_pos, removed: false }, true)));
+        let past = iter::once_with(|| self.history.versions_before(compaction_gen))
+            .flatten()
+            .map(|(key, version)| Ok((key, version, false)));
+        let values = &self.kvs_reader.values;
+        let mut moved = Vec::new();
+        let mut barrier = self.barrier.as_deref();
+        for entry in current.chain(past) {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            let (key, VersionPos { cmd_pos, removed }, is_current) = entry?;
+
+            if cmd_pos.gen >= compaction_gen {
+                continue;
+            }
+            if cmd_pos.is_expired() && !keep_expired {
+                moved.push((key, cmd_pos, None));
+                continue;
+            }
+            let new_pos = compaction_writer.pos;
+            let len = self.kvs_reader.read_and(&cmd_pos, |record| {
+                if values.is_current(record) {
+                    compaction_writer.write_all(record)?;
+                    return Ok(record.len() as u64);
+                }
+                match Record::decode_from(&mut &record[..], values)? {
+                    Decoded::Record(record, _) => record.encode_to(&mut compaction_writer, values),
+                    Decoded::Unauthenticated => Err(KvsError::AuthenticationFailed { gen: cmd_pos.gen, offset: cmd_pos.pos }),
+                    _ => Err(KvsError::Corruption { gen: cmd_pos.gen, offset: cmd_pos.pos }),
+                }
+            })?;
+            let new_pos = CommandPos { gen: compaction_gen, pos: new_pos, len, ..cmd_pos };
+            if let (Some(sorted_writer), true) = (&mut sorted_writer, is_current) {
+                sorted_writer.push(&key, &new_pos)?;
+            }
+            if !bounded || !is_current {
+                moved.push((key, cmd_pos, Some(VersionPos { cmd_pos: new_pos, removed })));
+            }
+            if let Some(barrier) = barrier.take() {
+                barrier.wait();
+                barrier.wait();
+            }
+        }
+        // the stale logs are deleted afterwards, so the compacted one must be on disk first.
+        compaction_writer.sync_data()?;
+        let log_len = compaction_writer.pos;
+        if let Some(sorted_writer) = sorted_writer {
+            sorted_writer.finish(log_len)?;
+            let sorted = self.index.open_sorted(&self.path, compaction_gen, log_len)?.ok_or_else(|| {
+                KvsError::StringError(format!("unable to read back the sorted index of log {}", compaction_gen))
+            })?;
+            return Ok(Some((moved, Some(sorted))));
+        }
+        hint::write_hint(
+            &self.path,
+            compaction_gen,
+            log_len,
+            moved.iter().filter_map(|(key, _, new_version)| Some((key, (*new_version)?))),
+        )?;
+        Ok(Some((moved, None)))
+    }
+}
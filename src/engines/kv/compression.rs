@@ -0,0 +1,90 @@
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::Compression;
+
+/// Codec of a value stored as is.
+pub const CODEC_NONE: u8 = 0;
+/// Codec of a value compressed with LZ4, its uncompressed length first.
+pub const CODEC_LZ4: u8 = 1;
+/// Codec of a value compressed into a zstd frame.
+pub const CODEC_ZSTD: u8 = 2;
+
+// values shorter than this are stored as is, as they hardly ever shrink.
+const MIN_COMPRESSED_LEN: usize = 64;
+
+/// Compresses the values of the set records a `KvStore` writes with the codec of
+/// its `Compression`, and counts the bytes of the values before and after.
+///
+/// Every value is compressed on its own, so that a record is read without its
+/// neighbours. A value that is short or does not shrink is stored as is.
+#[derive(Default)]
+pub struct Compressor {
+    compression: Compression,
+    recompress: bool,
+    raw: AtomicU64,
+    stored: AtomicU64,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression, recompress: bool) -> Compressor {
+        Compressor {
+            compression,
+            recompress,
+            raw: AtomicU64::new(0),
+            stored: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether compactions rewrite the records of other codecs with this one.
+    pub fn recompresses(&self) -> bool {
+        self.recompress
+    }
+
+    /// The codec the values are compressed with.
+    pub fn codec(&self) -> u8 {
+        match self.compression {
+            Compression::None => CODEC_NONE,
+            Compression::Lz4 => CODEC_LZ4,
+            Compression::Zstd(_) => CODEC_ZSTD,
+        }
+    }
+
+    /// Returns the codec `value` is to be stored with, and the bytes stored.
+    pub fn compress<'a>(&self, value: &'a [u8]) -> (u8, Cow<'a, [u8]>) {
+        let compressed = match self.compression {
+            _ if value.len() < MIN_COMPRESSED_LEN => None,
+            Compression::None => None,
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(value)),
+            // levels out of range are clamped by zstd, so it only fails on allocation.
+            Compression::Zstd(level) => zstd::bulk::compress(value, level).ok(),
+        };
+        let (codec, stored) = match compressed {
+            Some(compressed) if compressed.len() < value.len() => (self.codec(), Cow::Owned(compressed)),
+            _ => (CODEC_NONE, Cow::Borrowed(value)),
+        };
+        self.raw.fetch_add(value.len() as u64, Ordering::Relaxed);
+        self.stored.fetch_add(stored.len() as u64, Ordering::Relaxed);
+        (codec, stored)
+    }
+
+    /// Returns the bytes of the values compressed so far, before and after.
+    pub fn counters(&self) -> (u64, u64) {
+        (self.raw.load(Ordering::Relaxed), self.stored.load(Ordering::Relaxed))
+    }
+}
+
+/// Whether `codec` is one this build reads.
+pub fn is_known(codec: u8) -> bool {
+    codec <= CODEC_ZSTD
+}
+
+/// Returns the value `stored` with `codec`, or `None` if it fails to decompress.
+pub fn decompress(codec: u8, stored: Vec<u8>) -> Option<Vec<u8>> {
+    match codec {
+        CODEC_NONE => Some(stored),
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(&stored).ok(),
+        CODEC_ZSTD => zstd::stream::decode_all(&stored[..]).ok(),
+        _ => None,
+    }
+}
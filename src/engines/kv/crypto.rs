@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::{KvsError, Result};
+
+// key id (4) + nonce (12) before the ciphertext of an encrypted value
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// bytes an encrypted value takes on top of its plaintext.
+const SEALED_OVERHEAD: usize = KEY_ID_LEN + NONCE_LEN + TAG_LEN;
+
+// associated data of the fingerprint of a key, encrypted with the zero nonce,
+// which no record uses but with a chance of 2^-96.
+const FINGERPRINT_AAD: &[u8] = b"kvs key fingerprint";
+
+/// The AEAD cipher a `KvStore` encrypts values with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-256 in Galois/Counter Mode, fastest on CPUs with AES instructions.
+    Aes256Gcm,
+    /// ChaCha20-Poly1305, fastest without them.
+    ChaCha20Poly1305,
+}
+
+/// A 256-bit key, and the cipher to use it with.
+///
+/// A key is told apart from the others by a fingerprint computed from it, which
+/// every value encrypted with it is stored with, so no id has to be managed.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: Cipher,
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// Creates a key for `cipher` out of 32 bytes, which should be random.
+    pub fn new(cipher: Cipher, key: [u8; 32]) -> EncryptionKey {
+        EncryptionKey { cipher, key }
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey").field("cipher", &self.cipher).finish_non_exhaustive()
+    }
+}
+
+enum KeyCipher {
+    Aes(Box<Aes256Gcm>),
+    ChaCha(Box<ChaCha20Poly1305>),
+}
+
+impl KeyCipher {
+    fn new(key: &EncryptionKey) -> KeyCipher {
+        match key.cipher {
+            Cipher::Aes256Gcm => KeyCipher::Aes(Box::new(Aes256Gcm::new(&key.key.into()))),
+            Cipher::ChaCha20Poly1305 => KeyCipher::ChaCha(Box::new(ChaCha20Poly1305::new(&key.key.into()))),
+        }
+    }
+
+    fn encrypt(&self, nonce: &[u8], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            KeyCipher::Aes(cipher) => cipher.encrypt(nonce.into(), payload).ok(),
+            KeyCipher::ChaCha(cipher) => cipher.encrypt(nonce.into(), payload).ok(),
+        }
+    }
+
+    fn decrypt(&self, nonce: &[u8], payload: Payload) -> Option<Vec<u8>> {
+        match self {
+            KeyCipher::Aes(cipher) => cipher.decrypt(nonce.into(), payload).ok(),
+            KeyCipher::ChaCha(cipher) => cipher.decrypt(nonce.into(), payload).ok(),
+        }
+    }
+
+    fn fingerprint(&self) -> u32 {
+        let tag = self
+            .encrypt(&[0; NONCE_LEN], Payload { msg: &[], aad: FINGERPRINT_AAD })
+            .expect("an empty message always encrypts");
+        u32::from_le_bytes(tag[..KEY_ID_LEN].try_into().expect("key id is 4 bytes"))
+    }
+}
+
+/// The keys of a `KvStore`: the current one, which values are encrypted with,
+/// and the older ones, which the values written before a rotation are still
+/// read with until a compaction encrypts them with the current one.
+///
+/// An encrypted value is stored as the fingerprint of its key, a random nonce,
+/// then the ciphertext and its tag. The associated data binds it to its record
+/// (see `record::Record`), so it cannot be passed off as the value of another.
+#[derive(Default)]
+pub struct Keyring {
+    current: Option<u32>,
+    keys: HashMap<u32, KeyCipher>,
+}
+
+impl Keyring {
+    pub fn new(current: Option<&EncryptionKey>, old_keys: &[EncryptionKey]) -> Keyring {
+        let mut keys = HashMap::new();
+        for key in old_keys {
+            let cipher = KeyCipher::new(key);
+            keys.insert(cipher.fingerprint(), cipher);
+        }
+        let current = current.map(|key| {
+            let cipher = KeyCipher::new(key);
+            let id = cipher.fingerprint();
+            keys.insert(id, cipher);
+            id
+        });
+        Keyring { current, keys }
+    }
+
+    /// The fingerprint of the key values are encrypted with, if any.
+    pub fn current(&self) -> Option<u32> {
+        self.current
+    }
+
+    /// Returns `value` encrypted with the current key, or `None` without one.
+    pub fn encrypt(&self, aad: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let id = match self.current {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.keys[&id]
+            .encrypt(&nonce, Payload { msg: value, aad })
+            .ok_or_else(|| KvsError::StringError(format!("unable to encrypt a value of {} bytes", value.len())))?;
+        Ok(Some([&id.to_le_bytes()[..], &nonce, &ciphertext].concat()))
+    }
+
+    /// Returns the value `sealed` by `encrypt`, or `None` if it fails to
+    /// authenticate against its key and `aad`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownEncryptionKey` if the key of the value is not
+    /// in the keyring.
+    pub fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> Result<Option<Vec<u8>>> {
+        let id = match key_id(sealed) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let cipher = self.keys.get(&id).ok_or(KvsError::UnknownEncryptionKey(id))?;
+        let (nonce, ciphertext) = sealed[KEY_ID_LEN..].split_at(NONCE_LEN);
+        Ok(cipher.decrypt(nonce, Payload { msg: ciphertext, aad }))
+    }
+}
+
+/// Returns the fingerprint of the key the value `sealed` is encrypted with, or
+/// `None` if it is too short to be an encrypted value.
+pub fn key_id(sealed: &[u8]) -> Option<u32> {
+    if sealed.len() < SEALED_OVERHEAD {
+        return None;
+    }
+    Some(u32::from_le_bytes(sealed[..KEY_ID_LEN].try_into().expect("key id is 4 bytes")))
+}
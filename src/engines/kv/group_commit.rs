@@ -0,0 +1,77 @@
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::KvWriter;
+use crate::engines::BatchOp;
+use crate::{ReadVersions, Result};
+
+/// A write waiting to be committed to the log.
+pub enum WriteOp {
+    /// Sets `key` to `value`, until `expires_at` in milliseconds since the UNIX
+    /// epoch if given.
+    Set { key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64> },
+    /// Removes `key`, failing with `KvsError::KeyNotFound` if it is absent.
+    Remove { key: Vec<u8> },
+    /// Applies the writes of a `WriteBatch` with a single record, provided every
+    /// key of `reads` is still at the given version.
+    Batch { ops: Vec<BatchOp>, reads: ReadVersions },
+    /// Sets `key` to `new`, or removes it if `new` is `None`, provided its value is
+    /// `expected`, `None` meaning absent.
+    CompareAndSwap { key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>> },
+}
+
+// where the leader leaves the result of a queued write for its writer.
+type Slot = Arc<Mutex<Option<Result<bool>>>>;
+
+/// Group commit in front of the `KvWriter`.
+///
+/// Concurrent writers append their operation to a shared queue and then line up
+/// for the writer mutex. Whoever gets it first becomes the leader: it takes the
+/// whole queue, writes it with one flush (and at most one sync), and leaves every
+/// queued writer its result. The writers behind it find their result ready once
+/// they get the mutex, without touching the log.
+pub struct GroupCommit {
+    queue: Mutex<Vec<(WriteOp, Slot)>>,
+    writer: Mutex<KvWriter>,
+}
+
+impl GroupCommit {
+    pub fn new(writer: KvWriter) -> Self {
+        GroupCommit {
+            queue: Mutex::new(Vec::new()),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Commits `op` together with whatever other writes are queued by then.
+    ///
+    /// Returns once `op` is in the log and the index, with whether it was applied:
+    /// only a compare-and-swap whose comparison fails is not.
+    pub fn submit(&self, op: WriteOp) -> Result<bool> {
+        let slot: Slot = Arc::new(Mutex::new(None));
+        self.queue.lock()?.push((op, slot.clone()));
+
+        let mut writer = self.writer.lock()?;
+        if let Some(result) = slot.lock()?.take() {
+            // committed by an earlier leader.
+            return result;
+        }
+
+        let (ops, slots): (Vec<_>, Vec<_>) = mem::take(&mut *self.queue.lock()?)
+            .into_iter()
+            .unzip();
+        let results = writer.commit(ops);
+        for (waiting, result) in slots.iter().zip(results) {
+            *waiting.lock()? = Some(result);
+        }
+        writer.compact_if_needed();
+
+        let result = slot.lock()?.take().expect("the leader commits its own write");
+        result
+    }
+
+    /// Locks the writer, waiting for the group being committed to finish.
+    pub fn writer(&self) -> Result<MutexGuard<'_, KvWriter>> {
+        Ok(self.writer.lock()?)
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::history::VersionPos;
+use super::record::Stamp;
+use super::CommandPos;
+use crate::Result;
+
+/// Magic bytes at the start of every hint file.
+const HINT_MAGIC: [u8; 4] = *b"KVSH";
+/// Version of the hint format written by this build; hint files of other
+/// versions are ignored.
+const HINT_VERSION: u8 = 3;
+// magic + version + log length
+const HINT_HEADER_LEN: usize = HINT_MAGIC.len() + 1 + 8;
+// key length (4) + generation (8) + offset (8) + record length (8) + deadline (8)
+// + sequence number (8) + timestamp (8) + removed flag (1)
+const ENTRY_HEADER_LEN: usize = 53;
+const CRC_LEN: usize = 4;
+
+/// An entry of a hint file: a key and where the record of one of its versions is.
+pub type HintEntry = (Vec<u8>, VersionPos);
+
+/// Writes the hint file of the compacted log `gen`, which is `log_len` bytes long
+/// and holds the records of the versions in `entries`.
+///
+/// A hint file lists the key and position of every record in its log, without the
+/// values, so that opening the store does not need to read the whole log:
+///
+/// ```text
+/// +-------+---------+---------+---------+-----+---------+-------+
+/// | magic | version | log_len | entry 0 | ... | entry n |  crc  |
+/// | KVSH  |   u8    | u64 LE  |         |     |         | u32LE |
+/// +-------+---------+---------+---------+-----+---------+-------+
+///
+/// entry: | key_len u32LE | gen u64LE | pos u64LE | len u64LE | expires_at u64LE |
+///        | seq u64LE | timestamp u64LE | removed u8 | key |
+/// ```
+///
+/// `expires_at` is the deadline of the key in milliseconds since the UNIX epoch, or
+/// zero if it does not expire. `seq` and `timestamp` are the stamp of the record,
+/// and `removed` is 1 for a remove record. `crc` is the CRC-32 of everything
+/// before it.
+pub fn write_hint<'a, I>(dir: &Path, gen: u64, log_len: u64, entries: I) -> Result<()>
+where
+    I: IntoIterator<Item = (&'a Vec<u8>, VersionPos)>,
+{
+    let mut writer = HashingWriter {
+        inner: BufWriter::new(File::create(hint_path(dir, gen))?),
+        hasher: crc32fast::Hasher::new(),
+    };
+    writer.write_all(&HINT_MAGIC)?;
+    writer.write_all(&[HINT_VERSION])?;
+    writer.write_all(&log_len.to_le_bytes())?;
+    for (key, VersionPos { cmd_pos, removed }) in entries {
+        let key_len = u32::try_from(key.len()).expect("keys are checked to fit when logged");
+        writer.write_all(&key_len.to_le_bytes())?;
+        writer.write_all(&cmd_pos.gen.to_le_bytes())?;
+        writer.write_all(&cmd_pos.pos.to_le_bytes())?;
+        writer.write_all(&cmd_pos.len.to_le_bytes())?;
+        writer.write_all(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes())?;
+        writer.write_all(&cmd_pos.stamp.seq.to_le_bytes())?;
+        writer.write_all(&cmd_pos.stamp.timestamp.to_le_bytes())?;
+        writer.write_all(&[u8::from(removed)])?;
+        writer.write_all(key)?;
+    }
+    let crc = writer.hasher.finalize();
+    writer.inner.write_all(&crc.to_le_bytes())?;
+    writer.inner.flush()?;
+    Ok(())
+}
+
+/// Reads the hint file of the log `gen`, which must be `log_len` bytes long.
+///
+/// Returns `None` if there is no hint file, or if it is damaged or does not match
+/// the log, in which case the log has to be replayed instead.
+pub fn read_hint(dir: &Path, gen: u64, log_len: u64) -> Result<Option<Vec<HintEntry>>> {
+    let mut buf = Vec::new();
+    match File::open(hint_path(dir, gen)) {
+        Ok(file) => BufReader::new(file).read_to_end(&mut buf)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if buf.len() < HINT_HEADER_LEN + CRC_LEN {
+        return Ok(None);
+    }
+    let (body, crc) = buf.split_at(buf.len() - CRC_LEN);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().expect("crc is 4 bytes")) {
+        return Ok(None);
+    }
+    let (header, mut body) = body.split_at(HINT_HEADER_LEN);
+    if header[..HINT_MAGIC.len()] != HINT_MAGIC
+        || header[HINT_MAGIC.len()] != HINT_VERSION
+        || read_u64(&header[HINT_MAGIC.len() + 1..]) != log_len
+    {
+        return Ok(None);
+    }
+
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        if body.len() < ENTRY_HEADER_LEN {
+            return Ok(None);
+        }
+        let (entry, rest) = body.split_at(ENTRY_HEADER_LEN);
+        let key_len = u32::from_le_bytes(entry[..4].try_into().expect("length is 4 bytes")) as usize;
+        let (entry_gen, pos, len) = (read_u64(&entry[4..12]), read_u64(&entry[12..20]), read_u64(&entry[20..28]));
+        if rest.len() < key_len || entry_gen != gen || pos.saturating_add(len) > log_len {
+            return Ok(None);
+        }
+        let deadline = read_u64(&entry[28..36]);
+        let cmd_pos = CommandPos {
+            expires_at: (deadline != 0).then_some(deadline),
+            stamp: Stamp { seq: read_u64(&entry[36..44]), timestamp: read_u64(&entry[44..52]) },
+            ..(gen, pos..pos + len).into()
+        };
+        let (key, rest) = rest.split_at(key_len);
+        entries.push((key.to_vec(), VersionPos { cmd_pos, removed: entry[52] != 0 }));
+        body = rest;
+    }
+    Ok(Some(entries))
+}
+
+/// Removes the hint file of the log `gen`, if any.
+pub fn remove_hint(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().expect("field is 8 bytes"))
+}
+
+/// Computes the checksum of everything written through it.
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::options::RetentionPolicy;
+use super::index::Index;
+use super::{now_millis, CommandPos};
+
+/// A version of a key in the logs: where its record is, and whether the key was
+/// removed by it.
+#[derive(Clone, Copy, Debug)]
+pub struct VersionPos {
+    pub cmd_pos: CommandPos,
+    pub removed: bool,
+}
+
+/// The past versions of the keys of a `KvStore` that its `RetentionPolicy` keeps.
+///
+/// The current version of a key is the one in the index. The version it replaces
+/// moves here before the index is updated, so that a reader looking at the index
+/// and then at the history never misses it. A removal is kept as a version of its
+/// own, after the one it removed; so is a value once it has expired.
+pub struct History {
+    // the past versions of every key, by increasing sequence number.
+    versions: RwLock<BTreeMap<Vec<u8>, Vec<VersionPos>>>,
+    policy: RetentionPolicy,
+}
+
+impl History {
+    pub fn new(policy: RetentionPolicy) -> Self {
+        History { versions: RwLock::new(BTreeMap::new()), policy }
+    }
+
+    /// Whether the policy keeps any past version at all.
+    pub fn is_enabled(&self) -> bool {
+        match self.policy {
+            RetentionPolicy::Latest => false,
+            RetentionPolicy::LastVersions(versions) => versions > 1,
+            RetentionPolicy::NewerThan(_) => true,
+        }
+    }
+
+    /// Adds a past version of `key`, in sequence order.
+    ///
+    /// If a version with the same sequence number is there already, the record was
+    /// found again in another log: the new position replaces it, and the old one is
+    /// returned.
+    pub fn insert(&self, key: &[u8], version: VersionPos) -> Option<CommandPos> {
+        let mut map = self.write();
+        let versions = match map.get_mut(key) {
+            Some(versions) => versions,
+            None => map.entry(key.to_vec()).or_default(),
+        };
+        let seq = version.cmd_pos.stamp.seq;
+        match versions.binary_search_by_key(&seq, |past| past.cmd_pos.stamp.seq) {
+            Ok(i) => Some(mem::replace(&mut versions[i], version).cmd_pos),
+            Err(i) => {
+                versions.insert(i, version);
+                None
+            }
+        }
+    }
+
+    /// Returns the sequence number of the latest past version of `key`.
+    pub fn newest_seq(&self, key: &[u8]) -> Option<u64> {
+        self.read().get(key)?.last().map(|past| past.cmd_pos.stamp.seq)
+    }
+
+    /// Drops the past versions of `key` the policy no longer keeps, `has_current`
+    /// telling whether the key has a current version.
+    ///
+    /// Returns where their records are, which are stale from now on.
+    pub fn prune(&self, key: &[u8], has_current: bool) -> Vec<CommandPos> {
+        let mut map = self.write();
+        let pruned = match map.get_mut(key) {
+            Some(versions) => self.drain_pruned(versions, has_current),
+            None => return Vec::new(),
+        };
+        if map.get(key).is_some_and(Vec::is_empty) {
+            map.remove(key);
+        }
+        pruned
+    }
+
+    /// Drops the past versions of every key the policy no longer keeps, like
+    /// `History::prune`.
+    pub fn prune_all(&self, index: &Index) -> Vec<CommandPos> {
+        let mut map = self.write();
+        let mut pruned = Vec::new();
+        map.retain(|key, versions| {
+            // a key the index fails to look up is taken as present, which keeps
+            // one version more at worst.
+            let has_current = index.contains_key(key).unwrap_or(true);
+            pruned.extend(self.drain_pruned(versions, has_current));
+            !versions.is_empty()
+        });
+        pruned
+    }
+
+    /// Returns the latest past version of `key` whose sequence number is at most `seq`.
+    pub fn find_at(&self, key: &[u8], seq: u64) -> Option<VersionPos> {
+        let map = self.read();
+        let versions = map.get(key)?;
+        let end = versions.partition_point(|past| past.cmd_pos.stamp.seq <= seq);
+        end.checked_sub(1).map(|i| versions[i])
+    }
+
+    /// Returns the past versions of `key`, oldest first.
+    pub fn versions(&self, key: &[u8]) -> Vec<VersionPos> {
+        self.read().get(key).cloned().unwrap_or_default()
+    }
+
+    /// Returns the total length of the records of the past versions.
+    pub fn len_bytes(&self) -> u64 {
+        self.read().values().flatten().map(|past| past.cmd_pos.len).sum()
+    }
+
+    /// Returns the past versions whose records are in logs before `gen`.
+    pub fn versions_before(&self, gen: u64) -> Vec<(Vec<u8>, VersionPos)> {
+        self.read()
+            .iter()
+            .flat_map(|(key, versions)| versions.iter().map(move |past| (key.clone(), *past)))
+            .filter(|(_, past)| past.cmd_pos.gen < gen)
+            .collect()
+    }
+
+    /// Points the past version of `key` at `old` to `new`, a copy of its record.
+    ///
+    /// Returns `false` if the version has been dropped in the meantime.
+    pub fn relocate(&self, key: &[u8], old: &CommandPos, new: CommandPos) -> bool {
+        let mut map = self.write();
+        let past = map
+            .get_mut(key)
+            .and_then(|versions| versions.iter_mut().find(|past| past.cmd_pos.same_record(old)));
+        match past {
+            Some(past) => {
+                past.cmd_pos = new;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops the past version of `key` at `cmd_pos`.
+    pub fn forget(&self, key: &[u8], cmd_pos: &CommandPos) {
+        let mut map = self.write();
+        if let Some(versions) = map.get_mut(key) {
+            versions.retain(|past| !past.cmd_pos.same_record(cmd_pos));
+            if versions.is_empty() {
+                map.remove(key);
+            }
+        }
+    }
+
+    /// Removes the oldest versions the policy no longer keeps from `versions`.
+    fn drain_pruned(&self, versions: &mut Vec<VersionPos>, has_current: bool) -> Vec<CommandPos> {
+        let keep = match self.policy {
+            _ if !self.is_enabled() => 0,
+            RetentionPolicy::LastVersions(max) => max - usize::from(has_current),
+            RetentionPolicy::NewerThan(age) => {
+                let oldest = now_millis().saturating_sub(age.as_millis().try_into().unwrap_or(u64::MAX));
+                versions.len() - versions.partition_point(|past| past.cmd_pos.stamp.timestamp < oldest)
+            }
+            RetentionPolicy::Latest => 0,
+        };
+        let end = versions.len().saturating_sub(keep);
+        versions.drain(..end).map(|past| past.cmd_pos).collect()
+    }
+
+    // the map is consistent between every two calls, so a panic elsewhere does
+    // not make it unusable.
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<Vec<u8>, Vec<VersionPos>>> {
+        self.versions.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<Vec<u8>, Vec<VersionPos>>> {
+        self.versions.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,734 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Fuse;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::vec;
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+
+use super::options::IndexMode;
+use super::record::Stamp;
+use super::CommandPos;
+use crate::{KvsError, Result, Version};
+
+/// Magic bytes at the start of every sorted index file.
+const INDEX_MAGIC: [u8; 4] = *b"KVSI";
+/// Version of the sorted index format written by this build.
+const INDEX_VERSION: u8 = 1;
+// magic + version
+const INDEX_HEADER_LEN: u64 = INDEX_MAGIC.len() as u64 + 1;
+// key length (4) + offset (8) + record length (8) + version (16) + deadline (8)
+// + sequence number (8) + timestamp (8)
+const ENTRY_HEADER_LEN: usize = 60;
+// key length (4) + offset (8) + length (4) + crc (4)
+const BLOCK_REF_LEN: usize = 20;
+// table offset (8) + table crc (4) + log length (8) + live bytes (8)
+const TRAILER_LEN: u64 = 28;
+/// Size past which a block of entries is closed and a new one started.
+const BLOCK_SIZE: usize = 4096;
+
+/// The entry of a key in memory.
+#[derive(Clone, Copy, Debug)]
+pub enum Slot {
+    /// The current version of the key is the record at the given position.
+    Live(CommandPos),
+    /// The key was removed by a record in the log `gen`, while the sorted index
+    /// may still list it.
+    Removed { gen: u64 },
+}
+
+impl Slot {
+    fn live(self) -> Option<CommandPos> {
+        match self {
+            Slot::Live(cmd_pos) => Some(cmd_pos),
+            Slot::Removed { .. } => None,
+        }
+    }
+
+    /// The log the entry was written to.
+    fn gen(self) -> u64 {
+        match self {
+            Slot::Live(cmd_pos) => cmd_pos.gen,
+            Slot::Removed { gen } => gen,
+        }
+    }
+}
+
+/// The keys of a `KvStore` and where their current record is.
+///
+/// With `IndexMode::Full` every key is in a `SkipMap` in memory. With
+/// `IndexMode::Bounded` the keys are in the `SortedIndex` written by the last
+/// compaction, and only the ones written since are in memory, removed ones
+/// included. Once the sorted index of a compaction is in place, the keys written
+/// before it are dropped from memory.
+///
+/// Only the writer changes the index; readers go through it concurrently.
+pub struct Index {
+    mem: SkipMap<Vec<u8>, AtomicCell<Slot>>,
+    bounded: Option<Bounded>,
+}
+
+struct Bounded {
+    // the sorted index of the last compaction, if any.
+    sorted: RwLock<Option<Arc<SortedIndex>>>,
+    cache_entries: usize,
+    max_memory_keys: usize,
+}
+
+impl Index {
+    pub fn new(mode: IndexMode) -> Index {
+        let bounded = match mode {
+            IndexMode::Full => None,
+            IndexMode::Bounded { cache_entries, max_memory_keys } => Some(Bounded {
+                sorted: RwLock::new(None),
+                cache_entries,
+                max_memory_keys,
+            }),
+        };
+        Index { mem: SkipMap::new(), bounded }
+    }
+
+    /// Whether the keys are kept in sorted index files rather than in memory.
+    pub fn is_bounded(&self) -> bool {
+        self.bounded.is_some()
+    }
+
+    /// Whether a bounded index holds more keys in memory than it may, which calls
+    /// for a compaction.
+    pub fn over_budget(&self) -> bool {
+        self.bounded
+            .as_ref()
+            .is_some_and(|bounded| self.mem.len() > bounded.max_memory_keys)
+    }
+
+    /// Returns where the current record of `key` is, `None` if it is absent.
+    pub fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        match self.mem.get(key) {
+            Some(entry) => Ok(entry.value().load().live()),
+            // a key only leaves memory once the sorted index holding it is in
+            // place, so it is looked up there after memory.
+            None => match self.sorted() {
+                Some(sorted) => sorted.get(key),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Whether `key` is present.
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Points `key` at `cmd_pos`.
+    pub fn insert(&self, key: Vec<u8>, cmd_pos: CommandPos) {
+        self.store(key, Slot::Live(cmd_pos));
+    }
+
+    /// Drops `key`, removed by a record in the log `gen`.
+    pub fn remove(&self, key: &[u8], gen: u64) {
+        if self.is_bounded() {
+            // the sorted index, or the one a compaction in progress is writing,
+            // may list the key, so memory has to tell it is gone.
+            self.store(key.to_vec(), Slot::Removed { gen });
+        } else {
+            self.mem.remove(key);
+        }
+    }
+
+    /// Returns the entries held in memory, live or removed.
+    pub fn memory_entries(&self) -> impl Iterator<Item = (Vec<u8>, Slot)> + '_ {
+        self.mem.iter().map(|entry| (entry.key().clone(), entry.value().load()))
+    }
+
+    /// Returns the entries of the keys in `range`, in key order.
+    ///
+    /// Entries are looked up as the iterator advances, so writes made in the
+    /// meantime may or may not show up, but a key present all along is never
+    /// missed, even if a compaction replaces the sorted index in between.
+    pub fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Entries<'_> {
+        let mem = self
+            .mem
+            .range(range.clone())
+            .map(|entry| (entry.key().clone(), entry.value().load()));
+        Entries::new(Box::new(mem), self.sorted(), Some(self), range)
+    }
+
+    /// Returns a copy of the entries in memory, with the sorted index they are
+    /// on top of, which a snapshot reads from.
+    pub fn freeze(&self) -> FrozenIndex {
+        FrozenIndex {
+            mem: self.memory_entries().collect(),
+            sorted: self.sorted(),
+        }
+    }
+
+    /// Returns the total length of the records the index points at.
+    pub fn live_bytes(&self) -> Result<u64> {
+        let sorted = self.sorted();
+        let mut live = sorted.as_ref().map_or(0, |sorted| sorted.live_bytes);
+        for entry in self.mem.iter() {
+            if let Some(sorted) = &sorted {
+                // the entry in memory takes the place of the one in the sorted index.
+                live -= sorted.get(entry.key())?.map_or(0, |cmd_pos| cmd_pos.len);
+            }
+            live += entry.value().load().live().map_or(0, |cmd_pos| cmd_pos.len);
+        }
+        Ok(live)
+    }
+
+    /// Opens the sorted index of the log `gen`, which must be `log_len` bytes long,
+    /// with the cache size of this index.
+    ///
+    /// Returns `None` if there is none, or if it is damaged or does not match the log.
+    pub fn open_sorted(&self, dir: &Path, gen: u64, log_len: u64) -> Result<Option<SortedIndex>> {
+        let cache_entries = self.bounded.as_ref().map_or(0, |bounded| bounded.cache_entries);
+        SortedIndex::open(dir, gen, log_len, cache_entries)
+    }
+
+    /// Puts `sorted` in place of the current sorted index, then drops the entries
+    /// in memory written to logs before the one it indexes, which it holds now.
+    ///
+    /// Readers that missed an entry in memory look at the new sorted index, as it
+    /// is in place before any entry is dropped.
+    pub fn install(&self, sorted: SortedIndex) {
+        let bounded = self.bounded.as_ref().expect("only a bounded index has sorted indexes");
+        let gen = sorted.gen;
+        *bounded.sorted.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(sorted));
+        for entry in self.mem.iter() {
+            if entry.value().load().gen() < gen {
+                entry.remove();
+            }
+        }
+    }
+
+    /// An existing entry is updated in place, as `SkipMap::insert` unlinks it
+    /// before linking the new one and a concurrent read could miss the key in
+    /// between. Only the writer changes the index, so no other entry can be
+    /// inserted for the key in the meantime.
+    fn store(&self, key: Vec<u8>, slot: Slot) {
+        match self.mem.get(&key) {
+            Some(entry) => entry.value().store(slot),
+            None => {
+                self.mem.insert(key, AtomicCell::new(slot));
+            }
+        }
+    }
+
+    fn sorted(&self) -> Option<Arc<SortedIndex>> {
+        let bounded = self.bounded.as_ref()?;
+        bounded.sorted.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+/// A copy of an `Index` as it was at some point, see `Index::freeze`.
+pub struct FrozenIndex {
+    mem: BTreeMap<Vec<u8>, Slot>,
+    sorted: Option<Arc<SortedIndex>>,
+}
+
+impl FrozenIndex {
+    /// Returns where the record of `key` was, `None` if it was absent.
+    pub fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        match (self.mem.get(key), &self.sorted) {
+            (Some(slot), _) => Ok(slot.live()),
+            (None, Some(sorted)) => sorted.get(key),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Returns the entries of the keys that were in `range`, in key order.
+    pub fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Entries<'_> {
+        let mem = self.mem.range(range.clone()).map(|(key, slot)| (key.clone(), *slot));
+        Entries::new(Box::new(mem), self.sorted.clone(), None, range)
+    }
+}
+
+/// The entries of a range of keys of an `Index` or a `FrozenIndex`, in key order:
+/// the ones in memory merged with the ones of the sorted index, memory first.
+pub(super) struct Entries<'a> {
+    mem: Fuse<Box<dyn Iterator<Item = (Vec<u8>, Slot)> + 'a>>,
+    // the next entry in memory, already taken from `mem`.
+    mem_next: Option<(Vec<u8>, Slot)>,
+    sorted: Option<Cursor>,
+    // the index the entries are read from while it changes, if any.
+    live: Option<&'a Index>,
+    // the entries returned from now on come after this bound.
+    lower: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl<'a> Entries<'a> {
+    fn new(
+        mem: Box<dyn Iterator<Item = (Vec<u8>, Slot)> + 'a>,
+        sorted: Option<Arc<SortedIndex>>,
+        live: Option<&'a Index>,
+        (start, end): (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Self {
+        Entries {
+            mem: mem.fuse(),
+            mem_next: None,
+            sorted: sorted.map(|sorted| Cursor::new(sorted, &start)),
+            live,
+            lower: start,
+            end,
+        }
+    }
+
+    /// Moves on to the sorted index of the live index if a compaction replaced it.
+    ///
+    /// It is checked after memory: a key written before the compaction only
+    /// leaves memory once the new sorted index is in place, so if memory no
+    /// longer has it, the new sorted index does.
+    fn follow_sorted(&mut self) {
+        let current = match self.live {
+            Some(live) => live.sorted(),
+            None => return,
+        };
+        let replaced = match (&self.sorted, &current) {
+            (Some(cursor), Some(current)) => !Arc::ptr_eq(&cursor.sorted, current),
+            (None, None) => false,
+            _ => true,
+        };
+        if replaced {
+            self.sorted = current.map(|sorted| Cursor::new(sorted, &self.lower));
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, CommandPos)>> {
+        loop {
+            if self.mem_next.is_none() {
+                self.mem_next = self.mem.next();
+            }
+            self.follow_sorted();
+            let sorted_next = match &mut self.sorted {
+                Some(cursor) => cursor.fill(&self.lower)?,
+                None => None,
+            };
+
+            let from_mem = match (&self.mem_next, sorted_next) {
+                (None, None) => return Ok(None),
+                (Some((mem_key, _)), Some(sorted_key)) => mem_key.as_slice() <= sorted_key,
+                (mem_next, _) => mem_next.is_some(),
+            };
+            let (key, cmd_pos) = if from_mem {
+                let (key, slot) = self.mem_next.take().expect("memory has an entry");
+                if let Some(cursor) = &mut self.sorted {
+                    cursor.skip(&key);
+                }
+                match slot {
+                    Slot::Live(cmd_pos) if after(&self.lower, &key) => (key, cmd_pos),
+                    Slot::Live(_) => continue,
+                    Slot::Removed { .. } => {
+                        self.lower = Bound::Excluded(key);
+                        continue;
+                    }
+                }
+            } else {
+                let cursor = self.sorted.as_mut().expect("the sorted index has an entry");
+                cursor.next.take().expect("the sorted index has an entry")
+            };
+            if !before(&self.end, &key) {
+                return Ok(None);
+            }
+            self.lower = Bound::Excluded(key.clone());
+            return Ok(Some((key, cmd_pos)));
+        }
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Result<(Vec<u8>, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+/// Whether `key` comes after the lower bound `lower`.
+fn after(lower: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match lower {
+        Bound::Included(lower) => key >= lower.as_slice(),
+        Bound::Excluded(lower) => key > lower.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// Whether `key` comes before the upper bound `end`.
+fn before(end: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match end {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// Reads the entries of a sorted index in key order, a block at a time.
+struct Cursor {
+    sorted: Arc<SortedIndex>,
+    // the next block to read.
+    block: usize,
+    entries: vec::IntoIter<(Vec<u8>, CommandPos)>,
+    // the next entry, already taken from `entries`.
+    next: Option<(Vec<u8>, CommandPos)>,
+}
+
+impl Cursor {
+    /// Starts at the block the first key after `lower` would be in.
+    fn new(sorted: Arc<SortedIndex>, lower: &Bound<Vec<u8>>) -> Self {
+        let block = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => sorted.block_of(key).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        Cursor { sorted, block, entries: Vec::new().into_iter(), next: None }
+    }
+
+    /// Makes `next` the first entry after `lower`, and returns its key.
+    fn fill(&mut self, lower: &Bound<Vec<u8>>) -> Result<Option<&[u8]>> {
+        loop {
+            match &self.next {
+                Some((key, _)) if after(lower, key) => break,
+                _ => {}
+            }
+            self.next = self.entries.next();
+            if self.next.is_none() {
+                if self.block == self.sorted.blocks.len() {
+                    break;
+                }
+                self.entries = self.sorted.read_block(self.block)?.into_iter();
+                self.block += 1;
+            }
+        }
+        Ok(self.next.as_ref().map(|(key, _)| key.as_slice()))
+    }
+
+    /// Drops the next entry if it is the one of `key`, which memory overrides.
+    fn skip(&mut self, key: &[u8]) {
+        if self.next.as_ref().is_some_and(|(next, _)| next.as_slice() == key) {
+            self.next = None;
+        }
+    }
+}
+
+/// A sorted index file, written by a compaction next to its log: the keys that
+/// were live in the compacted log, in key order, with where their record is.
+///
+/// The entries are grouped in blocks of about 4 KiB, each with a checksum. Only
+/// the first key of every block is kept in memory, and a lookup reads the one
+/// block the key can be in. The entries looked up most recently are cached:
+///
+/// ```text
+/// +-------+---------+---------+-----+---------+-------+---------+
+/// | magic | version | block 0 | ... | block n | table | trailer |
+/// | KVSI  |   u8    |         |     |         |       |         |
+/// +-------+---------+---------+-----+---------+-------+---------+
+///
+/// block entry: | key_len u32LE | pos u64LE | len u64LE | version gen u64LE |
+///              | version pos u64LE | expires_at u64LE | seq u64LE |
+///              | timestamp u64LE | key |
+/// table entry: | key_len u32LE | offset u64LE | len u32LE | crc u32LE | first key |
+/// trailer:     | table offset u64LE | table crc u32LE | log_len u64LE | live u64LE |
+/// ```
+///
+/// `expires_at` is zero for a key that does not expire, `crc` is the CRC-32 of
+/// the block, and `live` is the total length of the records indexed.
+pub struct SortedIndex {
+    // the log the entries point into.
+    gen: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    blocks: Vec<BlockRef>,
+    live_bytes: u64,
+    cache: Mutex<Lru>,
+}
+
+struct BlockRef {
+    first_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+    crc: u32,
+}
+
+impl SortedIndex {
+    fn open(dir: &Path, gen: u64, log_len: u64, cache_entries: usize) -> Result<Option<SortedIndex>> {
+        let path = index_path(dir, gen);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let file_len = file.seek(SeekFrom::End(0))?;
+        if file_len < INDEX_HEADER_LEN + TRAILER_LEN {
+            return Ok(None);
+        }
+        let mut header = [0u8; INDEX_HEADER_LEN as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        file.seek(SeekFrom::Start(file_len - TRAILER_LEN))?;
+        file.read_exact(&mut trailer)?;
+        let table_offset = read_u64(&trailer[..8]);
+        if header[..INDEX_MAGIC.len()] != INDEX_MAGIC
+            || header[INDEX_MAGIC.len()] != INDEX_VERSION
+            || read_u64(&trailer[12..20]) != log_len
+            || !(INDEX_HEADER_LEN..=file_len - TRAILER_LEN).contains(&table_offset)
+        {
+            return Ok(None);
+        }
+
+        let mut table = vec![0u8; (file_len - TRAILER_LEN - table_offset) as usize];
+        file.seek(SeekFrom::Start(table_offset))?;
+        file.read_exact(&mut table)?;
+        if crc32fast::hash(&table) != read_u32(&trailer[8..12]) {
+            return Ok(None);
+        }
+        let mut blocks = Vec::new();
+        let mut rest = table.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < BLOCK_REF_LEN {
+                return Ok(None);
+            }
+            let (head, tail) = rest.split_at(BLOCK_REF_LEN);
+            let key_len = read_u32(&head[..4]) as usize;
+            let (offset, len) = (read_u64(&head[4..12]), read_u32(&head[12..16]));
+            if tail.len() < key_len || offset.saturating_add(u64::from(len)) > table_offset {
+                return Ok(None);
+            }
+            let (first_key, tail) = tail.split_at(key_len);
+            blocks.push(BlockRef { first_key: first_key.to_vec(), offset, len, crc: read_u32(&head[16..20]) });
+            rest = tail;
+        }
+
+        Ok(Some(SortedIndex {
+            gen,
+            path,
+            file: Mutex::new(file),
+            blocks,
+            live_bytes: read_u64(&trailer[20..28]),
+            cache: Mutex::new(Lru::new(cache_entries)),
+        }))
+    }
+
+    /// Returns where the record of `key` is, `None` if it is not indexed.
+    pub fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        if let Some(cached) = self.cache().get(key) {
+            return Ok(cached);
+        }
+        let found = match self.block_of(key) {
+            Some(block) => self
+                .read_block(block)?
+                .into_iter()
+                .find(|(entry_key, _)| entry_key.as_slice() == key)
+                .map(|(_, cmd_pos)| cmd_pos),
+            None => None,
+        };
+        self.cache().put(key, found);
+        Ok(found)
+    }
+
+    /// Returns the block `key` would be in, `None` if it comes before them all.
+    fn block_of(&self, key: &[u8]) -> Option<usize> {
+        self.blocks
+            .partition_point(|block| block.first_key.as_slice() <= key)
+            .checked_sub(1)
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<(Vec<u8>, CommandPos)>> {
+        let block = &self.blocks[block];
+        let mut buf = vec![0u8; block.len as usize];
+        {
+            let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+            file.seek(SeekFrom::Start(block.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        let damaged = || {
+            KvsError::StringError(format!("damaged index block at offset {} of {}", block.offset, self.path.display()))
+        };
+        if crc32fast::hash(&buf) != block.crc {
+            return Err(damaged());
+        }
+
+        let mut entries = Vec::new();
+        let mut rest = buf.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < ENTRY_HEADER_LEN {
+                return Err(damaged());
+            }
+            let (entry, tail) = rest.split_at(ENTRY_HEADER_LEN);
+            let key_len = read_u32(&entry[..4]) as usize;
+            if tail.len() < key_len {
+                return Err(damaged());
+            }
+            let (pos, len) = (read_u64(&entry[4..12]), read_u64(&entry[12..20]));
+            let deadline = read_u64(&entry[36..44]);
+            let cmd_pos = CommandPos {
+                version: Version { gen: read_u64(&entry[20..28]), pos: read_u64(&entry[28..36]) },
+                expires_at: (deadline != 0).then_some(deadline),
+                stamp: Stamp { seq: read_u64(&entry[44..52]), timestamp: read_u64(&entry[52..60]) },
+                ..(self.gen, pos..pos + len).into()
+            };
+            let (key, tail) = tail.split_at(key_len);
+            entries.push((key.to_vec(), cmd_pos));
+            rest = tail;
+        }
+        Ok(entries)
+    }
+
+    // the cache is consistent between every two calls, so a panic elsewhere does
+    // not make it unusable.
+    fn cache(&self) -> MutexGuard<'_, Lru> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Writes the sorted index of a compacted log, see `SortedIndex`.
+pub struct SortedIndexWriter {
+    writer: BufWriter<File>,
+    pos: u64,
+    // the entries of the block being filled.
+    block: Vec<u8>,
+    blocks: Vec<BlockRef>,
+    live_bytes: u64,
+}
+
+impl SortedIndexWriter {
+    /// Creates the sorted index of the log `gen`.
+    pub fn create(dir: &Path, gen: u64) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(index_path(dir, gen))?);
+        writer.write_all(&INDEX_MAGIC)?;
+        writer.write_all(&[INDEX_VERSION])?;
+        Ok(SortedIndexWriter {
+            writer,
+            pos: INDEX_HEADER_LEN,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            blocks: Vec::new(),
+            live_bytes: 0,
+        })
+    }
+
+    /// Adds the entry of `key`, which comes after the keys added so far.
+    pub fn push(&mut self, key: &[u8], cmd_pos: &CommandPos) -> Result<()> {
+        if self.block.is_empty() {
+            self.blocks.push(BlockRef { first_key: key.to_vec(), offset: self.pos, len: 0, crc: 0 });
+        }
+        let key_len = u32::try_from(key.len()).expect("keys are checked to fit when logged");
+        self.block.extend_from_slice(&key_len.to_le_bytes());
+        self.block.extend_from_slice(&cmd_pos.pos.to_le_bytes());
+        self.block.extend_from_slice(&cmd_pos.len.to_le_bytes());
+        self.block.extend_from_slice(&cmd_pos.version.gen.to_le_bytes());
+        self.block.extend_from_slice(&cmd_pos.version.pos.to_le_bytes());
+        self.block.extend_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+        self.block.extend_from_slice(&cmd_pos.stamp.seq.to_le_bytes());
+        self.block.extend_from_slice(&cmd_pos.stamp.timestamp.to_le_bytes());
+        self.block.extend_from_slice(key);
+        self.live_bytes += cmd_pos.len;
+        if self.block.len() >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the block table and the trailer, and syncs the file, `log_len` being
+    /// the length of the log the entries point into.
+    pub fn finish(mut self, log_len: u64) -> Result<()> {
+        self.write_block()?;
+        let mut table = Vec::new();
+        for block in &self.blocks {
+            let key_len = u32::try_from(block.first_key.len()).expect("keys are checked to fit when logged");
+            table.extend_from_slice(&key_len.to_le_bytes());
+            table.extend_from_slice(&block.offset.to_le_bytes());
+            table.extend_from_slice(&block.len.to_le_bytes());
+            table.extend_from_slice(&block.crc.to_le_bytes());
+            table.extend_from_slice(&block.first_key);
+        }
+        self.writer.write_all(&table)?;
+        self.writer.write_all(&self.pos.to_le_bytes())?;
+        self.writer.write_all(&crc32fast::hash(&table).to_le_bytes())?;
+        self.writer.write_all(&log_len.to_le_bytes())?;
+        self.writer.write_all(&self.live_bytes.to_le_bytes())?;
+        self.writer.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = self.blocks.last_mut().expect("a block is started with its first entry");
+        block.len = u32::try_from(self.block.len()).expect("blocks are a few KiB");
+        block.crc = crc32fast::hash(&self.block);
+        self.writer.write_all(&self.block)?;
+        self.pos += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// Removes the sorted index of the log `gen`, if any.
+pub fn remove_index(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(index_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn index_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.index", gen))
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().expect("field is 8 bytes"))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().expect("field is 4 bytes"))
+}
+
+/// The entries of a sorted index looked up most recently, `None` for the keys it
+/// does not hold.
+struct Lru {
+    capacity: usize,
+    entries: HashMap<Vec<u8>, (Option<CommandPos>, u64)>,
+    // the keys by when they were last used.
+    order: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Lru { capacity, entries: HashMap::new(), order: BTreeMap::new(), clock: 0 }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Option<CommandPos>> {
+        let (cmd_pos, used) = self.entries.get_mut(key)?;
+        let key = self.order.remove(used).expect("every entry is in the order");
+        self.clock += 1;
+        *used = self.clock;
+        self.order.insert(self.clock, key);
+        Some(*cmd_pos)
+    }
+
+    fn put(&mut self, key: &[u8], cmd_pos: Option<CommandPos>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((cached, used)) = self.entries.get_mut(key) {
+            *cached = cmd_pos;
+            let key = self.order.remove(used).expect("every entry is in the order");
+            *used = self.clock;
+            self.order.insert(self.clock, key);
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key.to_vec(), (cmd_pos, self.clock));
+        self.order.insert(self.clock, key.to_vec());
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use memmap2::Mmap;
+
+use crate::Result;
+
+/// The log files of a `KvStore`, shared by all its `KvReader`s, so that the
+/// number of open files does not grow with the number of clones.
+///
+/// A sealed log, one writes have moved on from, is never written again, so it
+/// is memory-mapped on its first read and its records are read straight from the
+/// mapping. The active log is still being appended to; it is opened once and read
+/// with positional reads, which need no seek and so no reader of its own.
+///
+/// The logs compacted away are unmapped once the compaction is done. A snapshot
+/// may still read them, as they stay on disk while it pins them; they are then
+/// mapped by its reader only (see `KvReader`).
+///
+/// A read-only store maps no log, as the writer of the directory may truncate
+/// them: it reads them all like the active log (see `open_all`).
+///
+/// The value logs of a store are kept the same way, by a `LogFiles` of their own.
+pub struct LogFiles {
+    path: Arc<PathBuf>,
+    // the path of the file of a given generation in the directory.
+    file_path: fn(&Path, u64) -> PathBuf,
+    // the sealed logs mapped so far.
+    sealed: RwLock<BTreeMap<u64, Arc<Mmap>>>,
+    // the generation of the active log: the logs before it are sealed.
+    active_gen: AtomicU64,
+    active: Mutex<Option<(u64, Arc<File>)>>,
+    // whether the logs are read-only, and read from `opened` rather than mapped.
+    read_only: bool,
+    opened: RwLock<BTreeMap<u64, Arc<File>>>,
+    // oldest generation still referenced by the index.
+    safe_point: Arc<AtomicU64>,
+}
+
+impl LogFiles {
+    pub fn new(
+        path: Arc<PathBuf>,
+        file_path: fn(&Path, u64) -> PathBuf,
+        active_gen: u64,
+        safe_point: Arc<AtomicU64>,
+        read_only: bool,
+    ) -> LogFiles {
+        LogFiles {
+            path,
+            file_path,
+            sealed: RwLock::new(BTreeMap::new()),
+            active_gen: AtomicU64::new(active_gen),
+            active: Mutex::new(None),
+            read_only,
+            opened: RwLock::new(BTreeMap::new()),
+            safe_point,
+        }
+    }
+
+    /// Whether the log `gen` is sealed.
+    pub fn is_sealed(&self, gen: u64) -> bool {
+        gen < self.active_gen.load(Ordering::SeqCst)
+    }
+
+    /// Whether the log `gen` is read from its mapping: it is sealed, and the
+    /// store is not read-only.
+    pub fn is_mapped(&self, gen: u64) -> bool {
+        !self.read_only && self.is_sealed(gen)
+    }
+
+    /// Records that writes moved on to the log `gen`, sealing the ones before.
+    pub fn set_active(&self, gen: u64) {
+        self.active_gen.store(gen, Ordering::SeqCst);
+    }
+
+    /// Reads `buf.len()` bytes at `pos` of the log `gen`, which is not mapped.
+    pub fn read_at(&self, gen: u64, pos: u64, buf: &mut [u8]) -> Result<()> {
+        let opened = self.opened.read().unwrap_or_else(PoisonError::into_inner).get(&gen).cloned();
+        let file = if let Some(file) = opened {
+            file
+        } else {
+            let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+            match &*active {
+                Some((active_gen, file)) if *active_gen == gen => file.clone(),
+                _ => {
+                    let file = Arc::new(File::open((self.file_path)(&self.path, gen))?);
+                    *active = Some((gen, file.clone()));
+                    file
+                }
+            }
+        };
+        read_exact_at(&file, buf, pos)?;
+        Ok(())
+    }
+
+    /// Whether the log `gen` is before the safe point, left on disk for the
+    /// snapshots that may still read it.
+    pub fn is_retired(&self, gen: u64) -> bool {
+        gen < self.safe_point.load(Ordering::SeqCst)
+    }
+
+    /// Returns the mapping of the sealed log `gen`, holding at least `len` bytes
+    /// unless the log is shorter.
+    ///
+    /// Returns `None` if the log is before the safe point, in which case it is
+    /// not kept here, and the caller maps it itself with `map`.
+    pub fn mapped(&self, gen: u64, len: u64) -> Result<Option<Arc<Mmap>>> {
+        if self.is_retired(gen) {
+            return Ok(None);
+        }
+        if let Some(map) = self.sealed.read().unwrap_or_else(PoisonError::into_inner).get(&gen) {
+            if map.len() as u64 >= len {
+                return Ok(Some(map.clone()));
+            }
+        }
+        // mapped for the first time, or, with a mapping too short, after a
+        // reader got ahead of the log being sealed.
+        let map = Arc::new(self.map(gen)?);
+        let mut sealed = self.sealed.write().unwrap_or_else(PoisonError::into_inner);
+        // a compaction that moved the safe point past `gen` in the meantime would
+        // not unmap it.
+        if !self.is_retired(gen) {
+            sealed.insert(gen, map.clone());
+        }
+        Ok(Some(map))
+    }
+
+    /// Maps the sealed log `gen`.
+    pub fn map(&self, gen: u64) -> Result<Mmap> {
+        let file = File::open((self.file_path)(&self.path, gen))?;
+        // SAFETY: only the writer of the directory, which holds its lock, maps
+        // logs, and only sealed ones. It never writes to them again, and only
+        // truncates logs while it is being opened, before any is mapped; it
+        // deletes them at most, which leaves the mapping as it is. A read-only
+        // store, which a writer could truncate the logs of, maps none (see
+        // `is_mapped`).
+        Ok(unsafe { Mmap::map(&file)? })
+    }
+
+    /// Opens the logs of `gens` of a read-only store right away, so that they
+    /// are still read once deleted: it reads the logs as they were when it was
+    /// opened, whatever the writer of the directory does with them since. A log
+    /// the writer truncates reads as truncated rather than faulting a mapping.
+    pub fn open_all(&self, gens: &[u64]) -> Result<()> {
+        let mut opened = self.opened.write().unwrap_or_else(PoisonError::into_inner);
+        for &gen in gens {
+            opened.insert(gen, Arc::new(File::open((self.file_path)(&self.path, gen))?));
+        }
+        Ok(())
+    }
+
+    /// Unmaps the logs of `gens`, which a compaction left behind.
+    pub fn forget(&self, gens: &[u64]) {
+        let mut sealed = self.sealed.write().unwrap_or_else(PoisonError::into_inner);
+        for gen in gens {
+            sealed.remove(gen);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, pos) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                pos += read as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,367 @@
+use std::sync::{Arc, Barrier};
+use std::time::Duration;
+
+use super::EncryptionKey;
+
+/// Shortest interval of `SyncPolicy::Interval`, so that the sync thread does not
+/// spin.
+const MIN_SYNC_INTERVAL: Duration = Duration::from_millis(1);
+
+/// When `KvStore` forces written records down to the disk.
+///
+/// Records are always flushed to the operating system before a write returns;
+/// the policy only decides when they are `fdatasync`ed, i.e. which acknowledged
+/// writes can be lost on a power failure or kernel crash. Concurrent writes are
+/// committed as a group, which shares one flush and at most one sync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Never sync explicitly, leave it to the operating system.
+    #[default]
+    Never,
+    /// Sync before every write returns.
+    EveryWrite,
+    /// Sync from a background thread at the given interval.
+    ///
+    /// Intervals below a millisecond act as a millisecond.
+    Interval(Duration),
+    /// Sync once every given number of writes.
+    Batch(usize),
+}
+
+/// When `KvStore` compacts its logs on its own.
+///
+/// Whatever the policy, `KvStore::compact` compacts on demand.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionPolicy {
+    /// Compact once more than the given number of bytes in the logs are stale.
+    StaleBytes(u64),
+    /// Compact once the stale bytes exceed the given multiple of the live bytes.
+    StaleRatio(f64),
+    /// Compact once there are more than the given number of log files.
+    ///
+    /// A compaction leaves two logs behind, so values below 2 act as 2.
+    MaxGenerations(usize),
+    /// Only compact through `KvStore::compact`.
+    Disabled,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::StaleBytes(1024 * 1024)
+    }
+}
+
+/// Which past versions of its keys `KvStore` keeps, for `KvsEngine::get_at` and
+/// `KvsEngine::history`.
+///
+/// A version is one write of a key: a set, or a removal. The current version of a
+/// key is always kept; the policy decides when the records of the older ones are
+/// left for a compaction to reclaim.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RetentionPolicy {
+    /// Only keep the current version.
+    #[default]
+    Latest,
+    /// Keep the given number of most recent versions, the current one included.
+    ///
+    /// Values below 2 act as `RetentionPolicy::Latest`.
+    LastVersions(usize),
+    /// Keep the versions written less than the given duration ago.
+    ///
+    /// They are checked when the key is written and before every compaction.
+    NewerThan(Duration),
+}
+
+/// How `KvStore` keeps track of where the records of its keys are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IndexMode {
+    /// Keep every key in memory.
+    #[default]
+    Full,
+    /// Keep the keys in a sorted index file that every compaction writes, with
+    /// only the first key of every few KiB of it in memory, and the
+    /// `cache_entries` entries read most recently.
+    ///
+    /// The keys written since the last compaction are kept in memory; once there
+    /// are more than `max_memory_keys` of them, a compaction starts whatever the
+    /// `CompactionPolicy`.
+    Bounded {
+        /// Number of entries of the sorted index to cache.
+        cache_entries: usize,
+        /// Number of keys in memory past which a compaction starts.
+        max_memory_keys: usize,
+    },
+}
+
+/// Whether `KvStore` caches the values it reads, and which ones it evicts first
+/// once the cache is full.
+///
+/// The capacity is in bytes of keys and values. Values larger than the whole
+/// cache are not cached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ReadCachePolicy {
+    /// Read every value from the logs.
+    #[default]
+    Disabled,
+    /// Cache up to the given number of bytes, evicting the least recently read
+    /// values first.
+    Lru(u64),
+    /// Cache up to the given number of bytes, evicting the values not read since
+    /// the last sweep of a CLOCK hand. Hits are cheaper than with
+    /// `ReadCachePolicy::Lru` under concurrent reads, evictions less precise.
+    Clock(u64),
+}
+
+/// Whether `KvStore` compresses the values it writes, and with which codec.
+///
+/// The codec is recorded in every record, so a store written with one can be
+/// opened with another: the records written before stay as they are until a
+/// compaction, which only rewrites them with `KvStoreOptions::recompress`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Store values as they are.
+    #[default]
+    None,
+    /// Compress values with LZ4, which is fast but compresses less.
+    Lz4,
+    /// Compress values with zstd at the given level, from 1 to 22, 3 being its
+    /// usual default.
+    Zstd(i32),
+}
+
+/// When `KvStore` collects the garbage of its value logs on its own, see
+/// `KvStoreOptions::value_separation`.
+///
+/// Whatever the policy, `KvStore::collect_value_logs` collects them on demand.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueLogGcPolicy {
+    /// Collect the oldest value log once at least the given share of its bytes
+    /// is garbage, checked whenever a value log fills up and at the given interval.
+    GarbageRatio {
+        /// Share of garbage, from 0 to 1, past which the value log is collected.
+        ratio: f64,
+        /// Time between two checks.
+        interval: Duration,
+    },
+    /// Only collect through `KvStore::collect_value_logs`.
+    Disabled,
+}
+
+impl Default for ValueLogGcPolicy {
+    fn default() -> Self {
+        ValueLogGcPolicy::GarbageRatio { ratio: 0.5, interval: Duration::from_secs(10) }
+    }
+}
+
+/// What `KvStore::open` does with a damaged record it finds in a log it
+/// replays, see `KvStore::recovery_report`.
+///
+/// Whatever the mode, a record cut short at the end of a log, as a crash in the
+/// middle of a write leaves it, is truncated away, and a value that fails to
+/// authenticate fails the open, as it rather points at a wrong key than at damage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    /// Fail the open with `KvsError::Corruption`.
+    #[default]
+    Strict,
+    /// Truncate the log right before the damaged record, dropping the records
+    /// after it.
+    TruncateTail,
+    /// Skip the damaged record, and resume at the next record whose checksum
+    /// matches. The bytes skipped are left in the log until a compaction drops
+    /// them, so the store is to be opened in this mode until then.
+    SkipCorrupted,
+}
+
+/// Options to configure how a `KvStore` is opened.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, SyncPolicy, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite);
+/// let store = KvStore::open_with_options(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) compaction_policy: CompactionPolicy,
+    pub(super) expiry_sweep_interval: Duration,
+    pub(super) retention: RetentionPolicy,
+    pub(super) index_mode: IndexMode,
+    pub(super) read_cache: ReadCachePolicy,
+    pub(super) compression: Compression,
+    pub(super) recompress: bool,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) old_keys: Vec<EncryptionKey>,
+    pub(super) value_threshold: Option<usize>,
+    pub(super) value_log_size: u64,
+    pub(super) value_log_gc: ValueLogGcPolicy,
+    pub(super) recovery_mode: RecoveryMode,
+    pub(super) read_only: bool,
+    pub(super) compaction_barrier: Option<Arc<Barrier>>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::default(),
+            compaction_policy: CompactionPolicy::default(),
+            expiry_sweep_interval: Duration::from_secs(1),
+            retention: RetentionPolicy::default(),
+            index_mode: IndexMode::default(),
+            read_cache: ReadCachePolicy::default(),
+            compression: Compression::default(),
+            recompress: false,
+            encryption_key: None,
+            old_keys: Vec::new(),
+            value_threshold: None,
+            value_log_size: 64 * 1024 * 1024,
+            value_log_gc: ValueLogGcPolicy::default(),
+            recovery_mode: RecoveryMode::default(),
+            read_only: false,
+            compaction_barrier: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates options with the default settings, which are what `KvStore::open` uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets when written records are synced to disk, `SyncPolicy::Never` by default.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = match sync_policy {
+            SyncPolicy::Interval(interval) => SyncPolicy::Interval(interval.max(MIN_SYNC_INTERVAL)),
+            sync_policy => sync_policy,
+        };
+        self
+    }
+
+    /// Sets when the store compacts on its own, after 1 MiB of stale data by default.
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> Self {
+        self.compaction_policy = compaction_policy;
+        self
+    }
+
+    /// Sets how often expired keys are dropped from memory, every second by default.
+    ///
+    /// Expired keys read as absent right away whatever the interval.
+    pub fn expiry_sweep_interval(mut self, interval: Duration) -> Self {
+        self.expiry_sweep_interval = interval;
+        self
+    }
+
+    /// Sets which past versions of the keys are kept, only the current one by default.
+    pub fn retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Sets how the keys are indexed, all of them in memory by default.
+    ///
+    /// A store can be opened with either mode whatever the one it was written with.
+    pub fn index_mode(mut self, index_mode: IndexMode) -> Self {
+        self.index_mode = index_mode;
+        self
+    }
+
+    /// Sets whether and how the values read are cached, not at all by default.
+    pub fn read_cache(mut self, read_cache: ReadCachePolicy) -> Self {
+        self.read_cache = read_cache;
+        self
+    }
+
+    /// Sets how the values written are compressed, not at all by default.
+    ///
+    /// Values shorter than 64 bytes, or that do not shrink, are stored as they are.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets whether compactions rewrite the records copied with the codec of the
+    /// `Compression` set, decompressing them if it is `Compression::None`; they
+    /// are copied as they are by default.
+    pub fn recompress(mut self, recompress: bool) -> Self {
+        self.recompress = recompress;
+        self
+    }
+
+    /// Sets the key the values written are encrypted with, none by default.
+    ///
+    /// The values written with another key, or in plaintext, are read with the
+    /// ones given to `old_key`, and encrypted with this one by the compactions that
+    /// copy them: a key is rotated by opening the store with a new key and the old
+    /// one as an old key, then compacting it. Without a key, compactions decrypt
+    /// the values instead.
+    ///
+    /// A missing or wrong key is found out when a value encrypted with it is
+    /// decoded: on open for the logs replayed, on read for the compacted ones.
+    /// Keys stay in plaintext, in the logs as in the hint and index files.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Adds a key to read the values written before a rotation with.
+    pub fn old_key(mut self, key: EncryptionKey) -> Self {
+        self.old_keys.push(key);
+        self
+    }
+
+    /// Stores the values of at least `min_len` bytes in value logs apart from
+    /// their keys, which compactions do not copy; all values stay in the logs by
+    /// default.
+    ///
+    /// The values already in value logs are read whatever the setting.
+    pub fn value_separation(mut self, min_len: usize) -> Self {
+        self.value_threshold = Some(min_len);
+        self
+    }
+
+    /// Sets the size from which writes move on to a new value log, 64 MiB by default.
+    pub fn value_log_size(mut self, max_bytes: u64) -> Self {
+        self.value_log_size = max_bytes;
+        self
+    }
+
+    /// Sets when the garbage of the value logs is collected on its own, once half
+    /// of the oldest one is by default.
+    pub fn value_log_gc(mut self, policy: ValueLogGcPolicy) -> Self {
+        self.value_log_gc = policy;
+        self
+    }
+
+    /// Sets what the open does with damaged records, fail by default.
+    pub fn recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.recovery_mode = recovery_mode;
+        self
+    }
+
+    /// Sets whether the store is opened read-only, which it is not by default.
+    ///
+    /// Only one store at a time writes to a directory, which it locks until its
+    /// last clone is dropped. A read-only store does not lock the directory, so it can be opened while
+    /// another store, in this process or another one, writes to it. It reads the
+    /// store as it was when opened, and fails writes with `KvsError::ReadOnly`.
+    /// It leaves the files as they are: damaged records are not truncated away,
+    /// and legacy JSON logs fail the open rather than being upgraded.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Makes every compaction wait twice at `barrier` once it has copied a first
+    /// entry, so that a test can check what goes on while it is held there
+    /// before letting it go on. Only there with the `test-hooks` feature.
+    #[cfg(feature = "test-hooks")]
+    pub fn compaction_barrier(mut self, barrier: Arc<Barrier>) -> Self {
+        self.compaction_barrier = Some(barrier);
+        self
+    }
+}
@@ -0,0 +1,425 @@
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+use std::mem;
+
+use super::compression::{self, Compressor, CODEC_NONE};
+use super::crypto::{self, Keyring};
+use super::value_log::ValuePointer;
+use crate::{KvsError, Result};
+
+/// Magic bytes at the start of every binary log file.
+pub const LOG_MAGIC: [u8; 4] = *b"KVSL";
+/// Version of the binary record format written by this build.
+pub const LOG_VERSION: u8 = 3;
+// magic followed by the version byte
+const LOG_VERSION_END: usize = LOG_MAGIC.len() + 1;
+/// Length of the file header: magic, the version byte, then the sequence number
+/// of the last write made before the log was created as a `u64` LE.
+///
+/// The sequence number lets a store whose newest records were all dropped by a
+/// compaction go on numbering writes from where it was.
+pub const LOG_HEADER_LEN: u64 = LOG_VERSION_END as u64 + 8;
+
+// record type (1) + key length (4) + value length (4) + sequence number (8) + timestamp (8)
+const RECORD_HEADER_LEN: usize = 25;
+const CRC_LEN: usize = 4;
+
+const RECORD_SET: u8 = 1;
+const RECORD_REMOVE: u8 = 2;
+const RECORD_BATCH: u8 = 3;
+const RECORD_SET_EXPIRING: u8 = 4;
+const RECORD_SET_POINTER: u8 = 5;
+const RECORD_SET_POINTER_EXPIRING: u8 = 6;
+// bits 4 to 6 of the type of a set record are the codec its value is compressed
+// with, zero for none, and bit 7 is set if the value is then encrypted, so that
+// records stored differently sit side by side in a log.
+const CODEC_SHIFT: u8 = 4;
+const KIND_MASK: u8 = (1 << CODEC_SHIFT) - 1;
+const CODEC_MASK: u8 = 0x7;
+const ENCRYPTED: u8 = 0x80;
+
+// deadline (8) at the start of the value of an expiring set record
+const DEADLINE_LEN: usize = 8;
+
+/// Length of the header of a batch record, right after which its first inner
+/// record starts.
+pub const BATCH_HEADER_LEN: u64 = (CRC_LEN + RECORD_HEADER_LEN) as u64;
+
+/// A single entry of a log file.
+///
+/// On disk every record is laid out as
+///
+/// ```text
+/// +-------+------+---------+-----------+--------+-----------+-----+-------+
+/// |  crc  | type | key_len | value_len |  seq   | timestamp | key | value |
+/// | u32LE |  u8  | u32 LE  |  u32 LE   | u64 LE |  u64 LE   |     |       |
+/// +-------+------+---------+-----------+--------+-----------+-----+-------+
+/// ```
+///
+/// `crc` is the CRC-32 of everything after it, and `value_len` is always zero
+/// for a remove record. `seq` and `timestamp` are the `Stamp` of the write.
+/// The value of a set record is stored compressed and encrypted as `type` says.
+#[derive(Debug, PartialEq)]
+pub enum Record {
+    /// Sets `key` to `value`, until `expires_at` in milliseconds since the UNIX
+    /// epoch if given.
+    ///
+    /// An expiring set has a type of its own, and its value starts with the
+    /// deadline as a `u64` LE, which is neither compressed nor encrypted. An
+    /// encrypted value is authenticated along with the key, the sequence number
+    /// and the deadline.
+    Set { key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>, stamp: Stamp },
+    /// Sets `key` to the value `pointer` points at in a value log, until
+    /// `expires_at` if given.
+    ///
+    /// Its value is the pointer, after the deadline if any, and is neither
+    /// compressed nor encrypted: the value pointed at, a set record of its own, is.
+    Pointer { key: Vec<u8>, pointer: ValuePointer, expires_at: Option<u64>, stamp: Stamp },
+    /// Removes `key`.
+    Remove { key: Vec<u8>, stamp: Stamp },
+}
+
+/// When a write happened: its sequence number, which increases with every write
+/// to the store, and the time in milliseconds since the UNIX epoch.
+///
+/// Records upgraded from a JSON log have a zero timestamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stamp {
+    pub seq: u64,
+    pub timestamp: u64,
+}
+
+/// Outcome of reading one record from a log.
+pub enum Decoded {
+    /// A valid set or remove record and its length in bytes.
+    Record(Record, u64),
+    /// A valid batch record: its set and remove records with the length of each,
+    /// and its own length in bytes.
+    Batch(Vec<(Record, u64)>, u64),
+    /// The log ended cleanly before the next record.
+    Eof,
+    /// The log ended part of the way through a record: either it was cut short,
+    /// or its length is damaged, which only the records after it, if any, tell.
+    Torn,
+    /// A complete record of the given length whose checksum does not match, or
+    /// that does not decode although it does.
+    Corrupted(u64),
+    /// A complete record whose value fails to authenticate against its key,
+    /// which is the wrong one, or the record was tampered with.
+    Unauthenticated,
+}
+
+/// What the values of set records go through on their way to a log and back:
+/// compression, then encryption.
+#[derive(Default)]
+pub struct ValueCodec {
+    pub compressor: Compressor,
+    pub keyring: Keyring,
+}
+
+impl ValueCodec {
+    /// Whether the encoded record `record` is compressed and encrypted the way
+    /// values are written now, or need not be: compactions rewrite the others,
+    /// the codec only being looked at if the compressor recompresses.
+    pub fn is_current(&self, record: &[u8]) -> bool {
+        let kind = record[CRC_LEN];
+        if !matches!(kind & KIND_MASK, RECORD_SET | RECORD_SET_EXPIRING) {
+            return true;
+        }
+        let codec = (kind >> CODEC_SHIFT) & CODEC_MASK;
+        if self.compressor.recompresses() && codec != self.compressor.codec() {
+            return false;
+        }
+        let key_id = match kind & ENCRYPTED {
+            0 => None,
+            _ => {
+                let key_len = decode_len(&record[CRC_LEN + 1..CRC_LEN + 5]);
+                let mut value = &record[CRC_LEN + RECORD_HEADER_LEN + key_len..];
+                if kind & KIND_MASK == RECORD_SET_EXPIRING {
+                    value = value.get(DEADLINE_LEN..).unwrap_or_default();
+                }
+                crypto::key_id(value)
+            }
+        };
+        key_id == self.keyring.current()
+    }
+}
+
+impl Record {
+    /// Writes the record to `writer`, the values of set records going through
+    /// `values`.
+    ///
+    /// Returns the number of bytes written.
+    pub fn encode_to<W: Write>(&self, writer: &mut W, values: &ValueCodec) -> Result<u64> {
+        match self {
+            Record::Set { key, value, expires_at, stamp } => {
+                let (codec, value) = values.compressor.compress(value);
+                let mut kind = codec << CODEC_SHIFT;
+                kind |= if expires_at.is_some() { RECORD_SET_EXPIRING } else { RECORD_SET };
+                let value = match values.keyring.encrypt(&associated_data(key, *expires_at, *stamp), &value)? {
+                    Some(sealed) => {
+                        kind |= ENCRYPTED;
+                        Cow::Owned(sealed)
+                    }
+                    None => value,
+                };
+                match expires_at {
+                    None => write_record(writer, kind, key, &value, *stamp),
+                    Some(deadline) => {
+                        let value = [&deadline.to_le_bytes()[..], &value].concat();
+                        write_record(writer, kind, key, &value, *stamp)
+                    }
+                }
+            }
+            Record::Pointer { key, pointer, expires_at: None, stamp } => {
+                write_record(writer, RECORD_SET_POINTER, key, &pointer.encode(), *stamp)
+            }
+            Record::Pointer { key, pointer, expires_at: Some(deadline), stamp } => {
+                let value = [&deadline.to_le_bytes()[..], &pointer.encode()].concat();
+                write_record(writer, RECORD_SET_POINTER_EXPIRING, key, &value, *stamp)
+            }
+            Record::Remove { key, stamp } => write_record(writer, RECORD_REMOVE, key, &[], *stamp),
+        }
+    }
+
+    /// Writes a batch record of `records` to `writer`, the values of its set
+    /// records going through `values`.
+    ///
+    /// A batch record applies its set and remove records together. It has an
+    /// empty key and a zero stamp, and its value is the records encoded one after
+    /// the other. Its checksum covers them all, so a batch is replayed completely
+    /// or not at all.
+    ///
+    /// Returns the number of bytes written for every inner record, in order.
+    pub fn encode_batch<W: Write>(records: &[Record], writer: &mut W, values: &ValueCodec) -> Result<Vec<u64>> {
+        let mut body = Vec::new();
+        let mut lens = Vec::with_capacity(records.len());
+        for record in records {
+            lens.push(record.encode_to(&mut body, values)?);
+        }
+        write_record(writer, RECORD_BATCH, &[], &body, Stamp::default())?;
+        Ok(lens)
+    }
+
+    /// Reads the next record of the current format from `reader`, the values of
+    /// set records going back through `values`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownEncryptionKey` if a value is encrypted with a
+    /// key `values` does not have.
+    pub fn decode_from<R: Read>(reader: &mut R, values: &ValueCodec) -> Result<Decoded> {
+        let mut header = [0u8; CRC_LEN + RECORD_HEADER_LEN];
+        match read_exact_or_eof(reader, &mut header)? {
+            ReadOutcome::Eof => return Ok(Decoded::Eof),
+            ReadOutcome::Partial => return Ok(Decoded::Torn),
+            ReadOutcome::Full => {}
+        }
+        let (crc, header) = header.split_at(CRC_LEN);
+
+        let key_len = decode_len(&header[1..5]);
+        let value_len = decode_len(&header[5..9]);
+        let len = (CRC_LEN + RECORD_HEADER_LEN + key_len + value_len) as u64;
+        let stamp = Stamp { seq: read_u64(&header[9..17]), timestamp: read_u64(&header[17..25]) };
+
+        // read through `take` so that a garbage length does not allocate more
+        // than what is actually left in the log.
+        let mut body = Vec::new();
+        reader.take((key_len + value_len) as u64).read_to_end(&mut body)?;
+        if body.len() < key_len + value_len {
+            return Ok(Decoded::Torn);
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(header);
+        hasher.update(&body);
+        if hasher.finalize() != u32::from_le_bytes(crc.try_into().expect("crc is 4 bytes")) {
+            return Ok(Decoded::Corrupted(len));
+        }
+
+        let mut value = body.split_off(key_len);
+        let key = body;
+        let kind = header[0] & KIND_MASK;
+        let (codec, encrypted) = ((header[0] >> CODEC_SHIFT) & CODEC_MASK, header[0] & ENCRYPTED != 0);
+        let is_set = matches!(kind, RECORD_SET | RECORD_SET_EXPIRING);
+        // a record whose checksum matches but that makes no sense is as damaged
+        // as one whose checksum does not.
+        if (codec != CODEC_NONE || encrypted) && !(is_set && compression::is_known(codec)) {
+            return Ok(Decoded::Corrupted(len));
+        }
+        let expires_at = match kind {
+            RECORD_SET_EXPIRING | RECORD_SET_POINTER_EXPIRING if value.len() >= DEADLINE_LEN => {
+                let rest = value.split_off(DEADLINE_LEN);
+                Some(read_u64(&mem::replace(&mut value, rest)))
+            }
+            RECORD_SET_EXPIRING | RECORD_SET_POINTER_EXPIRING => return Ok(Decoded::Corrupted(len)),
+            _ => None,
+        };
+        if encrypted {
+            value = match values.keyring.decrypt(&associated_data(&key, expires_at, stamp), &value)? {
+                Some(value) => value,
+                None => return Ok(Decoded::Unauthenticated),
+            };
+        }
+        let record = match kind {
+            RECORD_SET | RECORD_SET_EXPIRING => match compression::decompress(codec, value) {
+                Some(value) => Record::Set { key, value, expires_at, stamp },
+                None => return Ok(Decoded::Corrupted(len)),
+            },
+            RECORD_SET_POINTER | RECORD_SET_POINTER_EXPIRING => match ValuePointer::decode(&value) {
+                Some(pointer) => Record::Pointer { key, pointer, expires_at, stamp },
+                None => return Ok(Decoded::Corrupted(len)),
+            },
+            RECORD_REMOVE => Record::Remove { key, stamp },
+            RECORD_BATCH => {
+                let mut records = Vec::new();
+                let mut inner = &value[..];
+                loop {
+                    match Record::decode_from(&mut inner, values)? {
+                        Decoded::Record(record, inner_len) => records.push((record, inner_len)),
+                        Decoded::Eof => break,
+                        Decoded::Batch(..) | Decoded::Torn | Decoded::Corrupted(_) => return Ok(Decoded::Corrupted(len)),
+                        Decoded::Unauthenticated => return Ok(Decoded::Unauthenticated),
+                    }
+                }
+                return Ok(Decoded::Batch(records, len));
+            }
+            _ => return Ok(Decoded::Corrupted(len)),
+        };
+        Ok(Decoded::Record(record, len))
+    }
+}
+
+/// Reads the key, the stamp and the length of the record at the start of
+/// `bytes`, without checking nor decoding it.
+///
+/// Returns `None` if `bytes` end before the record does.
+pub fn peek_record(bytes: &[u8]) -> Option<(&[u8], Stamp, u64)> {
+    let header = bytes.get(CRC_LEN..CRC_LEN + RECORD_HEADER_LEN)?;
+    let key_len = decode_len(&header[1..5]);
+    let len = CRC_LEN + RECORD_HEADER_LEN + key_len + decode_len(&header[5..9]);
+    if bytes.len() < len {
+        return None;
+    }
+    let stamp = Stamp { seq: read_u64(&header[9..17]), timestamp: read_u64(&header[17..25]) };
+    Some((&bytes[CRC_LEN + RECORD_HEADER_LEN..][..key_len], stamp, len as u64))
+}
+
+/// Returns the offset in `bytes`, which start with a damaged record, of the
+/// next record whose checksum matches, `None` if there is none.
+///
+/// The record found may still fail to decode, for instance to authenticate,
+/// but is whole, so that a log can be read on from there. The records of a
+/// batch are whole records of their own, but are not to be read apart from it:
+/// a damaged batch is skipped as a whole, and has none after it if cut short.
+pub fn resync(bytes: &[u8], values: &ValueCodec) -> Option<usize> {
+    let start = match bytes.get(CRC_LEN..CRC_LEN + RECORD_HEADER_LEN) {
+        Some(header) if header[0] & KIND_MASK == RECORD_BATCH => {
+            CRC_LEN + RECORD_HEADER_LEN + decode_len(&header[1..5]) + decode_len(&header[5..9])
+        }
+        _ => 1,
+    };
+    (start..bytes.len()).find(|&offset| is_record_at(&bytes[offset..], values))
+}
+
+/// Whether `bytes` start with a whole record whose checksum matches.
+pub fn is_record_at(bytes: &[u8], values: &ValueCodec) -> bool {
+    // most offsets are ruled out by their type without a checksum.
+    match bytes.get(CRC_LEN) {
+        Some(kind) if (RECORD_SET..=RECORD_SET_POINTER_EXPIRING).contains(&(kind & KIND_MASK)) => {}
+        _ => return false,
+    }
+    match peek_record(bytes) {
+        Some((_, _, len)) => !matches!(
+            Record::decode_from(&mut &bytes[..len as usize], values),
+            Ok(Decoded::Eof | Decoded::Torn | Decoded::Corrupted(_))
+        ),
+        None => false,
+    }
+}
+
+/// Returns the data an encrypted value is authenticated along with: the
+/// sequence number, the deadline if any, and the key of its record.
+fn associated_data(key: &[u8], expires_at: Option<u64>, stamp: Stamp) -> Vec<u8> {
+    let mut aad = stamp.seq.to_le_bytes().to_vec();
+    if let Some(deadline) = expires_at {
+        aad.extend_from_slice(&deadline.to_le_bytes());
+    }
+    aad.extend_from_slice(key);
+    aad
+}
+
+/// Writes a record of type `kind` to `writer`, `value` being its value as stored.
+///
+/// Returns the number of bytes written.
+fn write_record<W: Write>(writer: &mut W, kind: u8, key: &[u8], value: &[u8], stamp: Stamp) -> Result<u64> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    header[0] = kind;
+    header[1..5].copy_from_slice(&encode_len(key.len())?);
+    header[5..9].copy_from_slice(&encode_len(value.len())?);
+    header[9..17].copy_from_slice(&stamp.seq.to_le_bytes());
+    header[17..25].copy_from_slice(&stamp.timestamp.to_le_bytes());
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hasher.update(key);
+    hasher.update(value);
+
+    writer.write_all(&hasher.finalize().to_le_bytes())?;
+    writer.write_all(&header)?;
+    writer.write_all(key)?;
+    writer.write_all(value)?;
+    Ok((CRC_LEN + RECORD_HEADER_LEN + key.len() + value.len()) as u64)
+}
+
+/// Writes the header that every binary log file starts with, `last_seq` being the
+/// sequence number of the last write made so far.
+pub fn write_log_header<W: Write>(writer: &mut W, last_seq: u64) -> io::Result<()> {
+    writer.write_all(&LOG_MAGIC)?;
+    writer.write_all(&[LOG_VERSION])?;
+    writer.write_all(&last_seq.to_le_bytes())
+}
+
+/// Reads the header of a log of the current version, returning the sequence
+/// number it was written with.
+pub fn read_log_header<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut header = [0u8; LOG_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    Ok(read_u64(&header[LOG_VERSION_END..]))
+}
+
+fn encode_len(len: usize) -> Result<[u8; 4]> {
+    u32::try_from(len)
+        .map(u32::to_le_bytes)
+        .map_err(|_| KvsError::StringError(format!("record field of {} bytes is too large", len)))
+}
+
+fn decode_len(bytes: &[u8]) -> usize {
+    u32::from_le_bytes(bytes.try_into().expect("length field is 4 bytes")) as usize
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().expect("field is 8 bytes"))
+}
+
+enum ReadOutcome {
+    Full,
+    Partial,
+    Eof,
+}
+
+/// Fills `buf` from `reader`, telling a clean EOF apart from one part of the way
+/// through `buf`.
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<ReadOutcome> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(ReadOutcome::Eof),
+            Ok(0) => return Ok(ReadOutcome::Partial),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(ReadOutcome::Full)
+}
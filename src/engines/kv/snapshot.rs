@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::hint;
+use super::index::{self, FrozenIndex};
+use super::value_log::value_log_path;
+use super::{log_path, CommandPos, KvReader};
+use crate::{Result, ScanBytesIter, ScanIter};
+
+/// A read-only view of a `KvStore` as of the moment it was taken.
+///
+/// Writes made to the store afterwards, compactions and value log garbage
+/// collections do not show through it: the snapshot keeps its own copy of the
+/// index, or of the part of it in memory with `IndexMode::Bounded`, and the log
+/// generations and value logs it points into stay on disk until it is dropped.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// store.set("key".to_owned(), "value1".to_owned())?;
+/// let snapshot = store.snapshot()?;
+/// store.set("key".to_owned(), "value2".to_owned())?;
+/// assert_eq!(snapshot.get("key".to_owned())?, Some("value1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshot {
+    index: FrozenIndex,
+    seq: u64,
+    // when the snapshot was taken, in milliseconds since the UNIX epoch: the keys
+    // that had expired by then read as absent.
+    taken_at: u64,
+    reader: KvReader,
+    pin: Pin,
+}
+
+impl Snapshot {
+    pub(super) fn new(index: FrozenIndex, seq: u64, taken_at: u64, reader: KvReader, pin: Pin) -> Self {
+        Snapshot { index, seq, taken_at, reader, pin }
+    }
+
+    /// Returns the sequence number of the snapshot: the writes with a sequence
+    /// number up to it show through the snapshot, the later ones do not.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the value a key had when the snapshot was taken.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get_bytes`.
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&key)? {
+            Some(cmd_pos) if !self.had_expired(&cmd_pos) => Ok(Some(self.reader.read_value(&cmd_pos)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the key/value pairs whose keys were in `range` when the snapshot was
+    /// taken, in key order, at most `limit` of them if given.
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(self.read_pairs(self.index.range(range), limit))
+    }
+
+    /// Returns the key/value pairs whose keys started with `prefix` when the
+    /// snapshot was taken, in key order, at most `limit` of them if given.
+    pub fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let entries = self
+            .index
+            .range((Bound::Included(prefix.clone()), Bound::Unbounded))
+            .take_while(move |entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix)));
+        Ok(self.read_pairs(entries, limit))
+    }
+
+    /// Gets the string value a string key had when the snapshot was taken.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the string key/value pairs whose keys were in `range` when the
+    /// snapshot was taken, like `Snapshot::scan_bytes`.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R, limit: Option<usize>) -> Result<ScanIter<'_>> {
+        let to_bytes = |bound: Bound<&String>| bound.map(|key| key.as_bytes().to_vec());
+        let range = (to_bytes(range.start_bound()), to_bytes(range.end_bound()));
+        Ok(crate::engines::into_strings(self.scan_bytes(range, limit)?))
+    }
+
+    /// Returns the string key/value pairs whose keys started with `prefix` when the
+    /// snapshot was taken, like `Snapshot::scan_prefix_bytes`.
+    pub fn scan_prefix(&self, prefix: String, limit: Option<usize>) -> Result<ScanIter<'_>> {
+        Ok(crate::engines::into_strings(self.scan_prefix_bytes(prefix.into_bytes(), limit)?))
+    }
+
+    fn read_pairs<'a, I>(&'a self, entries: I, limit: Option<usize>) -> ScanBytesIter<'a>
+    where I: Iterator<Item = Result<(Vec<u8>, CommandPos)>> + 'a
+    {
+        let pairs = entries.filter_map(move |entry| match entry {
+            Ok((_, cmd_pos)) if self.had_expired(&cmd_pos) => None,
+            Ok((key, cmd_pos)) => Some(self.reader.read_value(&cmd_pos).map(|value| (key, value))),
+            Err(err) => Some(Err(err)),
+        });
+        Box::new(pairs.take(limit.unwrap_or(usize::MAX)))
+    }
+
+    /// Whether the key at `cmd_pos` had expired when the snapshot was taken.
+    fn had_expired(&self, cmd_pos: &CommandPos) -> bool {
+        cmd_pos.expires_at.is_some_and(|deadline| deadline <= self.taken_at)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        // the logs left for the snapshot are removed again by the next compaction
+        // if this fails.
+        let _ = self.pin.pins.unpin(self.pin.epoch, &self.pin.path);
+    }
+}
+
+/// Keeps the log generations retired by compactions, and the value logs retired
+/// by their garbage collection, on disk for as long as a snapshot taken before
+/// may still read them.
+///
+/// Every retirement starts a new epoch. A file retired in some epoch is only
+/// deleted once no snapshot taken in that epoch or an earlier one is alive.
+#[derive(Default)]
+pub struct SnapshotPins {
+    state: Mutex<PinState>,
+}
+
+#[derive(Default)]
+struct PinState {
+    epoch: u64,
+    // the number of live snapshots taken in each epoch.
+    live: BTreeMap<u64, usize>,
+    // files retired but still on disk, and the epoch they were retired in.
+    retired: BTreeMap<Retired, u64>,
+}
+
+/// A file retired, but possibly still read by a snapshot.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Retired {
+    Log(u64),
+    ValueLog(u64),
+}
+
+/// The registration of a live snapshot with the `SnapshotPins` of its store.
+pub struct Pin {
+    pins: Arc<SnapshotPins>,
+    epoch: u64,
+    path: Arc<PathBuf>,
+}
+
+impl SnapshotPins {
+    /// Registers a snapshot taken now, which must be done with the writer locked so
+    /// that no compaction finishes in between.
+    pub fn pin(self: &Arc<Self>, path: Arc<PathBuf>) -> Result<Pin> {
+        let mut state = self.state.lock()?;
+        let epoch = state.epoch;
+        *state.live.entry(epoch).or_default() += 1;
+        Ok(Pin { pins: self.clone(), epoch, path })
+    }
+
+    /// Deletes the given log generations, which no longer hold anything the index
+    /// points at, or leaves them for the snapshots that may still read them.
+    pub fn retire<I: IntoIterator<Item = u64>>(&self, dir: &Path, gens: I) -> Result<()> {
+        self.retire_files(dir, gens.into_iter().map(Retired::Log))
+    }
+
+    /// Deletes the value log `id`, which no longer holds anything the index or the
+    /// history points at, or leaves it for the snapshots that may still read it.
+    pub fn retire_value_log(&self, dir: &Path, id: u64) -> Result<()> {
+        self.retire_files(dir, [Retired::ValueLog(id)])
+    }
+
+    fn retire_files<I: IntoIterator<Item = Retired>>(&self, dir: &Path, files: I) -> Result<()> {
+        let mut state = self.state.lock()?;
+        let epoch = state.epoch;
+        state.epoch += 1;
+        let pinned = state.live.keys().next().is_some_and(|&oldest| oldest <= epoch);
+        for file in files {
+            if pinned {
+                state.retired.entry(file).or_insert(epoch);
+            } else {
+                remove_file(dir, file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unregisters a snapshot and deletes the files nothing pins any more.
+    fn unpin(&self, epoch: u64, dir: &Path) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if let Some(count) = state.live.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                state.live.remove(&epoch);
+            }
+        }
+        let oldest = state.live.keys().next().copied().unwrap_or(u64::MAX);
+        let released: Vec<Retired> = state
+            .retired
+            .iter()
+            .filter(|&(_, &retired_in)| retired_in < oldest)
+            .map(|(&file, _)| file)
+            .collect();
+        for file in released {
+            state.retired.remove(&file);
+            remove_file(dir, file)?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes a retired file: a log with its hint file and its sorted index, or a
+/// value log.
+fn remove_file(dir: &Path, file: Retired) -> Result<()> {
+    let path = match file {
+        Retired::Log(gen) => log_path(dir, gen),
+        Retired::ValueLog(id) => value_log_path(dir, id),
+    };
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    match file {
+        Retired::Log(gen) => {
+            hint::remove_hint(dir, gen)?;
+            index::remove_index(dir, gen)
+        }
+        Retired::ValueLog(_) => Ok(()),
+    }
+}
@@ -0,0 +1,383 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender};
+
+use super::group_commit::GroupCommit;
+use super::history::History;
+use super::index::Index;
+use super::log_files::LogFiles;
+use super::options::ValueLogGcPolicy;
+use super::record::{self, LOG_HEADER_LEN};
+use super::snapshot::SnapshotPins;
+use super::{sorted_ids, CommandPos, KvReader};
+use crate::engines::dir::sync_dir;
+use crate::{KvsError, Result};
+
+/// Length of an encoded `ValuePointer`.
+pub const POINTER_LEN: usize = 24;
+
+// bytes of values relocated with the writer locked at a time.
+const RELOCATION_CHUNK: u64 = 4 * 1024 * 1024;
+
+/// Where a value stored apart from its key is: the value log it is in, and the
+/// position and length of its record there.
+///
+/// It is encoded as three `u64` LE, in that order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValuePointer {
+    pub id: u64,
+    pub pos: u64,
+    pub len: u64,
+}
+
+impl ValuePointer {
+    /// Encodes the pointer, as the value of its record.
+    pub fn encode(&self) -> [u8; POINTER_LEN] {
+        let mut bytes = [0; POINTER_LEN];
+        bytes[..8].copy_from_slice(&self.id.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.pos.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a pointer, `None` if `bytes` are not one.
+    pub fn decode(bytes: &[u8]) -> Option<ValuePointer> {
+        if bytes.len() != POINTER_LEN {
+            return None;
+        }
+        let field = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().expect("field is 8 bytes"));
+        Some(ValuePointer { id: field(0), pos: field(1), len: field(2) })
+    }
+}
+
+/// A record of a value log, as listed by `entries`.
+pub struct ValueEntry {
+    pub key: Vec<u8>,
+    pub seq: u64,
+    pub pos: u64,
+    pub len: u64,
+}
+
+pub fn value_log_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.vlog", id))
+}
+
+/// Lists the records of the value log mapped as `map`, up to a torn one a crash
+/// may have left at its end.
+pub fn entries(map: &[u8]) -> Vec<ValueEntry> {
+    let mut entries = Vec::new();
+    let mut pos = LOG_HEADER_LEN;
+    while let Some((key, stamp, len)) = map.get(pos as usize..).and_then(record::peek_record) {
+        entries.push(ValueEntry { key: key.to_vec(), seq: stamp.seq, pos, len });
+        pos += len;
+    }
+    entries
+}
+
+/// Returns the version of `key` written with sequence number `seq`, and whether
+/// it is the current one, if the index or the history still points at it.
+///
+/// A current version that has expired only counts if the history is kept, as it
+/// is to become part of it.
+pub fn find_version(index: &Index, history: &History, key: &[u8], seq: u64) -> Result<Option<(CommandPos, bool)>> {
+    if let Some(cmd_pos) = index.get(key)?.filter(|cmd_pos| cmd_pos.stamp.seq == seq) {
+        return Ok((history.is_enabled() || !cmd_pos.is_expired()).then_some((cmd_pos, true)));
+    }
+    Ok(history
+        .find_at(key, seq)
+        .filter(|version| version.cmd_pos.stamp.seq == seq && !version.removed)
+        .map(|version| (version.cmd_pos, false)))
+}
+
+/// Appends the values stored apart from their keys to the active value log, and
+/// moves on to a new one once it is `max_size` bytes long.
+///
+/// Values are written straight to the file rather than buffered, so that they
+/// can be read as soon as the records pointing at them are.
+pub struct ValueLogWriter {
+    path: Arc<PathBuf>,
+    files: Arc<LogFiles>,
+    // the active value log, created when the first value is written to it.
+    active_id: u64,
+    file: Option<File>,
+    pos: u64,
+    max_size: u64,
+    // whether values were written to the active value log since it was synced.
+    unsynced: bool,
+    // oldest value log still pointed at, shared with the readers.
+    safe_point: Arc<AtomicU64>,
+    // wakes up the garbage collection thread once a value log is sealed.
+    gc_trigger: Sender<CollectionRequest>,
+}
+
+impl ValueLogWriter {
+    pub fn new(
+        path: Arc<PathBuf>,
+        files: Arc<LogFiles>,
+        active_id: u64,
+        max_size: u64,
+        safe_point: Arc<AtomicU64>,
+        gc_trigger: Sender<CollectionRequest>,
+    ) -> ValueLogWriter {
+        ValueLogWriter {
+            path,
+            files,
+            active_id,
+            file: None,
+            pos: 0,
+            max_size,
+            unsynced: false,
+            safe_point,
+            gc_trigger,
+        }
+    }
+
+    /// Appends the encoded set record `record` to the active value log, `last_seq`
+    /// being the sequence number of the last write, for the header of a new one.
+    pub fn append(&mut self, record: &[u8], last_seq: u64) -> Result<ValuePointer> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let path = value_log_path(&self.path, self.active_id);
+                let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+                record::write_log_header(&mut file, last_seq)?;
+                sync_dir(&path)?;
+                self.pos = LOG_HEADER_LEN;
+                self.file.insert(file)
+            }
+        };
+        file.write_all(record)?;
+        let pointer = ValuePointer { id: self.active_id, pos: self.pos, len: record.len() as u64 };
+        self.pos += pointer.len;
+        self.unsynced = true;
+        if self.pos >= self.max_size {
+            self.seal()?;
+        }
+        Ok(pointer)
+    }
+
+    /// Seals the active value log, if anything was written to it, so that the
+    /// next values go to a new one.
+    ///
+    /// Returns the id of the value log now active, before which all are sealed.
+    pub fn seal(&mut self) -> Result<u64> {
+        if self.file.is_some() {
+            // only the active value log is synced with the log.
+            self.sync()?;
+            self.file = None;
+            self.active_id += 1;
+            self.files.set_active(self.active_id);
+            // the thread only stops when the store is dropped.
+            let _ = self.gc_trigger.send(None);
+        }
+        Ok(self.active_id)
+    }
+
+    /// Syncs the values written to the active value log since the last sync.
+    pub fn sync(&mut self) -> Result<()> {
+        if let (Some(file), true) = (&self.file, self.unsynced) {
+            file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Records that nothing points into the value log `id` nor the ones before
+    /// any more.
+    pub fn release(&self, id: u64) {
+        self.safe_point.fetch_max(id + 1, Ordering::SeqCst);
+    }
+}
+
+/// Asks the garbage collection thread to collect the value logs. Requests from
+/// `KvStore::collect_value_logs` carry a channel to send the outcome back on.
+pub type CollectionRequest = Option<Sender<Result<()>>>;
+
+/// Handle to the background thread that collects the garbage of the value logs
+/// of a `KvStore`, oldest first, independently of the compactions of its logs.
+///
+/// The values of a value log still pointed at are copied to the active one a
+/// few MiB at a time with the writer locked, and new pointer records point at
+/// the copies. The value log is deleted once the last `Snapshot` that may read
+/// it is dropped. Dropping the handle cancels a collection in progress and
+/// waits for the thread to exit.
+pub struct ValueLogCollector {
+    trigger: Sender<CollectionRequest>,
+    cancelled: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ValueLogCollector {
+    /// Spawns the garbage collection thread, which collects once per message sent
+    /// on the `trigger` end of the channel, and at the interval of the `policy`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn(
+        (trigger, requests): (Sender<CollectionRequest>, Receiver<CollectionRequest>),
+        committer: Weak<GroupCommit>,
+        index: Arc<Index>,
+        history: Arc<History>,
+        kvs_reader: KvReader,
+        pins: Arc<SnapshotPins>,
+        path: Arc<PathBuf>,
+        policy: ValueLogGcPolicy,
+    ) -> ValueLogCollector {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let worker = CollectionWorker {
+            committer,
+            index,
+            history,
+            kvs_reader,
+            pins,
+            path,
+            cancelled: cancelled.clone(),
+        };
+        let handle = thread::spawn(move || loop {
+            let request = match policy {
+                ValueLogGcPolicy::GarbageRatio { interval, .. } => match requests.recv_timeout(interval) {
+                    Ok(request) => request,
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                ValueLogGcPolicy::Disabled => match requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => break,
+                },
+            };
+            if worker.cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+            // a failed collection leaves the value log in place; it is picked up
+            // again by the next one.
+            match (request, policy) {
+                (Some(done), _) => {
+                    let _ = done.send(worker.collect_all());
+                }
+                (None, ValueLogGcPolicy::GarbageRatio { ratio, .. }) => {
+                    let _ = worker.collect_oldest(ratio);
+                }
+                (None, ValueLogGcPolicy::Disabled) => {}
+            }
+        });
+        ValueLogCollector {
+            trigger,
+            cancelled,
+            handle: Some(handle),
+        }
+    }
+
+    /// Collects every value log written so far, waiting for it to finish.
+    pub fn collect(&self) -> Result<()> {
+        let (done, outcome) = bounded(1);
+        self.trigger
+            .send(Some(done))
+            .map_err(|_| KvsError::StringError("value log collection thread has exited".to_owned()))?;
+        outcome
+            .recv()
+            .map_err(|_| KvsError::StringError("value log collection thread has exited".to_owned()))?
+    }
+}
+
+impl Drop for ValueLogCollector {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        let _ = self.trigger.send(None);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct CollectionWorker {
+    committer: Weak<GroupCommit>,
+    index: Arc<Index>,
+    history: Arc<History>,
+    kvs_reader: KvReader,
+    pins: Arc<SnapshotPins>,
+    path: Arc<PathBuf>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CollectionWorker {
+    /// Seals the active value log and collects all of them, oldest first.
+    fn collect_all(&self) -> Result<()> {
+        let active_id = match self.committer.upgrade() {
+            Some(committer) => committer.writer()?.value_log.seal()?,
+            None => return Ok(()),
+        };
+        let files = &self.kvs_reader.value_logs;
+        for id in sorted_ids(&self.path, "vlog")?.into_iter().take_while(|&id| id < active_id) {
+            if files.is_retired(id) {
+                continue;
+            }
+            if self.cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+            let map = files.map(id)?;
+            self.collect(id, &map, entries(&map))?;
+        }
+        Ok(())
+    }
+
+    /// Collects the oldest sealed value log if at least `ratio` of its bytes are
+    /// garbage.
+    fn collect_oldest(&self, ratio: f64) -> Result<()> {
+        let files = &self.kvs_reader.value_logs;
+        let id = match sorted_ids(&self.path, "vlog")?.into_iter().find(|&id| !files.is_retired(id)) {
+            Some(id) if files.is_sealed(id) => id,
+            _ => return Ok(()),
+        };
+        let map = files.map(id)?;
+        let entries = entries(&map);
+        let mut live = 0;
+        for entry in &entries {
+            // only an estimate: the values are checked again as they are copied.
+            if find_version(&self.index, &self.history, &entry.key, entry.seq)?.is_some() {
+                live += entry.len;
+            }
+        }
+        let total = (map.len() as u64).saturating_sub(LOG_HEADER_LEN);
+        if (total - live.min(total)) as f64 >= total as f64 * ratio {
+            self.collect(id, &map, entries)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the live values of the value log `id`, mapped as `map`, to the
+    /// active one, then retires it.
+    fn collect(&self, id: u64, map: &[u8], entries: Vec<ValueEntry>) -> Result<()> {
+        let mut rest = &entries[..];
+        while !rest.is_empty() {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let mut chunk_len = 0;
+            let count = rest
+                .iter()
+                .take_while(|entry| {
+                    let fits = chunk_len < RELOCATION_CHUNK;
+                    chunk_len += entry.len;
+                    fits
+                })
+                .count();
+            let (chunk, tail) = rest.split_at(count);
+            match self.committer.upgrade() {
+                Some(committer) => committer.writer()?.relocate_values(id, map, chunk)?,
+                None => return Ok(()),
+            }
+            rest = tail;
+        }
+
+        match self.committer.upgrade() {
+            Some(committer) => committer.writer()?.finish_value_log_gc(id)?,
+            None => return Ok(()),
+        }
+        // delete the value log, unless a snapshot may still read it.
+        self.kvs_reader.value_logs.forget(&[id]);
+        self.pins.retire_value_log(&self.path, id)
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::HashSet;
+use std::fs;
+use std::iter;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crossbeam::channel::{unbounded, Sender};
+
+use self::compaction::{CompactionRequest, Compactor};
+use self::manifest::Manifest;
+use self::memtable::Memtable;
+use self::table::{remove_table, table_number, Table};
+use self::wal::{remove_wal, wal_number, WalWriter};
+use crate::engines::dir::DirLock;
+use crate::engines::BatchOp;
+use crate::{KvsEngine, KvsError, ReadVersions, Result, ScanBytesIter, Version, WriteBatch};
+
+pub use self::options::LsmOptions;
+
+mod bloom;
+mod compaction;
+mod manifest;
+mod memtable;
+mod options;
+mod table;
+mod wal;
+
+/// Number of levels of tables, level 0 included.
+const LEVEL_COUNT: usize = 7;
+
+/// The `LsmKvsEngine` stores binary key/value pairs in a log-structured merge
+/// tree.
+///
+/// Writes go to a write-ahead log (see `wal::WalWriter`) and to a sorted
+/// `SkipMap` in memory, the memtable. Once the memtable reaches
+/// `LsmOptions::memtable_size`, it is frozen and a new one started with a new
+/// log, and a background thread flushes the frozen one to an immutable sorted
+/// table file (see `table::Table`). Tables are organized in levels: flushes add
+/// tables to level 0, whose tables may overlap, and compactions merge them down
+/// into the deeper levels, where the tables of a level hold disjoint key ranges
+/// and every level is several times larger than the one above it (see
+/// `compaction::Compactor`). A `MANIFEST` file records which tables make up
+/// every level.
+///
+/// A read looks the key up in the memtables, then in the tables from the newest
+/// to the oldest, skipping the tables whose bloom filter rules the key out. Scans
+/// merge all of them in key order, reading the tables a block at a time, which
+/// suits datasets larger than memory.
+///
+/// Every write is numbered; the number of the write that set a key is its
+/// `Version`, so the engine supports transactions. It has no expiry nor history.
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    shared: Arc<Shared>,
+    writer: Arc<Mutex<LsmWriter>>,
+    compactor: Arc<Compactor>,
+    // exclusive lock of the directory, released once the compactor has stopped
+    _lock: Arc<DirLock>,
+}
+
+/// The newest write of a key known to a memtable or a table: its sequence
+/// number, and the value it set, `None` for a removal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub seq: u64,
+    pub value: Option<Vec<u8>>,
+}
+
+/// What the engine, its writer and its compaction thread share.
+struct Shared {
+    dir: PathBuf,
+    options: LsmOptions,
+    state: RwLock<Arc<State>>,
+    // the next number to give a log or a table file.
+    next_number: AtomicU64,
+}
+
+/// The memtables and the tables the engine reads from.
+///
+/// It is replaced as a whole by every change, so a reader that took it goes on
+/// with a consistent set even as the memtable is frozen or tables are compacted.
+#[derive(Clone)]
+struct State {
+    mem: Arc<Memtable>,
+    // the frozen memtables waiting for a flush, newest first.
+    frozen: Vec<Arc<Memtable>>,
+    // the tables of every level, newest first for level 0 and in key order for
+    // the others.
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+/// Appends writes to the write-ahead log and the memtable, one at a time.
+struct LsmWriter {
+    wal: WalWriter,
+    seq: u64,
+    shared: Arc<Shared>,
+    compaction_trigger: Sender<CompactionRequest>,
+}
+
+impl LsmKvsEngine {
+    /// Opens a `LsmKvsEngine` with the given path, with the default options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or table corruption errors during the recovery.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with_options(path, LsmOptions::default())
+    }
+
+    /// Opens a `LsmKvsEngine` with the given path and options.
+    ///
+    /// The tables listed in the manifest are opened, and the writes of the logs
+    /// that were not flushed yet are replayed into a memtable.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StoreLocked` if another store has the directory
+    /// open, and propagates I/O errors.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmKvsEngine> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        let lock = DirLock::acquire(&dir)?;
+
+        let mut manifest = Manifest::load(&dir)?.unwrap_or_default();
+        if manifest.levels.len() < LEVEL_COUNT {
+            manifest.levels.resize(LEVEL_COUNT, Vec::new());
+        }
+        let levels = manifest
+            .levels
+            .iter()
+            .map(|numbers| numbers.iter().map(|&number| Table::open(&dir, number).map(Arc::new)).collect())
+            .collect::<Result<Vec<Vec<_>>>>()?;
+
+        // a crash can leave behind the tables of a compaction that was not saved
+        // to the manifest, and the logs of a flush that was.
+        let listed: HashSet<_> = manifest.levels.iter().flatten().copied().collect();
+        let mut last_number = manifest.log_number;
+        let mut wals = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if let Some(number) = table_number(&path) {
+                last_number = last_number.max(number);
+                if !listed.contains(&number) {
+                    remove_table(&path)?;
+                }
+            } else if let Some(number) = wal_number(&path) {
+                last_number = last_number.max(number);
+                if number < manifest.log_number {
+                    remove_wal(&dir, number)?;
+                } else {
+                    wals.push(number);
+                }
+            }
+        }
+        wals.sort_unstable();
+
+        let mut seq = manifest.last_seq;
+        let replayed = Arc::new(Memtable::new(wals.first().copied().unwrap_or(manifest.log_number)));
+        for &number in &wals {
+            wal::replay(&dir, number, |record_seq, ops| {
+                seq = seq.max(record_seq);
+                apply(&replayed, record_seq, ops);
+            })?;
+        }
+        // the replayed writes are flushed like a frozen memtable, which also
+        // removes their logs.
+        let frozen = if wals.is_empty() { Vec::new() } else { vec![replayed] };
+
+        let number = last_number + 1;
+        let wal = WalWriter::create(&dir, number, options.sync_writes)?;
+        let shared = Arc::new(Shared {
+            dir,
+            options,
+            state: RwLock::new(Arc::new(State {
+                mem: Arc::new(Memtable::new(number)),
+                frozen,
+                levels,
+            })),
+            next_number: AtomicU64::new(number + 1),
+        });
+
+        let (compaction_trigger, compaction_requests) = unbounded();
+        let writer = LsmWriter {
+            wal,
+            seq,
+            shared: shared.clone(),
+            compaction_trigger: compaction_trigger.clone(),
+        };
+        let compactor = Compactor::spawn((compaction_trigger, compaction_requests), shared.clone(), manifest);
+        // the logs replayed and the tables found may already call for work.
+        let _ = writer.compaction_trigger.send(None);
+
+        Ok(LsmKvsEngine {
+            shared,
+            writer: Arc::new(Mutex::new(writer)),
+            compactor: Arc::new(compactor),
+            _lock: Arc::new(lock),
+        })
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writer.lock()?.write(vec![BatchOp::Set { key, value }])
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.shared.state()?.get(&key)?.and_then(|entry| entry.value))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut writer = self.writer.lock()?;
+        // with the writer locked, nothing can write the key in between.
+        match self.shared.state()?.get(&key)? {
+            Some(Entry { value: Some(_), .. }) => writer.write(vec![BatchOp::Remove { key }]),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.writer.lock()?.write(batch.into_ops())
+    }
+
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        let mut writer = self.writer.lock()?;
+        let current = self.shared.state()?.get(&key)?.and_then(|entry| entry.value);
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => writer.write(vec![BatchOp::Set { key, value }])?,
+            None if current.is_some() => writer.write(vec![BatchOp::Remove { key }])?,
+            // the key is absent, as expected.
+            None => {}
+        }
+        Ok(true)
+    }
+
+    fn compact(&self) -> Result<()> {
+        {
+            let mut writer = self.writer.lock()?;
+            if !self.shared.state()?.mem.is_empty() {
+                writer.freeze()?;
+            }
+        }
+        self.compactor.compact()
+    }
+
+    fn get_versioned(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Version)>> {
+        Ok(self.shared.state()?.get(&key)?.and_then(|entry| {
+            let version = version(entry.seq);
+            entry.value.map(|value| (value, version))
+        }))
+    }
+
+    fn commit(&self, reads: ReadVersions, batch: WriteBatch) -> Result<()> {
+        let mut writer = self.writer.lock()?;
+        let state = self.shared.state()?;
+        for (key, read) in reads {
+            let current = state.get(&key)?.filter(|entry| entry.value.is_some()).map(|entry| version(entry.seq));
+            if current != read {
+                return Err(KvsError::TransactionConflict);
+            }
+        }
+        writer.write(batch.into_ops())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let end = range.end_bound().cloned();
+        let entries = self
+            .shared
+            .state()?
+            .entries(range.start_bound().cloned())
+            .take_while(move |entry| {
+                entry.as_ref().map_or(true, |(key, _)| match &end {
+                    Bound::Included(end) => key <= end,
+                    Bound::Excluded(end) => key < end,
+                    Bound::Unbounded => true,
+                })
+            });
+        Ok(into_pairs(entries, limit))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let entries = self
+            .shared
+            .state()?
+            .entries(Bound::Included(prefix.clone()))
+            .take_while(move |entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix)));
+        Ok(into_pairs(entries, limit))
+    }
+}
+
+impl Shared {
+    fn state(&self) -> Result<Arc<State>> {
+        Ok(self.state.read()?.clone())
+    }
+
+    /// Replaces the state with a copy changed by `change`.
+    fn update(&self, change: impl FnOnce(&mut State)) -> Result<()> {
+        let mut state = self.state.write()?;
+        let mut next = State::clone(&state);
+        change(&mut next);
+        *state = Arc::new(next);
+        Ok(())
+    }
+
+    fn next_number(&self) -> u64 {
+        self.next_number.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+impl State {
+    /// Returns the newest write of `key`, `None` if it was never written or its
+    /// removal was compacted away.
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if let Some(entry) = self.mem.get(key) {
+            return Ok(Some(entry));
+        }
+        if let Some(entry) = self.frozen.iter().find_map(|memtable| memtable.get(key)) {
+            return Ok(Some(entry));
+        }
+        for table in &self.levels[0] {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        for tables in &self.levels[1..] {
+            // at most one table of a deeper level can hold the key.
+            let candidate = tables.partition_point(|table| table.last_key() < key);
+            if let Some(entry) = tables.get(candidate).map(|table| table.get(key)).transpose()?.flatten() {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the newest write of every key from `lower` on, in key order,
+    /// removals included.
+    fn entries(&self, lower: Bound<Vec<u8>>) -> MergeIter {
+        let mut sources: Vec<Source> = Vec::new();
+        for memtable in iter::once(&self.mem).chain(&self.frozen) {
+            sources.push(Box::new(memtable.iter(lower.clone()).map(Ok)));
+        }
+        for table in &self.levels[0] {
+            sources.push(Box::new(table.iter(lower.clone())));
+        }
+        for tables in &self.levels[1..] {
+            sources.push(level_iter(tables.clone(), lower.clone()));
+        }
+        MergeIter::new(sources)
+    }
+}
+
+impl LsmWriter {
+    /// Logs and applies the writes of `ops` as a single write.
+    fn write(&mut self, ops: Vec<BatchOp>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let seq = self.seq + 1;
+        self.wal.append(seq, &ops)?;
+        self.seq = seq;
+        let memtable = self.shared.state()?.mem.clone();
+        apply(&memtable, seq, ops);
+        if memtable.size() >= self.shared.options.memtable_size {
+            self.freeze()?;
+        }
+        Ok(())
+    }
+
+    /// Freezes the memtable and moves on to a new one with a new log, leaving the
+    /// frozen one for the compaction thread to flush.
+    fn freeze(&mut self) -> Result<()> {
+        let number = self.shared.next_number();
+        self.wal = WalWriter::create(&self.shared.dir, number, self.shared.options.sync_writes)?;
+        self.shared.update(|state| {
+            let full = mem::replace(&mut state.mem, Arc::new(Memtable::new(number)));
+            state.frozen.insert(0, full);
+        })?;
+        let _ = self.compaction_trigger.send(None);
+        Ok(())
+    }
+}
+
+/// Applies the writes of `ops`, numbered `seq`, to `memtable`.
+fn apply(memtable: &Memtable, seq: u64, ops: Vec<BatchOp>) {
+    for op in ops {
+        match op {
+            BatchOp::Set { key, value } => memtable.insert(key, Entry { seq, value: Some(value) }),
+            BatchOp::Remove { key } => memtable.insert(key, Entry { seq, value: None }),
+        }
+    }
+}
+
+/// The version of a key set by the write numbered `seq`.
+fn version(seq: u64) -> Version {
+    Version { gen: 0, pos: seq }
+}
+
+/// Entries of a memtable, a table or a level, in key order.
+type Source = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry)>>>;
+
+/// The entries of the tables of a level from `lower` on, the tables being
+/// disjoint and in key order.
+fn level_iter(tables: Vec<Arc<Table>>, lower: Bound<Vec<u8>>) -> Source {
+    let skipped = tables.partition_point(|table| match &lower {
+        Bound::Included(lower) => table.last_key() < lower.as_slice(),
+        Bound::Excluded(lower) => table.last_key() <= lower.as_slice(),
+        Bound::Unbounded => false,
+    });
+    Box::new(tables.into_iter().skip(skipped).flat_map(move |table| table.iter(lower.clone())))
+}
+
+/// Merges sources of entries in key order into the newest entry of every key,
+/// the sources being ordered from the newest to the oldest.
+struct MergeIter {
+    sources: Vec<Source>,
+    // the next entry of every source, read ahead.
+    heads: Vec<Option<(Vec<u8>, Entry)>>,
+    started: bool,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<Source>) -> MergeIter {
+        let heads = sources.iter().map(|_| None).collect();
+        MergeIter { sources, heads, started: false }
+    }
+
+    fn advance(&mut self, source: usize) -> Result<()> {
+        // left empty if the source fails.
+        self.heads[source] = None;
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            for source in 0..self.sources.len() {
+                if let Err(err) = self.advance(source) {
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        // the first of the sources at the smallest key is the newest.
+        let newest = (0..self.heads.len())
+            .filter_map(|source| self.heads[source].as_ref().map(|(key, _)| (key, source)))
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, source)| source)?;
+        let (key, entry) = self.heads[newest].take().expect("the newest source has an entry");
+        for source in 0..self.heads.len() {
+            let behind = source == newest || self.heads[source].as_ref().is_some_and(|(other, _)| *other == key);
+            if behind {
+                if let Err(err) = self.advance(source) {
+                    return Some(Err(err));
+                }
+            }
+        }
+        Some(Ok((key, entry)))
+    }
+}
+
+/// Drops the removals from merged entries, and keeps at most `limit` pairs.
+fn into_pairs(
+    entries: impl Iterator<Item = Result<(Vec<u8>, Entry)>> + 'static,
+    limit: Option<usize>,
+) -> ScanBytesIter<'static> {
+    let pairs = entries.filter_map(|entry| match entry {
+        Ok((key, Entry { value: Some(value), .. })) => Some(Ok((key, value))),
+        Ok(_) => None,
+        Err(err) => Some(Err(err)),
+    });
+    Box::new(pairs.take(limit.unwrap_or(usize::MAX)))
+}
@@ -0,0 +1,72 @@
+/// A bloom filter over the keys of a table, to skip the tables that cannot hold
+/// a key without reading any of their blocks.
+///
+/// Every key sets `probes` bits picked by double hashing, as in LevelDB. On disk
+/// the filter is its bits followed by the number of probes as a `u8`.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u8,
+}
+
+impl BloomFilter {
+    /// Builds a filter of `bits_per_key` bits per key over the given key hashes.
+    ///
+    /// With no bits per key, the filter is empty and lets every key through.
+    pub fn build(hashes: &[u32], bits_per_key: usize) -> BloomFilter {
+        if bits_per_key == 0 {
+            return BloomFilter { bits: Vec::new(), probes: 0 };
+        }
+        // ln(2) * bits per key probes give the fewest false positives.
+        let probes = (bits_per_key * 69 / 100).clamp(1, 30) as u8;
+        let bit_count = (hashes.len() * bits_per_key).max(64);
+        let mut filter = BloomFilter {
+            bits: vec![0; bit_count.div_ceil(8)],
+            probes,
+        };
+        let bit_count = filter.bits.len() * 8;
+        for &hash in hashes {
+            for bit in probe_bits(hash, probes, bit_count) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Returns `false` if the key is certainly not in the table.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        if self.bits.is_empty() {
+            return true;
+        }
+        probe_bits(hash(key), self.probes, self.bits.len() * 8)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.bits.clone();
+        bytes.push(self.probes);
+        bytes
+    }
+
+    pub fn decode(mut bytes: Vec<u8>) -> BloomFilter {
+        let probes = bytes.pop().unwrap_or(0);
+        BloomFilter { bits: bytes, probes }
+    }
+}
+
+/// Hashes a key for the filter, with 32-bit FNV-1a and the finalizer of
+/// MurmurHash3 to spread keys that only differ in their last bytes.
+pub fn hash(key: &[u8]) -> u32 {
+    let mut hash = key
+        .iter()
+        .fold(0x811c_9dc5u32, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193));
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+fn probe_bits(hash: u32, probes: u8, bit_count: usize) -> impl Iterator<Item = usize> {
+    let delta = hash.rotate_right(17);
+    (0..probes as u32).map(move |probe| hash.wrapping_add(probe.wrapping_mul(delta)) as usize % bit_count)
+}
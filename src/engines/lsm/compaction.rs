@@ -0,0 +1,322 @@
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+
+use super::manifest::Manifest;
+use super::table::{Table, TableWriter};
+use super::wal::remove_wal;
+use super::{level_iter, Entry, MergeIter, Shared, Source, LEVEL_COUNT};
+use crate::{KvsError, Result};
+
+/// Asks the compaction thread for the flushes and compactions that are due, or,
+/// from `LsmKvsEngine::compact`, for a full compaction, with a channel to send
+/// the outcome back on.
+pub type CompactionRequest = Option<Sender<Result<()>>>;
+
+/// Handle to the background thread that flushes the frozen memtables of a
+/// `LsmKvsEngine` to tables, and compacts the tables.
+///
+/// A frozen memtable is flushed to a new table of level 0, after which its
+/// write-ahead log is removed. Once level 0 has `LsmOptions::level0_tables`
+/// tables, they are merged with the tables of level 1 they overlap into new
+/// tables of level 1. Once a deeper level outgrows its size, one of its tables,
+/// taken in turn across the key space, is merged with the tables it overlaps in
+/// the level below. The merges only keep the newest entry of every key, and drop
+/// removals once no deeper level may hold the key.
+///
+/// Readers go on with the tables they started with; the replaced ones are marked
+/// obsolete and their files removed once no reader holds them. Dropping the
+/// handle cancels the work left and waits for the thread to exit.
+pub struct Compactor {
+    trigger: Sender<CompactionRequest>,
+    cancelled: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Spawns the compaction thread, which works once per message sent on the
+    /// `trigger` end of the channel.
+    pub fn spawn(
+        (trigger, requests): (Sender<CompactionRequest>, Receiver<CompactionRequest>),
+        shared: Arc<Shared>,
+        manifest: Manifest,
+    ) -> Compactor {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut worker = CompactionWorker {
+            shared,
+            manifest,
+            pointers: vec![None; LEVEL_COUNT],
+            cancelled: cancelled.clone(),
+        };
+        let handle = thread::spawn(move || {
+            for request in requests {
+                if worker.cancelled.load(Ordering::SeqCst) {
+                    break;
+                }
+                match request {
+                    // a failed flush or compaction leaves everything in place;
+                    // it is tried again on the next request.
+                    None => {
+                        let _ = worker.flush().and_then(|()| worker.compact_levels());
+                    }
+                    Some(done) => {
+                        let _ = done.send(worker.compact_all());
+                    }
+                }
+            }
+        });
+        Compactor {
+            trigger,
+            cancelled,
+            handle: Some(handle),
+        }
+    }
+
+    /// Flushes the frozen memtables and merges all the tables into one level,
+    /// waiting for it to finish.
+    pub fn compact(&self) -> Result<()> {
+        let (done, outcome) = bounded(1);
+        self.trigger
+            .send(Some(done))
+            .map_err(|_| KvsError::StringError("compaction thread has exited".to_owned()))?;
+        outcome
+            .recv()
+            .map_err(|_| KvsError::StringError("compaction thread has exited".to_owned()))?
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        let _ = self.trigger.send(None);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct CompactionWorker {
+    shared: Arc<Shared>,
+    // the manifest as last saved; only this thread saves it.
+    manifest: Manifest,
+    // for every level, the last key of the table it last compacted.
+    pointers: Vec<Option<Vec<u8>>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CompactionWorker {
+    /// Flushes the frozen memtables to level 0, oldest first.
+    fn flush(&mut self) -> Result<()> {
+        loop {
+            let state = self.shared.state()?;
+            let Some(memtable) = state.frozen.last().cloned() else {
+                return Ok(());
+            };
+            let entries = memtable.iter(Bound::Unbounded).map(Ok);
+            let tables = self.write_tables(entries, false, u64::MAX)?;
+
+            let mut levels = state.levels.clone();
+            for table in tables.into_iter().rev() {
+                levels[0].insert(0, table);
+            }
+            // the memtable written after it holds the oldest writes left in a log.
+            let next = state.frozen.iter().rev().nth(1).unwrap_or(&state.mem);
+            let log_number = next.wal;
+            let last_seq = self.manifest.last_seq.max(memtable.last_seq());
+            let flushed_logs = self.manifest.log_number..log_number;
+            self.save(&levels, log_number, last_seq)?;
+            self.shared.update(|state| {
+                state.frozen.retain(|frozen| !Arc::ptr_eq(frozen, &memtable));
+                state.levels = levels;
+            })?;
+            for number in flushed_logs {
+                remove_wal(&self.shared.dir, number)?;
+            }
+        }
+    }
+
+    /// Compacts the levels that call for it until none does.
+    fn compact_levels(&mut self) -> Result<()> {
+        while !self.cancelled.load(Ordering::SeqCst) {
+            let state = self.shared.state()?;
+            let options = &self.shared.options;
+            let level = if state.levels[0].len() >= options.level0_tables.max(1) {
+                0
+            } else {
+                let max_size = |level: u32| {
+                    options.level1_size.saturating_mul(options.level_size_multiplier.saturating_pow(level - 1))
+                };
+                match (1..LEVEL_COUNT - 1).find(|&level| level_size(&state.levels[level]) > max_size(level as u32)) {
+                    Some(level) => level,
+                    None => return Ok(()),
+                }
+            };
+            self.compact_level(&state.levels, level)?;
+        }
+        Ok(())
+    }
+
+    /// Merges all of level 0, or one table of a deeper level, with the tables
+    /// they overlap in the level below.
+    fn compact_level(&mut self, levels: &[Vec<Arc<Table>>], level: usize) -> Result<()> {
+        let inputs = if level == 0 {
+            levels[0].clone()
+        } else {
+            let tables = &levels[level];
+            let next = self.pointers[level]
+                .as_ref()
+                .and_then(|pointer| tables.iter().position(|table| table.first_key() > pointer.as_slice()))
+                .unwrap_or(0);
+            vec![tables[next].clone()]
+        };
+        let first = inputs.iter().map(|table| table.first_key()).min().expect("a level compacted has tables");
+        let last = inputs.iter().map(|table| table.last_key()).max().expect("a level compacted has tables");
+        let overlapping: Vec<_> = levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(first, last))
+            .cloned()
+            .collect();
+        // a removal has to stay as long as an older entry of the key may be below.
+        let bottom = levels[level + 2..]
+            .iter()
+            .flatten()
+            .all(|table| !table.overlaps(first, last));
+        self.pointers[level] = Some(last.to_vec());
+
+        // newest first: level 0 is kept newest first, and a level is newer than
+        // the one below.
+        let mut sources: Vec<Source> = inputs
+            .iter()
+            .map(|table| Box::new(table.iter(Bound::Unbounded)) as Source)
+            .collect();
+        sources.push(level_iter(overlapping.clone(), Bound::Unbounded));
+        let outputs = self.write_tables(MergeIter::new(sources), bottom, self.shared.options.table_size)?;
+
+        let replaced: Vec<_> = inputs.into_iter().chain(overlapping).collect();
+        let numbers: HashSet<_> = replaced.iter().map(|table| table.number()).collect();
+        let mut levels = levels.to_vec();
+        for tables in &mut levels {
+            tables.retain(|table| !numbers.contains(&table.number()));
+        }
+        levels[level + 1].extend(outputs);
+        levels[level + 1].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        self.install(levels, replaced)
+    }
+
+    /// Flushes the frozen memtables, then merges all the tables into the deepest
+    /// level in use, dropping every removal.
+    fn compact_all(&mut self) -> Result<()> {
+        self.flush()?;
+        let state = self.shared.state()?;
+        if state.levels.iter().all(Vec::is_empty) {
+            return Ok(());
+        }
+        let level = (1..LEVEL_COUNT).rev().find(|&level| !state.levels[level].is_empty()).unwrap_or(1);
+
+        let mut sources: Vec<Source> = state.levels[0]
+            .iter()
+            .map(|table| Box::new(table.iter(Bound::Unbounded)) as Source)
+            .collect();
+        sources.extend(state.levels[1..].iter().map(|tables| level_iter(tables.clone(), Bound::Unbounded)));
+        let outputs = self.write_tables(MergeIter::new(sources), true, self.shared.options.table_size)?;
+
+        let mut levels = vec![Vec::new(); LEVEL_COUNT];
+        levels[level] = outputs;
+        self.install(levels, state.levels.iter().flatten().cloned().collect())?;
+        self.compact_levels()
+    }
+
+    /// Saves and puts in place the levels left by a compaction, and leaves the
+    /// tables it replaced to be removed.
+    fn install(&mut self, levels: Vec<Vec<Arc<Table>>>, replaced: Vec<Arc<Table>>) -> Result<()> {
+        self.save(&levels, self.manifest.log_number, self.manifest.last_seq)?;
+        self.shared.update(|state| state.levels = levels)?;
+        for table in replaced {
+            table.mark_obsolete();
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, levels: &[Vec<Arc<Table>>], log_number: u64, last_seq: u64) -> Result<()> {
+        let manifest = Manifest {
+            log_number,
+            last_seq,
+            levels: levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.number()).collect())
+                .collect(),
+        };
+        if let Err(err) = manifest.save(&self.shared.dir) {
+            // the new tables are not listed anywhere, so they can go.
+            let listed: HashSet<_> = self.manifest.levels.iter().flatten().collect();
+            for table in levels.iter().flatten().filter(|table| !listed.contains(&table.number())) {
+                table.mark_obsolete();
+            }
+            return Err(err);
+        }
+        self.manifest = manifest;
+        Ok(())
+    }
+
+    /// Writes `entries` to new tables, starting a new one past `table_size` bytes.
+    ///
+    /// The tables written are removed again if it fails.
+    fn write_tables(
+        &self,
+        entries: impl Iterator<Item = Result<(Vec<u8>, Entry)>>,
+        drop_removals: bool,
+        table_size: u64,
+    ) -> Result<Vec<Arc<Table>>> {
+        let mut tables = Vec::new();
+        let mut writer = None;
+        let result = self.fill_tables(entries, drop_removals, table_size, &mut tables, &mut writer);
+        if let Err(err) = result {
+            if let Some(writer) = writer {
+                let _ = writer.abandon();
+            }
+            for table in &tables {
+                table.mark_obsolete();
+            }
+            return Err(err);
+        }
+        Ok(tables)
+    }
+
+    fn fill_tables(
+        &self,
+        entries: impl Iterator<Item = Result<(Vec<u8>, Entry)>>,
+        drop_removals: bool,
+        table_size: u64,
+        tables: &mut Vec<Arc<Table>>,
+        writer: &mut Option<TableWriter>,
+    ) -> Result<()> {
+        let (dir, bloom_bits_per_key) = (&self.shared.dir, self.shared.options.bloom_bits_per_key);
+        for entry in entries {
+            let (key, entry) = entry?;
+            if drop_removals && entry.value.is_none() {
+                continue;
+            }
+            let current = match writer {
+                Some(current) => current,
+                None => writer.insert(TableWriter::create(dir, self.shared.next_number())?),
+            };
+            current.push(&key, &entry)?;
+            if current.len() >= table_size {
+                let full = writer.take().expect("a table is being written");
+                tables.push(Arc::new(full.finish(dir, bloom_bits_per_key)?));
+            }
+        }
+        if let Some(last) = writer.take() {
+            tables.push(Arc::new(last.finish(dir, bloom_bits_per_key)?));
+        }
+        Ok(())
+    }
+}
+
+fn level_size(tables: &[Arc<Table>]) -> u64 {
+    tables.iter().map(|table| table.size()).sum()
+}
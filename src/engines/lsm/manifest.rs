@@ -0,0 +1,111 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::engines::dir::sync_dir;
+use crate::{KvsError, Result};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+/// Magic bytes at the start of the manifest.
+const MANIFEST_MAGIC: [u8; 4] = *b"KVSM";
+/// Version of the manifest format written by this build.
+const MANIFEST_VERSION: u8 = 1;
+// magic + version + crc
+const MANIFEST_HEADER_LEN: usize = MANIFEST_MAGIC.len() + 1 + 4;
+
+/// Which tables make up every level, and which write-ahead logs still hold writes
+/// that are in no table.
+///
+/// The manifest is rewritten as a whole after every flush and compaction, to a
+/// temporary file renamed over the previous one, so it is always either the old
+/// or the new one. It is laid out as `KVSM`, the format version as a `u8`, and
+/// the CRC-32 of the rest as a `u32` LE, followed by `log_number u64 |
+/// last_seq u64 | level_count u32`, then `table_count u32` and the table numbers
+/// as `u64` for every level, all LE.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    /// The oldest log whose writes may not be in a table yet; older ones can go.
+    pub log_number: u64,
+    /// The sequence number of the last write in a table, at least.
+    pub last_seq: u64,
+    /// The table numbers of every level, newest first for level 0 and in key
+    /// order for the others.
+    pub levels: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    /// Reads the manifest of `dir`, `None` if there is none yet.
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let bytes = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let damaged = || KvsError::StringError(format!("damaged manifest in {}", dir.display()));
+        if bytes.len() < MANIFEST_HEADER_LEN
+            || bytes[..MANIFEST_MAGIC.len()] != MANIFEST_MAGIC
+            || bytes[MANIFEST_MAGIC.len()] != MANIFEST_VERSION
+        {
+            return Err(damaged());
+        }
+        let body = &bytes[MANIFEST_HEADER_LEN..];
+        if crc32fast::hash(body) != read_u32(&bytes[MANIFEST_HEADER_LEN - 4..]) {
+            return Err(damaged());
+        }
+
+        let mut rest = body;
+        let mut take = |len: usize| {
+            let (field, tail) = rest.split_at_checked(len).ok_or_else(damaged)?;
+            rest = tail;
+            Ok::<_, KvsError>(field)
+        };
+        let log_number = read_u64(take(8)?);
+        let last_seq = read_u64(take(8)?);
+        let mut levels = Vec::new();
+        for _ in 0..read_u32(take(4)?) {
+            let count = read_u32(take(4)?) as usize;
+            let numbers = take(count.checked_mul(8).ok_or_else(damaged)?)?;
+            levels.push(numbers.chunks(8).map(read_u64).collect());
+        }
+        if !rest.is_empty() {
+            return Err(damaged());
+        }
+        Ok(Some(Manifest { log_number, last_seq, levels }))
+    }
+
+    /// Replaces the manifest of `dir` with this one.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&self.log_number.to_le_bytes());
+        body.extend_from_slice(&self.last_seq.to_le_bytes());
+        body.extend_from_slice(&(self.levels.len() as u32).to_le_bytes());
+        for level in &self.levels {
+            body.extend_from_slice(&(level.len() as u32).to_le_bytes());
+            for number in level {
+                body.extend_from_slice(&number.to_le_bytes());
+            }
+        }
+
+        let tmp_path = dir.join(MANIFEST_TMP_FILE);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&MANIFEST_MAGIC)?;
+        file.write_all(&[MANIFEST_VERSION])?;
+        file.write_all(&crc32fast::hash(&body).to_le_bytes())?;
+        file.write_all(&body)?;
+        file.sync_data()?;
+        let path = dir.join(MANIFEST_FILE);
+        fs::rename(&tmp_path, &path)?;
+        // the rename is only kept after a crash once the directory is on disk too.
+        sync_dir(&path)?;
+        Ok(())
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().expect("field is 8 bytes"))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().expect("field is 4 bytes"))
+}
@@ -0,0 +1,103 @@
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::Entry;
+
+// bytes counted for an entry on top of its key and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// The newest writes to the engine, in memory, before they are flushed to a table.
+///
+/// Only the writer inserts; readers go through it concurrently. An overwritten
+/// key has its entry updated in place, as replacing it in the `SkipMap` would
+/// leave a moment when readers miss the key. Once full, the memtable is frozen:
+/// it is kept for readers, and no longer written, until the table it is flushed
+/// to is in place.
+pub struct Memtable {
+    /// The write-ahead log of the memtable, the oldest one if it was replayed
+    /// from several.
+    pub wal: u64,
+    entries: SkipMap<Vec<u8>, Mutex<Entry>>,
+    size: AtomicUsize,
+    last_seq: AtomicU64,
+}
+
+impl Memtable {
+    pub fn new(wal: u64) -> Memtable {
+        Memtable {
+            wal,
+            entries: SkipMap::new(),
+            size: AtomicUsize::new(0),
+            last_seq: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.entries.get(key).map(|entry| read(entry.value()))
+    }
+
+    pub fn insert(&self, key: Vec<u8>, entry: Entry) {
+        let len = key.len() + entry.value.as_ref().map_or(0, Vec::len) + ENTRY_OVERHEAD;
+        self.size.fetch_add(len, Ordering::SeqCst);
+        self.last_seq.fetch_max(entry.seq, Ordering::SeqCst);
+        match self.entries.get(&key) {
+            Some(current) => *current.value().lock().unwrap_or_else(PoisonError::into_inner) = entry,
+            None => {
+                self.entries.insert(key, Mutex::new(entry));
+            }
+        }
+    }
+
+    /// Approximate number of bytes taken by the writes made to the memtable,
+    /// overwritten ones included.
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The sequence number of the last write made to the memtable, 0 if none.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::SeqCst)
+    }
+
+    /// Returns the entries of the memtable in key order, from `lower` on.
+    pub fn iter(self: &Arc<Self>, lower: Bound<Vec<u8>>) -> MemtableIter {
+        MemtableIter { memtable: self.clone(), lower }
+    }
+}
+
+/// The entries of a memtable in key order.
+///
+/// Every step looks the next key up again, so the iterator does not borrow the
+/// memtable, and sees the keys inserted ahead of it in the meantime.
+pub struct MemtableIter {
+    memtable: Arc<Memtable>,
+    lower: Bound<Vec<u8>>,
+}
+
+impl Iterator for MemtableIter {
+    type Item = (Vec<u8>, Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self
+            .memtable
+            .entries
+            .range::<[u8], _>((self.lower.as_ref().map(Vec::as_slice), Bound::Unbounded))
+            .next()?;
+        let key = next.key().clone();
+        self.lower = Bound::Excluded(key.clone());
+        Some((key, read(next.value())))
+    }
+}
+
+// an entry is only ever replaced as a whole, so a panic elsewhere does not leave
+// it inconsistent.
+fn read(entry: &Mutex<Entry>) -> Entry {
+    entry.lock().unwrap_or_else(PoisonError::into_inner).clone()
+}
@@ -0,0 +1,91 @@
+/// Options to configure how a `LsmKvsEngine` is opened.
+///
+/// ```rust
+/// # use kvs::{LsmKvsEngine, LsmOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let options = LsmOptions::new().memtable_size(16 * 1024 * 1024).sync_writes(true);
+/// let engine = LsmKvsEngine::open_with_options(current_dir()?, options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    pub(super) memtable_size: usize,
+    pub(super) table_size: u64,
+    pub(super) level0_tables: usize,
+    pub(super) level1_size: u64,
+    pub(super) level_size_multiplier: u64,
+    pub(super) bloom_bits_per_key: usize,
+    pub(super) sync_writes: bool,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level1_size: 10 * 1024 * 1024,
+            level_size_multiplier: 10,
+            bloom_bits_per_key: 10,
+            sync_writes: false,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// Creates options with the default settings, which are what `LsmKvsEngine::open` uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the size in bytes the memtable grows to before it is flushed to a
+    /// table, 4 MiB by default.
+    pub fn memtable_size(mut self, memtable_size: usize) -> Self {
+        self.memtable_size = memtable_size;
+        self
+    }
+
+    /// Sets the size in bytes past which a compaction starts a new table, 2 MiB by
+    /// default.
+    pub fn table_size(mut self, table_size: u64) -> Self {
+        self.table_size = table_size;
+        self
+    }
+
+    /// Sets the number of tables flushed to level 0 that get them merged into
+    /// level 1, 4 by default.
+    pub fn level0_tables(mut self, level0_tables: usize) -> Self {
+        self.level0_tables = level0_tables;
+        self
+    }
+
+    /// Sets the size in bytes of level 1 past which its tables are merged into
+    /// level 2, 10 MiB by default.
+    pub fn level1_size(mut self, level1_size: u64) -> Self {
+        self.level1_size = level1_size;
+        self
+    }
+
+    /// Sets how many times larger every level past the first one may grow than
+    /// the one above it, 10 by default.
+    pub fn level_size_multiplier(mut self, multiplier: u64) -> Self {
+        self.level_size_multiplier = multiplier;
+        self
+    }
+
+    /// Sets the number of bits of bloom filter every table keeps per key, 10 by
+    /// default for about 1% of false positives. 0 writes no filter.
+    pub fn bloom_bits_per_key(mut self, bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bits_per_key;
+        self
+    }
+
+    /// Sets whether the write-ahead log is synced before every write returns,
+    /// `false` by default, which only flushes it to the operating system.
+    pub fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+}
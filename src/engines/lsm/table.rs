@@ -0,0 +1,425 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::vec;
+
+use super::bloom::{self, BloomFilter};
+use super::Entry;
+use crate::{KvsError, Result};
+
+/// Magic bytes at the start of every table file.
+const TABLE_MAGIC: [u8; 4] = *b"KVST";
+/// Version of the table format written by this build.
+const TABLE_VERSION: u8 = 1;
+// magic + version
+const TABLE_HEADER_LEN: u64 = TABLE_MAGIC.len() as u64 + 1;
+// key length (4) + value length (4) + sequence number (8) + kind (1)
+const ENTRY_HEADER_LEN: usize = 17;
+// key length (4) + offset (8) + length (4) + crc (4)
+const BLOCK_REF_LEN: usize = 20;
+// filter offset (8) + block table offset (8) + last key offset (8) + crc (4)
+// + entry count (8)
+const TRAILER_LEN: u64 = 36;
+/// Size past which a block of entries is closed and a new one started.
+const BLOCK_SIZE: usize = 4096;
+
+const ENTRY_SET: u8 = 1;
+const ENTRY_REMOVE: u8 = 2;
+
+/// An immutable sorted table of keys and their newest write, the unit a memtable
+/// is flushed to and compactions merge.
+///
+/// On disk a table is laid out as
+///
+/// ```text
+/// +--------+--------+--------+-------------+----------+---------+
+/// | header | blocks | filter | block table | last key | trailer |
+/// +--------+--------+--------+-------------+----------+---------+
+/// ```
+///
+/// The header is `KVST` and the format version as a `u8`. Every block holds a few
+/// KiB of entries in key order, each laid out as
+/// `key_len u32 | value_len u32 | seq u64 | kind u8 | key | value`, all LE, with
+/// a kind of 1 for a set and 2 for a removal. The filter is the `BloomFilter` of
+/// the keys, and the block table has a `key_len u32 | offset u64 | len u32 |
+/// crc u32 | first key` entry per block, `crc` being the CRC-32 of the block. The
+/// trailer is the offsets of the filter, of the block table and of the last key
+/// as `u64` LE, the CRC-32 of everything between the blocks and the trailer as a
+/// `u32` LE, and the number of entries as a `u64` LE.
+///
+/// Only the filter and the first key of every block are kept in memory; blocks
+/// are read from the file as they are needed. A table replaced by a compaction is
+/// marked obsolete, and its file is removed once the last reader lets go of it.
+pub struct Table {
+    number: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    blocks: Vec<BlockRef>,
+    filter: BloomFilter,
+    last_key: Vec<u8>,
+    size: u64,
+    obsolete: AtomicBool,
+}
+
+struct BlockRef {
+    first_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+    crc: u32,
+}
+
+impl Table {
+    /// Opens the table `number` of `dir`.
+    pub fn open(dir: &Path, number: u64) -> Result<Table> {
+        let path = table_path(dir, number);
+        let mut file = File::open(&path)?;
+        let damaged = || KvsError::StringError(format!("damaged table {}", path.display()));
+
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < TABLE_HEADER_LEN + TRAILER_LEN {
+            return Err(damaged());
+        }
+        let mut header = [0u8; TABLE_HEADER_LEN as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        file.seek(SeekFrom::Start(size - TRAILER_LEN))?;
+        file.read_exact(&mut trailer)?;
+        let filter_offset = read_u64(&trailer[..8]);
+        let (table_offset, last_key_offset) = (read_u64(&trailer[8..16]), read_u64(&trailer[16..24]));
+        if header[..TABLE_MAGIC.len()] != TABLE_MAGIC
+            || header[TABLE_MAGIC.len()] != TABLE_VERSION
+            || !(TABLE_HEADER_LEN <= filter_offset
+                && filter_offset <= table_offset
+                && table_offset <= last_key_offset
+                && last_key_offset <= size - TRAILER_LEN)
+        {
+            return Err(damaged());
+        }
+
+        let mut meta = vec![0u8; (size - TRAILER_LEN - filter_offset) as usize];
+        file.seek(SeekFrom::Start(filter_offset))?;
+        file.read_exact(&mut meta)?;
+        if crc32fast::hash(&meta) != read_u32(&trailer[24..28]) {
+            return Err(damaged());
+        }
+        let mut last_key = meta.split_off((last_key_offset - filter_offset) as usize);
+        let table = meta.split_off((table_offset - filter_offset) as usize);
+        let filter = BloomFilter::decode(meta);
+
+        let mut blocks = Vec::new();
+        let mut rest = table.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < BLOCK_REF_LEN {
+                return Err(damaged());
+            }
+            let (head, tail) = rest.split_at(BLOCK_REF_LEN);
+            let key_len = read_u32(&head[..4]) as usize;
+            let (offset, len) = (read_u64(&head[4..12]), read_u32(&head[12..16]));
+            if tail.len() < key_len || offset.saturating_add(u64::from(len)) > filter_offset {
+                return Err(damaged());
+            }
+            let (first_key, tail) = tail.split_at(key_len);
+            blocks.push(BlockRef { first_key: first_key.to_vec(), offset, len, crc: read_u32(&head[16..20]) });
+            rest = tail;
+        }
+        if blocks.is_empty() {
+            return Err(damaged());
+        }
+        last_key.shrink_to_fit();
+
+        Ok(Table {
+            number,
+            path,
+            file: Mutex::new(file),
+            blocks,
+            filter,
+            last_key,
+            size,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    /// Size of the table file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn first_key(&self) -> &[u8] {
+        &self.blocks[0].first_key
+    }
+
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    /// Whether the keys of the table and the keys from `first` to `last` overlap.
+    pub fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        self.first_key() <= last && first <= self.last_key()
+    }
+
+    /// Returns the newest write of `key` in the table, if any.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if key > self.last_key() || !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        match self.block_of(key) {
+            Some(block) => Ok(self
+                .read_block(block)?
+                .into_iter()
+                .find(|(entry_key, _)| entry_key.as_slice() == key)
+                .map(|(_, entry)| entry)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the entries of the table in key order, from `lower` on.
+    pub fn iter(self: &Arc<Self>, lower: Bound<Vec<u8>>) -> TableIter {
+        let block = match &lower {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_of(key).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        TableIter { table: self.clone(), block, entries: Vec::new().into_iter(), lower }
+    }
+
+    /// Leaves the file of the table to be removed once it is no longer read.
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the block `key` would be in, `None` if it comes before them all.
+    fn block_of(&self, key: &[u8]) -> Option<usize> {
+        self.blocks
+            .partition_point(|block| block.first_key.as_slice() <= key)
+            .checked_sub(1)
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<(Vec<u8>, Entry)>> {
+        let block = &self.blocks[block];
+        let mut buf = vec![0u8; block.len as usize];
+        {
+            let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+            file.seek(SeekFrom::Start(block.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        let damaged = || {
+            KvsError::StringError(format!("damaged table block at offset {} of {}", block.offset, self.path.display()))
+        };
+        if crc32fast::hash(&buf) != block.crc {
+            return Err(damaged());
+        }
+
+        let mut entries = Vec::new();
+        let mut rest = buf.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < ENTRY_HEADER_LEN {
+                return Err(damaged());
+            }
+            let (head, tail) = rest.split_at(ENTRY_HEADER_LEN);
+            let (key_len, value_len) = (read_u32(&head[..4]) as usize, read_u32(&head[4..8]) as usize);
+            if tail.len() < key_len + value_len {
+                return Err(damaged());
+            }
+            let (key, tail) = tail.split_at(key_len);
+            let (value, tail) = tail.split_at(value_len);
+            let value = match head[16] {
+                ENTRY_SET => Some(value.to_vec()),
+                ENTRY_REMOVE => None,
+                _ => return Err(damaged()),
+            };
+            entries.push((key.to_vec(), Entry { seq: read_u64(&head[8..16]), value }));
+            rest = tail;
+        }
+        Ok(entries)
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            // a file left behind is removed on the next open.
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The entries of a table in key order, read a block at a time.
+pub struct TableIter {
+    table: Arc<Table>,
+    // the next block to read.
+    block: usize,
+    entries: vec::IntoIter<(Vec<u8>, Entry)>,
+    lower: Bound<Vec<u8>>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.block >= self.table.blocks.len() {
+                return None;
+            }
+            let mut entries = match self.table.read_block(self.block) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    self.block = self.table.blocks.len();
+                    return Some(Err(err));
+                }
+            };
+            self.block += 1;
+            // only the first block read can hold keys before `lower`.
+            let skipped = entries.partition_point(|(key, _)| match &self.lower {
+                Bound::Included(lower) => key < lower,
+                Bound::Excluded(lower) => key <= lower,
+                Bound::Unbounded => false,
+            });
+            entries.drain(..skipped);
+            self.lower = Bound::Unbounded;
+            self.entries = entries.into_iter();
+        }
+    }
+}
+
+/// Writes a table, see `Table`.
+pub struct TableWriter {
+    number: u64,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    pos: u64,
+    // the entries of the block being filled.
+    block: Vec<u8>,
+    blocks: Vec<BlockRef>,
+    last_key: Vec<u8>,
+    hashes: Vec<u32>,
+}
+
+impl TableWriter {
+    /// Creates the table `number` of `dir`.
+    pub fn create(dir: &Path, number: u64) -> Result<Self> {
+        let path = table_path(dir, number);
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writer.write_all(&TABLE_MAGIC)?;
+        writer.write_all(&[TABLE_VERSION])?;
+        Ok(TableWriter {
+            number,
+            path,
+            writer,
+            pos: TABLE_HEADER_LEN,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            blocks: Vec::new(),
+            last_key: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    /// Adds the entry of `key`, which comes after the keys added so far.
+    pub fn push(&mut self, key: &[u8], entry: &Entry) -> Result<()> {
+        if self.block.is_empty() {
+            self.blocks.push(BlockRef { first_key: key.to_vec(), offset: self.pos, len: 0, crc: 0 });
+        }
+        let value = entry.value.as_deref().unwrap_or_default();
+        let key_len = u32::try_from(key.len()).expect("keys are checked to fit when logged");
+        let value_len = u32::try_from(value.len()).expect("values are checked to fit when logged");
+        self.block.extend_from_slice(&key_len.to_le_bytes());
+        self.block.extend_from_slice(&value_len.to_le_bytes());
+        self.block.extend_from_slice(&entry.seq.to_le_bytes());
+        self.block.push(if entry.value.is_some() { ENTRY_SET } else { ENTRY_REMOVE });
+        self.block.extend_from_slice(key);
+        self.block.extend_from_slice(value);
+        self.last_key = key.to_vec();
+        self.hashes.push(bloom::hash(key));
+        if self.block.len() >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Number of bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.pos + self.block.len() as u64
+    }
+
+    /// Writes the filter, the block table and the trailer, syncs the file and
+    /// opens it as a table.
+    pub fn finish(mut self, dir: &Path, bloom_bits_per_key: usize) -> Result<Table> {
+        self.write_block()?;
+        let mut meta = BloomFilter::build(&self.hashes, bloom_bits_per_key).encode();
+        let table_offset = self.pos + meta.len() as u64;
+        for block in &self.blocks {
+            let key_len = u32::try_from(block.first_key.len()).expect("keys are checked to fit when logged");
+            meta.extend_from_slice(&key_len.to_le_bytes());
+            meta.extend_from_slice(&block.offset.to_le_bytes());
+            meta.extend_from_slice(&block.len.to_le_bytes());
+            meta.extend_from_slice(&block.crc.to_le_bytes());
+            meta.extend_from_slice(&block.first_key);
+        }
+        let last_key_offset = self.pos + meta.len() as u64;
+        meta.extend_from_slice(&self.last_key);
+
+        self.writer.write_all(&meta)?;
+        self.writer.write_all(&self.pos.to_le_bytes())?;
+        self.writer.write_all(&table_offset.to_le_bytes())?;
+        self.writer.write_all(&last_key_offset.to_le_bytes())?;
+        self.writer.write_all(&crc32fast::hash(&meta).to_le_bytes())?;
+        self.writer.write_all(&(self.hashes.len() as u64).to_le_bytes())?;
+        self.writer.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+        Table::open(dir, self.number)
+    }
+
+    /// Removes the table being written.
+    pub fn abandon(self) -> Result<()> {
+        drop(self.writer);
+        remove_table(&self.path)
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = self.blocks.last_mut().expect("a block is started with its first entry");
+        block.len = u32::try_from(self.block.len()).expect("blocks hold at most one entry past a few KiB");
+        block.crc = crc32fast::hash(&self.block);
+        self.writer.write_all(&self.block)?;
+        self.pos += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// Returns the number of the table at `path`, `None` if it is not a table file.
+pub fn table_number(path: &Path) -> Option<u64> {
+    if path.extension()? != "sst" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+pub fn table_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{}.sst", number))
+}
+
+pub fn remove_table(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().expect("field is 8 bytes"))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().expect("field is 4 bytes"))
+}
@@ -0,0 +1,179 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::engines::BatchOp;
+use crate::{KvsError, Result};
+
+// crc (4) + length (4)
+const RECORD_HEADER_LEN: usize = 8;
+// sequence number (8) + write count (4)
+const PAYLOAD_HEADER_LEN: usize = 12;
+// kind (1) + key length (4) + value length (4)
+const OP_HEADER_LEN: usize = 9;
+
+const OP_SET: u8 = 1;
+const OP_REMOVE: u8 = 2;
+
+/// Appends the writes made to the memtable to its write-ahead log, so that they
+/// survive a restart until the memtable is flushed to a table.
+///
+/// Every write to the engine, a single set or remove or a whole batch, is one
+/// record laid out as `crc u32 | len u32 | seq u64 | count u32` followed by
+/// `count` writes as `kind u8 | key_len u32 | value_len u32 | key | value`, all
+/// LE. `crc` is the CRC-32 of the `len` bytes after the header, so a batch is
+/// replayed completely or not at all.
+pub struct WalWriter {
+    writer: BufWriter<File>,
+    sync: bool,
+}
+
+impl WalWriter {
+    /// Creates the log `number` of `dir`, syncing every record if `sync` is set.
+    pub fn create(dir: &Path, number: u64, sync: bool) -> Result<Self> {
+        let file = OpenOptions::new().create_new(true).write(true).open(wal_path(dir, number))?;
+        Ok(WalWriter { writer: BufWriter::new(file), sync })
+    }
+
+    /// Appends the writes of `ops` with the sequence number `seq`.
+    pub fn append(&mut self, seq: u64, ops: &[BatchOp]) -> Result<()> {
+        let mut payload = Vec::with_capacity(PAYLOAD_HEADER_LEN);
+        payload.extend_from_slice(&seq.to_le_bytes());
+        payload.extend_from_slice(&encode_len(ops.len())?);
+        for op in ops {
+            let (kind, key, value): (u8, &[u8], &[u8]) = match op {
+                BatchOp::Set { key, value } => (OP_SET, key, value),
+                BatchOp::Remove { key } => (OP_REMOVE, key, &[]),
+            };
+            payload.push(kind);
+            payload.extend_from_slice(&encode_len(key.len())?);
+            payload.extend_from_slice(&encode_len(value.len())?);
+            payload.extend_from_slice(key);
+            payload.extend_from_slice(value);
+        }
+
+        let len = encode_len(payload.len())?;
+        self.writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        self.writer.write_all(&len)?;
+        self.writer.write_all(&payload)?;
+        self.writer.flush()?;
+        if self.sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays the log `number` of `dir`, calling `apply` with the sequence number
+/// and the writes of every record in order.
+///
+/// A record cut short at the end of the log, by a crash in the middle of a write,
+/// is truncated away; a damaged record anywhere else fails with
+/// `KvsError::Corruption`. A record whose length is damaged may run past the end
+/// of the log as well, so only a record no valid one follows is taken for a
+/// torn tail.
+pub fn replay(dir: &Path, number: u64, mut apply: impl FnMut(u64, Vec<BatchOp>)) -> Result<()> {
+    let path = wal_path(dir, number);
+    let bytes = fs::read(&path)?;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let rest = &bytes[pos..];
+        let corrupted = KvsError::Corruption { gen: number, offset: pos as u64 };
+        let len = match record_len(rest) {
+            Some(len) => len,
+            None if (1..rest.len()).any(|next| is_record_at(&rest[next..])) => return Err(corrupted),
+            None => {
+                OpenOptions::new().write(true).open(&path)?.set_len(pos as u64)?;
+                break;
+            }
+        };
+        let payload = &rest[RECORD_HEADER_LEN..len];
+        if crc32fast::hash(payload) != read_u32(&rest[..4]) {
+            if pos + len == bytes.len() {
+                // the last record, written only in part.
+                OpenOptions::new().write(true).open(&path)?.set_len(pos as u64)?;
+                break;
+            }
+            return Err(corrupted);
+        }
+        let (seq, ops) = decode_payload(payload).ok_or(corrupted)?;
+        apply(seq, ops);
+        pos += len;
+    }
+    Ok(())
+}
+
+/// Returns the length of the record at the start of `bytes`, header included,
+/// `None` if `bytes` end before it does.
+fn record_len(bytes: &[u8]) -> Option<usize> {
+    let len = RECORD_HEADER_LEN + read_u32(bytes.get(4..RECORD_HEADER_LEN)?) as usize;
+    (len <= bytes.len()).then_some(len)
+}
+
+/// Whether `bytes` start with a whole record whose checksum matches.
+fn is_record_at(bytes: &[u8]) -> bool {
+    record_len(bytes).is_some_and(|len| crc32fast::hash(&bytes[RECORD_HEADER_LEN..len]) == read_u32(bytes))
+}
+
+fn decode_payload(payload: &[u8]) -> Option<(u64, Vec<BatchOp>)> {
+    if payload.len() < PAYLOAD_HEADER_LEN {
+        return None;
+    }
+    let seq = read_u64(&payload[..8]);
+    let count = read_u32(&payload[8..12]) as usize;
+    let mut ops = Vec::new();
+    let mut rest = &payload[PAYLOAD_HEADER_LEN..];
+    for _ in 0..count {
+        if rest.len() < OP_HEADER_LEN {
+            return None;
+        }
+        let (head, tail) = rest.split_at(OP_HEADER_LEN);
+        let (key_len, value_len) = (read_u32(&head[1..5]) as usize, read_u32(&head[5..9]) as usize);
+        if tail.len() < key_len + value_len {
+            return None;
+        }
+        let (key, tail) = tail.split_at(key_len);
+        let (value, tail) = tail.split_at(value_len);
+        ops.push(match head[0] {
+            OP_SET => BatchOp::Set { key: key.to_vec(), value: value.to_vec() },
+            OP_REMOVE => BatchOp::Remove { key: key.to_vec() },
+            _ => return None,
+        });
+        rest = tail;
+    }
+    rest.is_empty().then_some((seq, ops))
+}
+
+/// Returns the number of the log at `path`, `None` if it is not a log file.
+pub fn wal_number(path: &Path) -> Option<u64> {
+    if path.extension()? != "wal" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+pub fn wal_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{}.wal", number))
+}
+
+/// Removes the log `number` of `dir`, if any.
+pub fn remove_wal(dir: &Path, number: u64) -> Result<()> {
+    match fs::remove_file(wal_path(dir, number)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn encode_len(len: usize) -> Result<[u8; 4]> {
+    u32::try_from(len)
+        .map(u32::to_le_bytes)
+        .map_err(|_| KvsError::StringError(format!("record field of {} bytes is too large", len)))
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().expect("field is 8 bytes"))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().expect("field is 4 bytes"))
+}
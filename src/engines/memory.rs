@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crossbeam_skiplist::SkipMap;
+
+use crate::engines::BatchOp;
+use crate::{KvsEngine, KvsError, ReadVersions, Result, ScanBytesIter, Version, WriteBatch};
+
+/// The `MemoryKvsEngine` keeps binary key/value pairs in memory only, in a
+/// concurrent ordered map; they are gone once the last clone of the engine is
+/// dropped.
+///
+/// Reads and scans go through the map without locking it, while writes are
+/// applied one at a time. An overwritten key has its value updated in place,
+/// so a reader never misses it. Every write is numbered, and the number of the
+/// write that set a key is its `Version`, so the engine supports transactions.
+/// It has no expiry nor history.
+///
+/// Built with `MemoryKvsEngine::with_max_bytes`, it serves as a bounded cache:
+/// once its keys and values take more than the given number of bytes, the least
+/// recently read or written keys are evicted, though never the key written last.
+///
+/// ```rust
+/// # use kvs::{KvsEngine, MemoryKvsEngine};
+/// let cache = MemoryKvsEngine::with_max_bytes(64 * 1024 * 1024);
+/// cache.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(cache.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: SkipMap<Vec<u8>, Mutex<Slot>>,
+    // the sequence number of the last write; writers hold it for the whole write.
+    writer: Mutex<u64>,
+    // `None` without a size limit.
+    recency: Option<Mutex<Recency>>,
+}
+
+/// The current value of a key and the sequence number of the write that set it.
+#[derive(Clone)]
+struct Slot {
+    seq: u64,
+    value: Vec<u8>,
+}
+
+/// The keys of a bounded engine from the least to the most recently used, and
+/// the bytes they take.
+struct Recency {
+    max_bytes: u64,
+    size: u64,
+    clock: u64,
+    // the last use and the size of every key.
+    keys: HashMap<Vec<u8>, (u64, u64)>,
+    order: BTreeMap<u64, Vec<u8>>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty `MemoryKvsEngine` without a size limit.
+    pub fn new() -> MemoryKvsEngine {
+        MemoryKvsEngine::default()
+    }
+
+    /// Creates an empty `MemoryKvsEngine` that evicts the least recently used
+    /// keys once its keys and values take more than `max_bytes` bytes.
+    pub fn with_max_bytes(max_bytes: u64) -> MemoryKvsEngine {
+        let recency = Recency {
+            max_bytes,
+            size: 0,
+            clock: 0,
+            keys: HashMap::new(),
+            order: BTreeMap::new(),
+        };
+        MemoryKvsEngine {
+            inner: Arc::new(Inner {
+                recency: Some(Mutex::new(recency)),
+                ..Inner::default()
+            }),
+        }
+    }
+
+    /// Reads a key, marking it as used.
+    fn read(&self, key: &[u8]) -> Result<Option<Slot>> {
+        let slot = self.peek(key);
+        if let (Some(_), Some(recency)) = (&slot, &self.inner.recency) {
+            recency.lock()?.touch(key);
+        }
+        Ok(slot)
+    }
+
+    /// Reads a key without marking it as used.
+    fn peek(&self, key: &[u8]) -> Option<Slot> {
+        self.inner.entries.get(key).map(|entry| lock(entry.value()).clone())
+    }
+
+    /// Applies `ops` as one write, then evicts keys if the engine outgrew its
+    /// limit. The caller holds the writer lock, whose sequence number is `seq`.
+    fn apply(&self, seq: &mut u64, ops: Vec<BatchOp>) -> Result<()> {
+        *seq += 1;
+        let mut recency = self.inner.recency.as_ref().map(|recency| recency.lock()).transpose()?;
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    if let Some(recency) = recency.as_mut() {
+                        recency.written(&key, (key.len() + value.len()) as u64);
+                    }
+                    let slot = Slot { seq: *seq, value };
+                    match self.inner.entries.get(&key) {
+                        Some(current) => *lock(current.value()) = slot,
+                        None => {
+                            self.inner.entries.insert(key, Mutex::new(slot));
+                        }
+                    }
+                }
+                BatchOp::Remove { key } => {
+                    if let Some(recency) = recency.as_mut() {
+                        recency.removed(&key);
+                    }
+                    self.inner.entries.remove(&key);
+                }
+            }
+        }
+        if let Some(mut recency) = recency {
+            for key in recency.evict() {
+                self.inner.entries.remove(&key);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut seq = self.inner.writer.lock()?;
+        self.apply(&mut seq, vec![BatchOp::Set { key, value }])
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.read(&key)?.map(|slot| slot.value))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut seq = self.inner.writer.lock()?;
+        if self.peek(&key).is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.apply(&mut seq, vec![BatchOp::Remove { key }])
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut seq = self.inner.writer.lock()?;
+        self.apply(&mut seq, batch.into_ops())
+    }
+
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        let mut seq = self.inner.writer.lock()?;
+        let current = self.peek(&key).map(|slot| slot.value);
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.apply(&mut seq, vec![BatchOp::Set { key, value }])?,
+            None if current.is_some() => self.apply(&mut seq, vec![BatchOp::Remove { key }])?,
+            // the key is absent, as expected.
+            None => {}
+        }
+        Ok(true)
+    }
+
+    fn compact(&self) -> Result<()> {
+        // nothing is kept but the current values.
+        Ok(())
+    }
+
+    fn get_versioned(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Version)>> {
+        Ok(self.read(&key)?.map(|slot| (slot.value, version(slot.seq))))
+    }
+
+    fn commit(&self, reads: ReadVersions, batch: WriteBatch) -> Result<()> {
+        let mut seq = self.inner.writer.lock()?;
+        for (key, read) in reads {
+            if self.peek(&key).map(|slot| version(slot.seq)) != read {
+                return Err(KvsError::TransactionConflict);
+            }
+        }
+        self.apply(&mut seq, batch.into_ops())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let pairs = self
+            .inner
+            .entries
+            .range(range)
+            .map(|entry| Ok((entry.key().clone(), lock(entry.value()).value.clone())));
+        Ok(Box::new(pairs.take(limit.unwrap_or(usize::MAX))))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        let pairs = self
+            .inner
+            .entries
+            .range((Bound::Included(prefix.clone()), Bound::Unbounded))
+            .take_while(move |entry| entry.key().starts_with(&prefix))
+            .map(|entry| Ok((entry.key().clone(), lock(entry.value()).value.clone())));
+        Ok(Box::new(pairs.take(limit.unwrap_or(usize::MAX))))
+    }
+}
+
+impl Recency {
+    /// Marks `key` as the most recently used, if it is held.
+    fn touch(&mut self, key: &[u8]) {
+        if let Some((used, _)) = self.keys.get_mut(key) {
+            let key = self.order.remove(used).expect("a key held is in the order");
+            self.clock += 1;
+            *used = self.clock;
+            self.order.insert(self.clock, key);
+        }
+    }
+
+    /// Records that `key` was set to a value taking `size` bytes with the key.
+    fn written(&mut self, key: &[u8], size: u64) {
+        self.removed(key);
+        self.clock += 1;
+        self.size += size;
+        self.keys.insert(key.to_vec(), (self.clock, size));
+        self.order.insert(self.clock, key.to_vec());
+    }
+
+    fn removed(&mut self, key: &[u8]) {
+        if let Some((used, size)) = self.keys.remove(key) {
+            self.order.remove(&used);
+            self.size -= size;
+        }
+    }
+
+    /// Forgets the least recently used keys until the rest fit in `max_bytes`,
+    /// keeping at least the most recently used one, and returns them.
+    fn evict(&mut self) -> Vec<Vec<u8>> {
+        let mut evicted = Vec::new();
+        while self.size > self.max_bytes && self.order.len() > 1 {
+            let (_, key) = self.order.pop_first().expect("more than one key is held");
+            let (_, size) = self.keys.remove(&key).expect("a key in the order is held");
+            self.size -= size;
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+fn version(seq: u64) -> Version {
+    Version { gen: 0, pos: seq }
+}
+
+// a slot is only ever replaced as a whole, so a panic elsewhere does not leave
+// it inconsistent.
+fn lock(slot: &Mutex<Slot>) -> MutexGuard<'_, Slot> {
+    slot.lock().unwrap_or_else(PoisonError::into_inner)
+}
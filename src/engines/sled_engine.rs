@@ -1,6 +1,7 @@
-use crate::{KvsEngine, Result, KvsError};
-use {sled, sled::Db};
-use std::{path::PathBuf, sync::Arc};
+use crate::{KvsEngine, Result, KvsError, ScanBytesIter, WriteBatch};
+use crate::engines::BatchOp;
+use sled::{Batch, Db, Iter};
+use std::{ops::RangeBounds, path::PathBuf, sync::Arc};
 
 /// sled implemented kv store
 #[derive(Clone)]
@@ -19,23 +20,65 @@ impl SledKvsEngine {
 
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.sled_db.insert(key, value.as_str())?;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.sled_db.insert(key, value)?;
         self.sled_db.flush()?;
         Ok(())
     }
     
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let value = self.sled_db.get(key)?;
-        match value {
-            Some(ivec) => Ok(Some(String::from_utf8(ivec.to_vec())?)),
-            None => Ok(None)
-        }
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.sled_db.get(key)?.map(|ivec| ivec.to_vec()))
     }
     
-    fn remove(&self, key: String) -> Result<()> {
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
         self.sled_db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
         self.sled_db.flush()?;
         Ok(())
     }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = Batch::default();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => sled_batch.insert(key, value),
+                BatchOp::Remove { key } => sled_batch.remove(key),
+            }
+        }
+        self.sled_db.apply_batch(sled_batch)?;
+        self.sled_db.flush()?;
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        match self.sled_db.compare_and_swap(key, expected, new)? {
+            Ok(()) => {
+                self.sled_db.flush()?;
+                Ok(true)
+            }
+            // the current value is not the expected one.
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn compact(&self) -> Result<()> {
+        // sled reclaims space in the background on its own.
+        Ok(())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        Ok(into_pairs(self.sled_db.range(range), limit))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<ScanBytesIter<'_>> {
+        Ok(into_pairs(self.sled_db.scan_prefix(prefix), limit))
+    }
+}
+
+/// Turns a sled iterator into key/value pairs, at most `limit` of them.
+fn into_pairs(iter: Iter, limit: Option<usize>) -> ScanBytesIter<'static> {
+    let pairs = iter.map(|pair| {
+        let (key, value) = pair?;
+        Ok((key.to_vec(), value.to_vec()))
+    });
+    Box::new(pairs.take(limit.unwrap_or(usize::MAX)))
 }
\ No newline at end of file
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use super::{KvsEngine, ReadVersions, Version, WriteBatch};
+use crate::Result;
+
+/// An optimistic transaction over several keys of a `KvsEngine`.
+///
+/// Reads go to the engine and remember the version of what they read; writes are
+/// buffered and seen by the reads of the transaction that follow them. `commit`
+/// applies the writes all together, and only if none of the keys read has changed
+/// in the meantime; otherwise it fails with `KvsError::TransactionConflict` and
+/// the transaction can be retried from the start.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut txn = store.begin();
+/// let value = txn.get("key1".to_owned())?.unwrap_or_default();
+/// txn.set("key2".to_owned(), value);
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    log: TransactionLog,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Self {
+        Transaction { engine, log: TransactionLog::default() }
+    }
+
+    /// Gets the value of a key as the transaction sees it.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.log.written(&key) {
+            return Ok(value);
+        }
+        let versioned = self.engine.get_versioned(key.clone())?;
+        Ok(self.log.read(key, versioned))
+    }
+
+    /// Sets the value of a key when the transaction commits.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.log.set(key, value);
+    }
+
+    /// Removes a key when the transaction commits. Removing a key that does not
+    /// exist is not an error, like in a `WriteBatch`.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.log.remove(key);
+    }
+
+    /// Gets the string value of a string key as the transaction sees it.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a string key to a string when the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// Removes a string key when the transaction commits.
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Applies the writes of the transaction if none of the keys it read has
+    /// changed since.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if a key read has changed, in
+    /// which case nothing is written.
+    pub fn commit(self) -> Result<()> {
+        let (reads, batch) = self.log.into_parts();
+        self.engine.commit(reads, batch)
+    }
+}
+
+/// What a transaction has read and written so far, shared by the transactions of
+/// the engines and of `KvsClient`.
+#[derive(Default)]
+pub(crate) struct TransactionLog {
+    // the version of every key read from the store, `None` if it was absent.
+    reads: HashMap<Vec<u8>, Option<Version>>,
+    // the value every key written is left with, `None` if it was removed.
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    batch: WriteBatch,
+}
+
+impl TransactionLog {
+    /// Returns what the transaction left `key` with, if it wrote it.
+    pub(crate) fn written(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.writes.get(key).cloned()
+    }
+
+    /// Remembers the version of a read from the store and returns the value.
+    ///
+    /// Only the first read of a key counts, a later one could hide a change made
+    /// in between.
+    pub(crate) fn read(&mut self, key: Vec<u8>, versioned: Option<(Vec<u8>, Version)>) -> Option<Vec<u8>> {
+        let version = versioned.as_ref().map(|(_, version)| *version);
+        self.reads.entry(key).or_insert(version);
+        versioned.map(|(value, _)| value)
+    }
+
+    pub(crate) fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key.clone(), Some(value.clone()));
+        self.batch.set_bytes(key, value);
+    }
+
+    pub(crate) fn remove(&mut self, key: Vec<u8>) {
+        self.writes.insert(key.clone(), None);
+        self.batch.remove_bytes(key);
+    }
+
+    pub(crate) fn into_parts(self) -> (ReadVersions, WriteBatch) {
+        (self.reads.into_iter().collect(), self.batch)
+    }
+}
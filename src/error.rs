@@ -1,7 +1,8 @@
+// `failure`'s derive expands into impls nested in an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
-use rayon;
 use std::{io, string::FromUtf8Error, sync::PoisonError, any::type_name};
-use sled;
 
 /// Error type for kvs.
 #[derive(Fail, Debug)]
@@ -18,6 +19,9 @@ pub enum KvsError {
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Encoding or decoding error of a client/server message.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
     /// sled engine error
     #[fail(display = "{}", _0)]
     Sled(#[cause] sled::Error),
@@ -34,7 +38,51 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
-    
+    /// A transaction read a key that has changed before it committed.
+    #[fail(display = "Transaction conflict")]
+    TransactionConflict,
+    /// The engine does not support the operation.
+    #[fail(display = "{} not supported by this engine", _0)]
+    Unsupported(String),
+    /// A log record failed its checksum.
+    #[fail(display = "Corrupted record in log generation {} at offset {}", gen, offset)]
+    Corruption {
+        /// generation of the damaged log
+        gen: u64,
+        /// byte offset of the damaged record in the log
+        offset: u64,
+    },
+    /// An encrypted value failed to authenticate: the key it was read with is not
+    /// the one it was written with, or its record was tampered with.
+    #[fail(display = "Authentication failed for the record in log generation {} at offset {}", gen, offset)]
+    AuthenticationFailed {
+        /// generation of the log
+        gen: u64,
+        /// byte offset of the record in the log
+        offset: u64,
+    },
+    /// A value stored apart from its key is damaged, or not the one its key
+    /// points at.
+    #[fail(display = "Corrupted value in value log {} at offset {}", id, offset)]
+    ValueLogCorruption {
+        /// id of the damaged value log
+        id: u64,
+        /// byte offset of the value in the value log
+        offset: u64,
+    },
+    /// A value is encrypted with a key that was not given to the store.
+    #[fail(display = "No encryption key with fingerprint {:08x}", _0)]
+    UnknownEncryptionKey(u32),
+    /// Another store, in this process or another one, has the directory open
+    /// for writing.
+    #[fail(display = "Store is locked by another store")]
+    StoreLocked {
+        /// id of the process holding the lock, `None` if it could not be read
+        pid: Option<u32>,
+    },
+    /// The store is opened read-only.
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
 }
 
 impl From<io::Error> for KvsError {
@@ -55,6 +103,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::FromUtf8Error(err)
@@ -2,7 +2,11 @@
 //! A simple key/value store.
 
 pub use error::{KvsError, Result};
-pub use engines::{KvsEngine, KvStore, SledKvsEngine, Command};
+pub use engines::{
+    Cipher, CompactionPolicy, Compression, EncryptionKey, IndexMode, KvsEngine, KvStore, KvStoreOptions, KvStoreStats, LsmKvsEngine, LsmOptions, MemoryKvsEngine,
+    ReadCachePolicy, ReadVersions, RecoveryMode, RecoveryReport, RetentionPolicy, ScanBytesIter, ScanIter,
+    Snapshot, SyncPolicy, SledKvsEngine, Transaction, ValueLogGcPolicy, Version, WriteBatch, Command,
+};
 
 mod error;
 mod engines;
@@ -1,37 +1,203 @@
-use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::ops::Bound;
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{KvsError, ReadVersions, Result, Version, WriteBatch};
 
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
     Get {
-        key: String,
+        key: Vec<u8>,
     },
     Set {
-        key: String,
-        value: String
+        key: Vec<u8>,
+        value: Vec<u8>
     },
     Remove {
-        key: String
-    }
+        key: Vec<u8>
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    GetAt {
+        key: Vec<u8>,
+        seq: u64,
+    },
+    History {
+        key: Vec<u8>,
+    },
+    Compact,
+    Scan {
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    ScanPrefix {
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    GetVersioned {
+        key: Vec<u8>,
+    },
+    Commit {
+        reads: ReadVersions,
+        batch: WriteBatch,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
 }
 
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String)    
+    Ok(Option<Vec<u8>>),
+    Err(String)
 }
 
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String)    
+    Err(String)
 }
 
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String)    
-}
\ No newline at end of file
+    Err(String)
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum TtlResponse {
+    Ok(Option<Duration>),
+    Err(String)
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum HistoryResponse {
+    Ok(Vec<(u64, Option<Vec<u8>>)>),
+    Err(String)
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum CompactResponse {
+    Ok(()),
+    Err(String)
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ScanResponse {
+    /// One page of the scan, and whether more pairs follow the last one of it.
+    Ok(Vec<(Vec<u8>, Vec<u8>)>, bool),
+    Err(String)
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum BatchResponse {
+    Ok(()),
+    Err(String)
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum GetVersionedResponse {
+    Ok(Option<(Vec<u8>, Version)>),
+    Err(String)
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum CommitResponse {
+    Ok(()),
+    Conflict,
+    Err(String)
+}
+
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum CompareAndSwapResponse {
+    Ok(bool),
+    Err(String)
+}
+
+
+/// Writes `message` as one frame: the length of its bincode encoding as a `u32`
+/// LE, then the encoding itself. Keys and values are carried as raw bytes.
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let len = bincode::serialized_size(message)?;
+    let len = u32::try_from(len)
+        .map_err(|_| KvsError::StringError(format!("message of {} bytes is too large", len)))?;
+    writer.write_all(&len.to_le_bytes())?;
+    bincode::serialize_into(&mut *writer, message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a frame written by `write_message`.
+///
+/// Returns `None` if the stream ended between frames. A read timeout is only
+/// returned as an error while waiting for a frame to start; once it has, the
+/// rest of the frame is waited for.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    loop {
+        match reader.read(&mut len[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let mut reader = WaitOutTimeouts(reader);
+    reader.read_exact(&mut len[1..])?;
+    let len = u32::from_le_bytes(len) as u64;
+    // read through `take` so that a garbage length does not allocate more than
+    // what is actually sent.
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(bincode::deserialize(&buf)?))
+}
+
+/// Whether `err` is a read timeout, which is reported as `WouldBlock` on Unix and
+/// `TimedOut` on Windows.
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Retries the reads of the inner reader that time out.
+struct WaitOutTimeouts<R>(R);
+
+impl<R: Read> Read for WaitOutTimeouts<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(err) if is_timeout(&err) => continue,
+                result => return result,
+            }
+        }
+    }
+}
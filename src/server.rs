@@ -1,11 +1,15 @@
 use core::time;
-use std::{net::{TcpListener, ToSocketAddrs, TcpStream}, io::{BufWriter, Write, self}, sync::{Arc, atomic::{AtomicBool, Ordering}}, thread};
+use std::{net::{TcpListener, ToSocketAddrs, TcpStream}, io::{BufReader, BufWriter, self}, ops::Bound, sync::{Arc, atomic::{AtomicBool, Ordering}}, thread};
 
-use serde_json::Deserializer;
 use slog::{Drain, o, info, error, Logger, warn};
 
-use crate::{KvsEngine, Result, protocols::{Request, GetResponse, SetResponse, RemoveResponse}, thread_pool::ThreadPool, KvsError};
+use crate::{KvsEngine, Result, ScanBytesIter, protocols::{Request, GetResponse, SetResponse, RemoveResponse, TtlResponse, HistoryResponse, CompactResponse, ScanResponse, BatchResponse, GetVersionedResponse, CommitResponse, CompareAndSwapResponse, read_message, write_message, is_timeout}, thread_pool::ThreadPool, KvsError};
 
+/// Most pairs sent back for one scan request.
+const SCAN_PAGE_LEN: usize = 1024;
+/// Most bytes of keys and values sent back for one scan request, past which the
+/// page ends after the pair that crossed it.
+const SCAN_PAGE_BYTES: usize = 4 << 20;
 
 /// kvs server to receive requests from kvs-client
 #[derive(Clone)]
@@ -73,53 +77,146 @@ fn serve<E: KvsEngine>(engine: E, read_stream: TcpStream, logger: &Logger, termi
     macro_rules! send_resp {
         ($resp:expr) => {{
             let respond = $resp;
-            serde_json::to_writer(&mut writer, &respond)?;
-            writer.flush()?;
+            write_message(&mut writer, &respond)?;
         }};
     }
     
-    let reader = Deserializer::from_reader(&read_stream);
-    let stream_reader = reader.into_iter::<Request>();
-    for chunck in stream_reader {
+    let mut reader = BufReader::new(&read_stream);
+    loop {
         if terminated.load(Ordering::SeqCst) {
             warn!(logger, "connection handling got terminated!");
             break;
         }
         
-        match chunck {
-            Ok(request) => match request {
+        match read_message::<_, Request>(&mut reader) {
+            Ok(Some(request)) => match request {
                 Request::Get { key } => {
-                    info!(logger, "handling request try to {method} {key}", method="get", key=&key);
-                    send_resp!(match engine.get(key) {
+                    info!(logger, "handling request try to {method} {key}", method="get", key=String::from_utf8_lossy(&key).as_ref());
+                    send_resp!(match engine.get_bytes(key) {
                         Result::Ok(result) => GetResponse::Ok(result),
                         Result::Err(kvs_error) => GetResponse::Err(format!("{}", kvs_error)),
                     })
                 },
                 Request::Set { key, value } => {
-                    info!(logger, "handling request try to {method} {key} as {value}", method="set", key=&key, value=&value);
-                    send_resp!(match engine.set(key, value) {
+                    info!(logger, "handling request try to {method} {key} as {len} bytes", method="set", key=String::from_utf8_lossy(&key).as_ref(), len=value.len());
+                    send_resp!(match engine.set_bytes(key, value) {
                         Result::Ok(_) => SetResponse::Ok(()),
                         Result::Err(kvs_error) => SetResponse::Err(format!("{}", kvs_error)),
                     })
                 },
                 Request::Remove { key } => {
-                    info!(logger, "handling request try to {method} {key}", method="remove", key=&key);
-                    send_resp!(match engine.remove(key) {
+                    info!(logger, "handling request try to {method} {key}", method="remove", key=String::from_utf8_lossy(&key).as_ref());
+                    send_resp!(match engine.remove_bytes(key) {
                         Result::Ok(_) => RemoveResponse::Ok(()),
                         Result::Err(kvs_error) => RemoveResponse::Err(format!("{}", kvs_error)),
                     })
                 },
+                Request::SetWithTtl { key, value, ttl } => {
+                    info!(logger, "handling request try to {method} {key} as {len} bytes for {ttl}", method="set", key=String::from_utf8_lossy(&key).as_ref(), len=value.len(), ttl=format!("{:?}", ttl));
+                    send_resp!(match engine.set_with_ttl_bytes(key, value, ttl) {
+                        Result::Ok(_) => SetResponse::Ok(()),
+                        Result::Err(kvs_error) => SetResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::Ttl { key } => {
+                    info!(logger, "handling request try to {method} {key}", method="get ttl of", key=String::from_utf8_lossy(&key).as_ref());
+                    send_resp!(match engine.ttl_bytes(key) {
+                        Result::Ok(ttl) => TtlResponse::Ok(ttl),
+                        Result::Err(kvs_error) => TtlResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::GetAt { key, seq } => {
+                    info!(logger, "handling request try to {method} {key} at {seq}", method="get", key=String::from_utf8_lossy(&key).as_ref(), seq=seq);
+                    send_resp!(match engine.get_at_bytes(key, seq) {
+                        Result::Ok(result) => GetResponse::Ok(result),
+                        Result::Err(kvs_error) => GetResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::History { key } => {
+                    info!(logger, "handling request try to {method} {key}", method="get history of", key=String::from_utf8_lossy(&key).as_ref());
+                    send_resp!(match engine.history_bytes(key) {
+                        Result::Ok(versions) => HistoryResponse::Ok(versions),
+                        Result::Err(kvs_error) => HistoryResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::Batch { batch } => {
+                    info!(logger, "handling request try to {method} {len} writes", method="batch", len=batch.len());
+                    send_resp!(match engine.write_batch(batch) {
+                        Result::Ok(_) => BatchResponse::Ok(()),
+                        Result::Err(kvs_error) => BatchResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::GetVersioned { key } => {
+                    info!(logger, "handling request try to {method} {key}", method="get versioned", key=String::from_utf8_lossy(&key).as_ref());
+                    send_resp!(match engine.get_versioned(key) {
+                        Result::Ok(result) => GetVersionedResponse::Ok(result),
+                        Result::Err(kvs_error) => GetVersionedResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::Commit { reads, batch } => {
+                    info!(logger, "handling request try to {method} {reads} reads and {writes} writes", method="commit", reads=reads.len(), writes=batch.len());
+                    send_resp!(match engine.commit(reads, batch) {
+                        Result::Ok(_) => CommitResponse::Ok(()),
+                        Result::Err(KvsError::TransactionConflict) => CommitResponse::Conflict,
+                        Result::Err(kvs_error) => CommitResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::CompareAndSwap { key, expected, new } => {
+                    info!(logger, "handling request try to {method} {key}", method="compare and swap", key=String::from_utf8_lossy(&key).as_ref());
+                    send_resp!(match engine.compare_and_swap_bytes(key, expected, new) {
+                        Result::Ok(swapped) => CompareAndSwapResponse::Ok(swapped),
+                        Result::Err(kvs_error) => CompareAndSwapResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::Compact => {
+                    info!(logger, "handling request try to {method}", method="compact");
+                    send_resp!(match engine.compact() {
+                        Result::Ok(_) => CompactResponse::Ok(()),
+                        Result::Err(kvs_error) => CompactResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::Scan { start, end, limit } => {
+                    let to_lossy = |bound: &Bound<Vec<u8>>| bound.as_ref().map(|key| String::from_utf8_lossy(key).into_owned());
+                    let range = format!("({:?}, {:?})", to_lossy(&start), to_lossy(&end));
+                    info!(logger, "handling request try to {method} {range}", method="scan", range=&range);
+                    send_resp!(match engine.scan_bytes((start, end), limit).and_then(scan_page) {
+                        Result::Ok(page) => page,
+                        Result::Err(kvs_error) => ScanResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
+                Request::ScanPrefix { prefix, limit } => {
+                    info!(logger, "handling request try to {method} {prefix}", method="scan", prefix=String::from_utf8_lossy(&prefix).as_ref());
+                    send_resp!(match engine.scan_prefix_bytes(prefix, limit).and_then(scan_page) {
+                        Result::Ok(page) => page,
+                        Result::Err(kvs_error) => ScanResponse::Err(format!("{}", kvs_error)),
+                    })
+                },
             },
-            Err(err) => {
-                if let Some(kind) = err.io_error_kind() {
-                    if kind == io::ErrorKind::TimedOut {
-                        continue;
-                    }
-                    return Result::Err(KvsError::from(err));
-                }
-            }
+            // the client closed the connection.
+            Ok(None) => break,
+            Err(KvsError::Io(ref err)) if is_timeout(err) => continue,
+            Err(err) => return Result::Err(err),
         }
 
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Takes the first page of a scan, up to `SCAN_PAGE_LEN` pairs or `SCAN_PAGE_BYTES`
+/// bytes, and whether more pairs follow it.
+fn scan_page(pairs: ScanBytesIter<'_>) -> Result<ScanResponse> {
+    let mut pairs = pairs.peekable();
+    let mut page = Vec::new();
+    let mut bytes = 0;
+    while page.len() < SCAN_PAGE_LEN && bytes < SCAN_PAGE_BYTES {
+        match pairs.next() {
+            Some(pair) => {
+                let (key, value) = pair?;
+                bytes += key.len() + value.len();
+                page.push((key, value));
+            }
+            None => return Ok(ScanResponse::Ok(page, false)),
+        }
+    }
+    Ok(ScanResponse::Ok(page, pairs.peek().is_some()))
+}
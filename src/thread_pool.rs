@@ -1,7 +1,6 @@
 use crate::error::Result;
 use std::{thread, sync::{atomic::{AtomicBool, Ordering}, Arc}};
 use crossbeam::channel::{bounded, Sender, Receiver};
-use rayon;
 
 /// thread pool trait
 pub trait ThreadPool {
@@ -73,12 +72,12 @@ impl ThreadPool for SharedQueueThreadPool
             run_receiver(cur_receiver);
         }
 
-        return Ok(
+        Ok(
             Self {
                 sender,
                 terminated,
                 threads,
-            });
+            })
     }
     
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs::{client::KvsClient, KvStore, KvStoreOptions, KvsEngine, KvsError, Result, WriteBatch};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +146,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +158,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +180,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +200,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +221,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +242,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +250,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +258,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +266,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +274,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +282,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +290,55 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key2", "--expected", "value1", "--new", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not the expected one"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key2", "--expected", "value3", "--new", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key2", "--expected", "value4", "--new", "value3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -294,30 +347,33 @@ fn cli_access_server(engine: &str, addr: &str) {
     sender.send(()).unwrap();
     handle.join().unwrap();
 
-    // Reopen and check value
+    // Reopen and check value, which the memory engine does not keep
+    let persistent = engine != "memory";
+    assert_eq!(temp_dir.path().join("engine").exists(), persistent);
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
-        .stdout(contains("value3"));
+        .stdout(contains(if persistent { "value3" } else { "Key not found" }));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +391,284 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_access_server_memory_engine() {
+    cli_access_server("memory", "127.0.0.1:4009");
+}
+
+// `KvsClient` should carry keys and values that are not UTF-8, batches of them
+// and transactions, to the server and back.
+#[test]
+fn client_access_server_binary_data() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let key = vec![0u8, 0xff, 0xfe];
+    let value: Vec<u8> = (0..=255).cycle().take(1 << 20).collect();
+    client.set_bytes(key.clone(), value.clone()).unwrap();
+    assert_eq!(client.get_bytes(key.clone()).unwrap(), Some(value.clone()));
+    assert_eq!(
+        client.scan_prefix_bytes(vec![0], None).unwrap().collect::<Result<Vec<_>>>().unwrap(),
+        vec![(key.clone(), value)]
+    );
+    client.remove_bytes(key.clone()).unwrap();
+    assert_eq!(client.get_bytes(key.clone()).unwrap(), None);
+
+    let mut batch = WriteBatch::new();
+    batch.set_bytes(key.clone(), vec![0xff]);
+    batch.set_bytes(vec![0xfe], vec![0]);
+    client.write_batch(batch).unwrap();
+    assert_eq!(client.get_bytes(key.clone()).unwrap(), Some(vec![0xff]));
+    assert_eq!(client.get_bytes(vec![0xfe]).unwrap(), Some(vec![0]));
+
+
+    let mut txn = client.begin();
+    assert_eq!(txn.get_bytes(vec![0xfe]).unwrap(), Some(vec![0]));
+    txn.set_bytes(vec![0xfe], vec![1]);
+    assert_eq!(txn.get_bytes(vec![0xfe]).unwrap(), Some(vec![1]));
+    txn.commit().unwrap();
+    assert_eq!(client.get_bytes(vec![0xfe]).unwrap(), Some(vec![1]));
+
+    assert!(!client.set_if_absent_bytes(vec![0xfe], vec![2]).unwrap());
+    assert!(client.compare_and_swap_bytes(vec![0xfe], Some(vec![1]), Some(vec![2])).unwrap());
+    assert!(client.remove_if_equals_bytes(vec![0xfe], vec![2]).unwrap());
+    assert_eq!(client.get_bytes(vec![0xfe]).unwrap(), None);
+
+    client.set_with_ttl_bytes(vec![0xfe], vec![3], Duration::from_secs(600)).unwrap();
+    assert!(client.ttl_bytes(vec![0xfe]).unwrap().unwrap() > Duration::from_secs(590));
+    assert_eq!(client.ttl_bytes(key).unwrap(), None);
+    // the server serves one connection at a time.
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "600", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("600\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `KvsClient` scans should go through every page of a scan that the server splits
+// across several responses.
+#[test]
+fn client_scan_pages() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let keys = |prefix: u8| (0..2500u16).map(move |i| [&[prefix][..], &i.to_be_bytes()].concat());
+    let mut batch = WriteBatch::new();
+    for prefix in [0x01, 0x02, 0xff] {
+        keys(prefix).for_each(|key| batch.set_bytes(key.clone(), key));
+    }
+    batch.set_bytes(vec![0x00], vec![0x00]);
+    client.write_batch(batch).unwrap();
+    let pairs = |prefix: u8| keys(prefix).map(|key| (key.clone(), key)).collect::<Vec<_>>();
+
+    let scan = |scan: kvs::ScanBytesIter<'_>| scan.collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(scan(client.scan_prefix_bytes(vec![0x01], None).unwrap()), pairs(0x01));
+    assert_eq!(scan(client.scan_prefix_bytes(vec![0xff], None).unwrap()), pairs(0xff));
+    assert_eq!(scan(client.scan_prefix_bytes(vec![0x02], Some(2000)).unwrap()), pairs(0x02)[..2000]);
+    assert_eq!(scan(client.scan_bytes(vec![0x01].., None).unwrap()), [pairs(0x01), pairs(0x02), pairs(0xff)].concat());
+    assert_eq!(client.scan_bytes(..vec![0x02], Some(1500)).unwrap().count(), 1500);
+
+    // a scan left before its last page leaves the connection ready for the next request.
+    let taken = client.scan_bytes(.., None).unwrap().take(1500).collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(taken.len(), 1500);
+    assert_eq!(client.get_bytes(vec![0x00]).unwrap(), Some(vec![0x00]));
+
+    // a page also ends once its values add up past a few megabytes.
+    let value = vec![0u8; 1 << 20];
+    let mut batch = WriteBatch::new();
+    for i in 0..6u8 {
+        batch.set_bytes(vec![0x03, i], value.clone());
+    }
+    client.write_batch(batch).unwrap();
+    let large = scan(client.scan_prefix_bytes(vec![0x03], None).unwrap());
+    assert_eq!(large.len(), 6);
+    assert!(large.iter().all(|(_, scanned)| *scanned == value));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client history` and `get --at` should show the versions the server keeps
+#[test]
+fn cli_history() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--keep-versions", "3"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(
+        client.history("key1".to_owned()).unwrap(),
+        vec![(1, Some("value1".to_owned())), (2, Some("value2".to_owned())), (3, None)]
+    );
+    assert_eq!(client.get_at("key1".to_owned(), 2).unwrap(), Some("value2".to_owned()));
+    // the server serves one connection at a time.
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["history", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\tvalue1\n2\tvalue2\n3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--at", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--at", "3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_encryption_key_file() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let key_dir = TempDir::new().unwrap();
+    let key_file = key_dir.path().join("key");
+    fs::write(&key_file, format!("{}\n", "2b".repeat(32))).unwrap();
+    let bad_key_file = key_dir.path().join("bad-key");
+    fs::write(&bad_key_file, "not a key").unwrap();
+
+    let start_server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr, "--cipher", "chacha20-poly1305", "--key-file"])
+            .arg(&key_file)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut child = start_server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "secret value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let log = fs::read(temp_dir.path().join("1.log")).unwrap();
+    assert!(!log.windows(6).any(|window| window == b"secret"));
+
+    let mut child = start_server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("secret value\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--key-file"])
+        .arg(&bad_key_file)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("neither 32 bytes nor 64 hex digits"));
+}
+
+#[test]
+fn cli_store_locked() {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked { pid }) => assert_eq!(pid, Some(child.id())),
+        result => panic!("expected the store to be locked, got {:?}", result.map(|_| ())),
+    }
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("StoreLocked {{ pid: Some({}) }}", child.id())));
+
+    let reader = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().read_only(true)).unwrap();
+    assert_eq!(reader.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+
+    // the lock goes with the process
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
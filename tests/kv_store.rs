@@ -1,14 +1,109 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    Cipher, CompactionPolicy, Compression, EncryptionKey, IndexMode, KvStore, KvStoreOptions, KvStoreStats, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, MemoryKvsEngine,
+    ReadCachePolicy, RecoveryMode, Result,
+    RetentionPolicy, SledKvsEngine, SyncPolicy, ValueLogGcPolicy, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// Generates a module per engine running the tests of the suite, and those listed
+// after the engine, against it. Each test is passed the function opening the
+// engine in a directory, so that it can reopen it there.
+macro_rules! engine_tests {
+    ($tests:tt $($engine:ident: $open:expr $(=> $only:tt)?),* $(,)?) => {
+        $(
+            mod $engine {
+                use super::*;
+                engine_tests!(@tests $open, $tests);
+                $(engine_tests!(@tests $open, $only);)?
+            }
+        )*
+    };
+    (@tests $open:expr, [$($test:ident),* $(,)?]) => {
+        $(
+            #[test]
+            fn $test() -> Result<()> {
+                crate::$test($open)
+            }
+        )*
+    };
+}
+
+engine_tests! {
+    [
+        get_stored_value,
+        overwrite_value,
+        get_non_existent_value,
+        remove_non_existent_key,
+        remove_key,
+        concurrent_set,
+        concurrent_get,
+        binary_data,
+        write_batch,
+        compare_and_swap,
+        concurrent_compare_and_swap,
+        scan,
+    ]
+    // sled reclaims space on its own, and the memory engine has no files to shrink
+    kvs_engine: |path: &Path| KvStore::open(path) => [
+        compaction,
+        transaction_commit,
+        transaction_conflict,
+        concurrent_transactions,
+    ],
+    sled_engine: open_sled => [
+        transaction_unsupported,
+        ttl_unsupported,
+        history_unsupported,
+    ],
+    lsm_engine: |path: &Path| LsmKvsEngine::open(path) => [
+        compaction,
+        transaction_commit,
+        transaction_conflict,
+        concurrent_transactions,
+        ttl_unsupported,
+        history_unsupported,
+    ],
+    memory_engine: memory_engine() => [
+        transaction_commit,
+        transaction_conflict,
+        concurrent_transactions,
+        ttl_unsupported,
+        history_unsupported,
+    ],
+}
+
+// Opens the same engine whatever the directory, so that its contents survive a
+// reopen like those of the engines on disk
+fn memory_engine() -> impl Fn(&Path) -> Result<MemoryKvsEngine> {
+    let engine = MemoryKvsEngine::new();
+    move |_| Ok(engine.clone())
+}
+
+// Opens a sled engine, retrying while the lock of the one just dropped is still
+// held, as sled only releases it once its flusher thread has stopped
+fn open_sled(path: &Path) -> Result<SledKvsEngine> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match SledKvsEngine::open(path) {
+            Err(KvsError::Sled(err)) if Instant::now() < deadline && err.to_string().contains("could not acquire lock") => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            result => return result,
+        }
+    }
+}
+
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+fn get_stored_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -18,7 +113,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -26,10 +121,9 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
+fn overwrite_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -38,7 +132,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -47,165 +141,2665 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
+fn get_non_existent_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
+fn remove_non_existent_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
+fn remove_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Should store keys and values that are not UTF-8
+fn binary_data<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    let key = vec![0u8, 0xff, b'k', 0];
+    let value: Vec<u8> = (0..=255).collect();
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(vec![0xfe], Vec::new())?;
+
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(engine.get_bytes(vec![0xfe])?, Some(Vec::new()));
+    assert!(matches!(engine.get(String::from_utf8_lossy(&key).into_owned()), Ok(None)));
+    // the String wrapper refuses values that are not UTF-8
+    engine.set_bytes(b"key".to_vec(), vec![0xff])?;
+    assert!(matches!(engine.get("key".to_owned()), Err(KvsError::FromUtf8Error(_))));
+
+    let pairs: Vec<_> = engine.scan_prefix_bytes(vec![0], None)?.collect::<Result<_>>()?;
+    assert_eq!(pairs, vec![(key.clone(), value)]);
+
+    engine.remove_bytes(key.clone())?;
+    assert_eq!(engine.get_bytes(key)?, None);
+    Ok(())
+}
+
+// Binary keys and values should survive a compaction and a reopen
 #[test]
-fn compaction() -> Result<()> {
+fn binary_data_after_compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..=255u8 {
+        store.set_bytes(vec![key_id, 0xff], vec![0, key_id])?;
+    }
+    store.compact()?;
+    drop(store);
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..=255u8 {
+        assert_eq!(store.get_bytes(vec![key_id, 0xff])?, Some(vec![0, key_id]));
+    }
+    Ok(())
+}
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+// Should apply all the writes of a batch
+fn write_batch<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key2".to_owned(), "value2b".to_owned());
+    batch.set("key4".to_owned(), "value4".to_owned());
+    batch.remove("key4".to_owned());
+    // removing an absent key is not an error in a batch
+    batch.remove("key5".to_owned());
+    assert_eq!(batch.len(), 6);
+    engine.write_batch(batch)?;
 
-        drop(store);
-        // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
-    }
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2b".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, None);
 
-    panic!("No compaction detected");
+    engine.write_batch(WriteBatch::new())?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // and replay them on open, before and after a compaction
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2b".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    engine.compact()?;
+    drop(engine);
+
+    let engine = open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2b".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
 }
 
+// A batch cut short by a crash should be dropped as a whole on open
 #[test]
-fn concurrent_set() -> Result<()> {
+fn open_with_torn_batch() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
-            barrier.wait();
-        });
-    }
-    barrier.wait();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1b".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
 
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
-    }
+    // cut the batch record in the middle of its last write
+    let log = log_files(temp_dir.path()).remove(0);
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new().write(true).open(&log)?.set_len(len - 3)?;
 
-    // Open from disk again and check persistent data
-    drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
-    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A transaction should see its own writes and apply them on commit
+fn transaction_commit<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+    txn.set("key1".to_owned(), "value1b".to_owned());
+    txn.remove("key2".to_owned());
+    assert_eq!(txn.get("key1".to_owned())?, Some("value1b".to_owned()));
+    assert_eq!(txn.get("key2".to_owned())?, None);
+    // nothing is visible before the commit
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    txn.commit()?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A transaction should abort if a key it read has changed
+fn transaction_conflict<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.begin();
+    txn.get("key1".to_owned())?;
+    txn.set("key2".to_owned(), "value2".to_owned());
+    // even with the same value
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("key2".to_owned())?, None);
 
+    // absent keys are versioned too
+    let mut txn = store.begin();
+    assert_eq!(txn.get("key3".to_owned())?, None);
+    txn.set("key2".to_owned(), "value2".to_owned());
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // keys only written are not checked
+    let mut txn = store.begin();
+    txn.set("key1".to_owned(), "value1b".to_owned());
+    store.set("key1".to_owned(), "value1c".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
     Ok(())
 }
 
+// Moving a value during a compaction does not change it, so it should not abort
+// a transaction that read it
 #[test]
-fn concurrent_get() -> Result<()> {
+fn transaction_across_compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
-    }
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Disabled);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
 
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
-    }
+    let mut txn = store.begin();
+    txn.get("key1".to_owned())?;
+    txn.set("key2".to_owned(), "value2".to_owned());
+    store.compact()?;
+    txn.commit()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Flushing and compacting a value does not change it, so it should not abort a
+// transaction that read it
+#[test]
+fn transaction_across_compaction_lsm_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = engine.begin();
+    txn.get("key1".to_owned())?;
+    txn.get("key3".to_owned())?;
+    txn.set("key2".to_owned(), "value2".to_owned());
+    engine.compact()?;
+    txn.commit()?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Concurrent read-modify-write transactions should not lose updates
+fn concurrent_transactions<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let mut txn = store.begin();
+                        let counter: u64 = txn.get("counter".to_owned()).unwrap().unwrap().parse().unwrap();
+                        txn.set("counter".to_owned(), (counter + 1).to_string());
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(KvsError::TransactionConflict) => continue,
+                            Err(e) => panic!("unexpected error {}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
     for handle in handles {
         handle.join().unwrap();
     }
 
-    // Open from disk again and check persistent data
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+// Engines without versions should refuse transactions
+fn transaction_unsupported<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    let mut txn = engine.begin();
+    assert!(matches!(txn.get("key1".to_owned()), Err(KvsError::Unsupported(_))));
+    Ok(())
+}
+
+// Should only write when the current value is the expected one
+fn compare_and_swap<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    let key = || "key1".to_owned();
+    assert!(engine.set_if_absent(key(), "value1".to_owned())?);
+    assert!(!engine.set_if_absent(key(), "value2".to_owned())?);
+    assert_eq!(engine.get(key())?, Some("value1".to_owned()));
+
+    assert!(!engine.compare_and_swap(key(), Some("value2".to_owned()), Some("value3".to_owned()))?);
+    assert!(!engine.compare_and_swap(key(), None, Some("value3".to_owned()))?);
+    assert_eq!(engine.get(key())?, Some("value1".to_owned()));
+    assert!(engine.compare_and_swap(key(), Some("value1".to_owned()), Some("value3".to_owned()))?);
+    assert_eq!(engine.get(key())?, Some("value3".to_owned()));
+
+    assert!(!engine.remove_if_equals(key(), "value1".to_owned())?);
+    assert_eq!(engine.get(key())?, Some("value3".to_owned()));
+    assert!(engine.remove_if_equals(key(), "value3".to_owned())?);
+    assert_eq!(engine.get(key())?, None);
+
+    // expecting an absent key to stay absent succeeds without writing anything
+    assert!(engine.compare_and_swap(key(), None, None)?);
+    assert!(!engine.remove_if_equals(key(), "value3".to_owned())?);
+    assert_eq!(engine.get(key())?, None);
+
+    // and replay the swaps on open
+    assert!(engine.set_if_absent(key(), "value4".to_owned())?);
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    assert_eq!(engine.get(key())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Concurrent compare-and-swap loops should not lose updates, and only one of
+// several concurrent `set_if_absent` should win
+fn concurrent_compare_and_swap<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                let won = store.set_if_absent("lock".to_owned(), i.to_string()).unwrap();
+                for _ in 0..50 {
+                    loop {
+                        let counter = store.get("counter".to_owned()).unwrap().unwrap();
+                        let next = (counter.parse::<u64>().unwrap() + 1).to_string();
+                        if store.compare_and_swap("counter".to_owned(), Some(counter), Some(next)).unwrap() {
+                            break;
+                        }
+                    }
+                }
+                won
+            })
+        })
+        .collect();
+    let winners = handles.into_iter().map(|handle| handle.join().unwrap()).filter(|&won| won).count();
+
+    assert_eq!(winners, 1);
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+// Keys set with a TTL should read as absent once it has passed
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(200))?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_millis(200))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    // a plain set clears the expiry
+    store.set("key2".to_owned(), "value2b".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let ttl = store.ttl("key1".to_owned())?.expect("key1 expires");
+    assert!(ttl <= Duration::from_millis(200));
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+    assert!(matches!(store.ttl("key4".to_owned()), Err(KvsError::KeyNotFound)));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(store.ttl("key1".to_owned()), Err(KvsError::KeyNotFound)));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+    assert_eq!(
+        collect_pairs(store.scan(.., None)?)?,
+        vec![
+            ("key2".to_owned(), "value2b".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+    assert!(store.set_if_absent("key1".to_owned(), "value1b".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+    Ok(())
+}
+
+// Expiry should survive a restart, whether the log is replayed or hinted
+#[test]
+fn ttl_across_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("short".to_owned(), "value1".to_owned(), Duration::from_millis(300))?;
+    store.set_with_ttl("long".to_owned(), "value2".to_owned(), Duration::from_secs(600))?;
     drop(store);
+
     let store = KvStore::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+    assert!(store.ttl("long".to_owned())?.expect("long expires") > Duration::from_secs(590));
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!hint_files(temp_dir.path()).is_empty());
+    assert!(store.ttl("long".to_owned())?.expect("long expires") > Duration::from_secs(590));
+    drop(store);
+
+    thread::sleep(Duration::from_millis(400));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Expired keys should be swept from the index and their records compacted away
+#[test]
+fn compaction_of_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::StaleBytes(64 * 1024))
+        .expiry_sweep_interval(Duration::from_millis(50));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let value = "v".repeat(1024);
+    for key_id in 0..100 {
+        store.set_with_ttl(format!("key{}", key_id), value.clone(), Duration::from_millis(100))?;
     }
-    for handle in handles {
-        handle.join().unwrap();
+    store.set("key".to_owned(), "value".to_owned())?;
+    let size_before = logs_size(temp_dir.path());
+
+    // nothing is written after the keys expire, so only the sweeper can get the
+    // compaction going.
+    let started = Instant::now();
+    while logs_size(temp_dir.path()) >= size_before / 10 {
+        assert!(started.elapsed() < Duration::from_secs(10), "No compaction detected");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// Engines without expiry should refuse TTLs
+fn ttl_unsupported<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    let result = engine.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_secs(1));
+    assert!(matches!(result, Err(KvsError::Unsupported(_))));
+    Ok(())
+}
+
+// A snapshot should keep reading the store as it was when taken
+#[test]
+fn snapshot_consistent_view() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(
+        collect_pairs(snapshot.scan(.., None)?)?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(collect_pairs(snapshot.scan_prefix("key2".to_owned(), None)?)?.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+
+    let later = store.snapshot()?;
+    assert!(later.seq() > snapshot.seq());
+    assert_eq!(later.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Compaction should leave the logs a snapshot reads until it is dropped
+#[test]
+fn snapshot_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Disabled);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let snapshot = store.snapshot()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("new value{}", key_id))?;
+    }
+    store.compact()?;
+    store.compact()?;
+    let pinned_logs = log_files(temp_dir.path()).len();
+
+    for key_id in 0..100 {
+        assert_eq!(snapshot.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("new value{}", key_id)));
     }
+    assert_eq!(collect_pairs(snapshot.scan(.., None)?)?.len(), 100);
 
+    drop(snapshot);
+    assert!(log_files(temp_dir.path()).len() < pinned_logs);
+    store.compact()?;
+    assert_eq!(log_files(temp_dir.path()).len(), 2);
     Ok(())
 }
+
+// Should keep the last versions of every key, removals included, across
+// compactions and restarts
+#[test]
+fn history_last_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Disabled)
+        .retention(RetentionPolicy::LastVersions(3));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 1..=5 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.set("key2".to_owned(), "value1".to_owned())?;
+
+    let history = store.history("key1".to_owned())?;
+    let seqs: Vec<u64> = history.iter().map(|(seq, _)| *seq).collect();
+    assert_eq!(seqs.len(), 3);
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+    let values: Vec<_> = history.into_iter().map(|(_, value)| value).collect();
+    assert_eq!(values, vec![Some("value3".to_owned()), Some("value4".to_owned()), Some("value5".to_owned())]);
+
+    assert_eq!(store.get_at("key1".to_owned(), seqs[0])?, Some("value3".to_owned()));
+    assert_eq!(store.get_at("key1".to_owned(), seqs[1])?, Some("value4".to_owned()));
+    assert_eq!(store.get_at("key1".to_owned(), u64::MAX)?, Some("value5".to_owned()));
+    // value2 is no longer kept.
+    assert_eq!(store.get_at("key1".to_owned(), seqs[0] - 1)?, None);
+    // key2 was only set afterwards.
+    assert_eq!(store.get_at("key2".to_owned(), seqs[2])?, None);
+
+    store.remove("key1".to_owned())?;
+    let history = store.history("key1".to_owned())?;
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[1], (seqs[2], Some("value5".to_owned())));
+    assert_eq!(history[2].1, None);
+    assert_eq!(store.get_at("key1".to_owned(), history[2].0)?, None);
+    assert_eq!(store.get_at("key1".to_owned(), seqs[2])?, Some("value5".to_owned()));
+
+    store.compact()?;
+    assert_eq!(store.history("key1".to_owned())?, history);
+    assert_eq!(store.get_at("key1".to_owned(), seqs[1])?, Some("value4".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.history("key1".to_owned())?, history);
+    store.set("key1".to_owned(), "value6".to_owned())?;
+    let reopened = store.history("key1".to_owned())?;
+    assert_eq!(reopened[..2], history[1..]);
+    assert!(reopened[2].0 > history[2].0);
+    assert_eq!(reopened[2].1, Some("value6".to_owned()));
+
+    Ok(())
+}
+
+// Should only keep the versions written within the retention period
+#[test]
+fn history_newer_than() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Disabled)
+        .retention(RetentionPolicy::NewerThan(Duration::from_millis(500)));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.history("key1".to_owned())?.len(), 2);
+
+    thread::sleep(Duration::from_millis(600));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let values: Vec<_> = store.history("key1".to_owned())?.into_iter().map(|(_, value)| value).collect();
+    assert_eq!(values, vec![Some("value3".to_owned())]);
+
+    // key2 was not written since, its old versions go with the next compaction.
+    assert_eq!(store.history("key2".to_owned())?.len(), 2);
+    store.compact()?;
+    let values: Vec<_> = store.history("key2".to_owned())?.into_iter().map(|(_, value)| value).collect();
+    assert_eq!(values, vec![Some("value2".to_owned())]);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.history("key1".to_owned())?.len(), 1);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Without retention, only the current version is known, and sequence numbers
+// keep increasing across restarts
+#[test]
+fn history_latest_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let history = store.history("key1".to_owned())?;
+    assert_eq!(history.len(), 1);
+    let (seq, value) = history[0].clone();
+    assert_eq!(value, Some("value2".to_owned()));
+    assert_eq!(store.get_at("key1".to_owned(), seq)?, Some("value2".to_owned()));
+    assert_eq!(store.get_at("key1".to_owned(), seq - 1)?, None);
+
+    // the last writes are dropped by the compaction, but not their numbers.
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key1".to_owned())?, vec![(seq, Some("value2".to_owned()))]);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.history("key2".to_owned())?[0].0, seq + 3);
+    assert!(store.history("key3".to_owned())?.is_empty());
+
+    Ok(())
+}
+
+// Past versions should stay readable while a compaction moves them around
+#[test]
+fn history_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Disabled)
+        .retention(RetentionPolicy::LastVersions(4));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..4 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", i))?;
+        }
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), "value4".to_owned())?;
+            }
+            Ok(())
+        })
+    };
+    store.compact()?;
+    writer.join().unwrap()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let values: Vec<_> = store
+                .history(format!("key{}", key_id))?
+                .into_iter()
+                .map(|(_, value)| value.unwrap())
+                .collect();
+            assert_eq!(values, vec!["value1", "value2", "value3", "value4"]);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+// Engines without history should refuse past versions
+fn history_unsupported<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(engine.get_at("key1".to_owned(), 1), Err(KvsError::Unsupported(_))));
+    assert!(matches!(engine.history("key1".to_owned()), Err(KvsError::Unsupported(_))));
+    Ok(())
+}
+
+fn bounded_options() -> KvStoreOptions {
+    KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Disabled)
+        .index_mode(IndexMode::Bounded { cache_entries: 16, max_memory_keys: 100 })
+}
+
+fn check_bounded_contents(store: &KvStore) -> Result<()> {
+    let mut expected = Vec::new();
+    for key_id in 0..1000 {
+        let key = format!("key{:04}", key_id);
+        let value = match key_id {
+            _ if key_id % 3 == 0 => None,
+            _ if key_id % 2 == 0 => Some(format!("new value{}", key_id)),
+            _ => Some(format!("value{}", key_id)),
+        };
+        assert_eq!(store.get(key.clone())?, value);
+        if let Some(value) = value {
+            expected.push((key, value));
+        }
+    }
+    assert_eq!(collect_pairs(store.scan(.., None)?)?, expected);
+    let range = collect_pairs(store.scan("key0100".to_owned().."key0200".to_owned(), None)?)?;
+    assert_eq!(range, expected.iter().filter(|(key, _)| key.starts_with("key01")).cloned().collect::<Vec<_>>());
+    assert_eq!(collect_pairs(store.scan_prefix("key09".to_owned(), Some(5))?)?.len(), 5);
+    Ok(())
+}
+
+// A bounded index should find every key, whether in memory or in the sorted
+// index of a compaction, across restarts in either index mode
+#[test]
+fn bounded_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), bounded_options())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..1000).filter(|key_id| key_id % 2 == 0) {
+        store.set(format!("key{:04}", key_id), format!("new value{}", key_id))?;
+    }
+    for key_id in (0..1000).filter(|key_id| key_id % 3 == 0) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+    assert!(matches!(store.remove("key0000".to_owned()), Err(KvsError::KeyNotFound)));
+    check_bounded_contents(&store)?;
+
+    store.compact()?;
+    let index_files = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().is_ok_and(|entry| entry.path().extension() == Some("index".as_ref())))
+        .count();
+    assert_eq!(index_files, 1);
+    check_bounded_contents(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), bounded_options())?;
+    check_bounded_contents(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check_bounded_contents(&store)?;
+    // a full index has no use for a sorted index, and does not write one
+    store.compact()?;
+    assert!(files_with_extension(temp_dir.path(), "index").is_empty());
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), bounded_options())?;
+    check_bounded_contents(&store)?;
+    Ok(())
+}
+
+// Scans of a bounded index should not miss keys while compactions replace its
+// sorted index
+#[test]
+fn bounded_index_scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), bounded_options())?;
+    for key_id in 0..500 {
+        store.set(format!("key{:04}", key_id), "value".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 0..5 {
+                for key_id in (0..500).step_by(7) {
+                    store.set(format!("key{:04}", key_id), format!("value{}", round))?;
+                }
+                store.compact()?;
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        assert_eq!(store.scan(.., None)?.count(), 500);
+    }
+    writer.join().expect("writer thread panicked")?;
+    assert_eq!(store.get("key0007".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Keys removed from a bounded index while its first compaction copies them
+// should not come back with its sorted index
+#[test]
+fn bounded_index_remove_during_first_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let barrier = Arc::new(Barrier::new(2));
+    let store = KvStore::open_with_options(temp_dir.path(), bounded_options().compaction_barrier(barrier.clone()))?;
+    for key_id in 0..50 {
+        store.set(format!("key{:04}", key_id), "value".to_owned())?;
+    }
+
+    let compaction = thread::spawn({
+        let store = store.clone();
+        move || store.compact()
+    });
+    // the compaction is held once it has copied the first key
+    barrier.wait();
+    for key_id in (0..50).step_by(2) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+    barrier.wait();
+    compaction.join().unwrap()?;
+
+    for key_id in 0..50 {
+        let expected = (key_id % 2 == 1).then(|| "value".to_owned());
+        assert_eq!(store.get(format!("key{:04}", key_id))?, expected);
+    }
+    assert_eq!(store.scan(.., None)?.count(), 25);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), bounded_options())?;
+    assert_eq!(store.get("key0000".to_owned())?, None);
+    assert_eq!(store.scan(.., None)?.count(), 25);
+    Ok(())
+}
+
+// Snapshots and past versions should work the same with a bounded index
+#[test]
+fn bounded_index_snapshot_and_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = bounded_options().retention(RetentionPolicy::LastVersions(2));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..300 {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+
+    let snapshot = store.snapshot()?;
+    for key_id in 0..300 {
+        store.set(format!("key{:04}", key_id), format!("new value{}", key_id))?;
+    }
+    store.remove("key0001".to_owned())?;
+    store.compact()?;
+
+    for key_id in 0..300 {
+        assert_eq!(snapshot.get(format!("key{:04}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(collect_pairs(snapshot.scan(.., None)?)?.len(), 300);
+    assert_eq!(store.get("key0001".to_owned())?, None);
+    assert_eq!(collect_pairs(store.scan(.., None)?)?.len(), 299);
+    drop(snapshot);
+
+    let history = store.history("key0002".to_owned())?;
+    assert_eq!(
+        history.iter().map(|(_, value)| value.clone()).collect::<Vec<_>>(),
+        vec![Some("value2".to_owned()), Some("new value2".to_owned())]
+    );
+    assert_eq!(store.get_at("key0002".to_owned(), history[0].0)?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.history("key0002".to_owned())?, history);
+    assert_eq!(store.history("key0001".to_owned())?.last().map(|(_, value)| value.clone()), Some(None));
+    assert_eq!(store.get("key0299".to_owned())?, Some("new value299".to_owned()));
+    Ok(())
+}
+
+fn small_lsm_options() -> LsmOptions {
+    LsmOptions::new()
+        .memtable_size(16 * 1024)
+        .table_size(8 * 1024)
+        .level1_size(64 * 1024)
+        .level_size_multiplier(4)
+}
+
+fn files_with_extension(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("unable to list the store directory")
+        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
+        .filter(|path| path.extension() == Some(extension.as_ref()))
+        .collect();
+    files.sort();
+    files
+}
+
+fn check_lsm_contents(engine: &LsmKvsEngine, round: usize) -> Result<()> {
+    let mut expected = Vec::new();
+    for key_id in 0..2000 {
+        let key = format!("key{:04}", key_id);
+        let value = (key_id % 3 != 0).then(|| format!("value{}-{}", round, key_id));
+        assert_eq!(engine.get(key.clone())?, value);
+        if let Some(value) = value {
+            expected.push((key, value));
+        }
+    }
+    assert_eq!(collect_pairs(engine.scan(.., None)?)?, expected);
+    let range = collect_pairs(engine.scan("key0500".to_owned()..="key0599".to_owned(), None)?)?;
+    assert_eq!(range, expected.iter().filter(|(key, _)| key.starts_with("key05")).cloned().collect::<Vec<_>>());
+    assert_eq!(collect_pairs(engine.scan_prefix("key19".to_owned(), None)?)?.len(), 67);
+    Ok(())
+}
+
+// The LSM engine should flush its memtable to tables, merge them down the
+// levels, and find every key across the memtables and levels, and a reopen
+#[test]
+fn lsm_engine_levels() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), small_lsm_options())?;
+    for round in 0..5 {
+        for key_id in 0..2000 {
+            engine.set(format!("key{:04}", key_id), format!("value{}-{}", round, key_id))?;
+        }
+    }
+    for key_id in (0..2000).step_by(3) {
+        engine.remove(format!("key{:04}", key_id))?;
+    }
+    assert!(matches!(engine.remove("key0000".to_owned()), Err(KvsError::KeyNotFound)));
+    check_lsm_contents(&engine, 4)?;
+    assert!(files_with_extension(temp_dir.path(), "sst").len() > 1);
+    drop(engine);
+
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), small_lsm_options())?;
+    check_lsm_contents(&engine, 4)?;
+    let tables_size = |dir: &Path| -> u64 {
+        files_with_extension(dir, "sst")
+            .iter()
+            .map(|path| fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0))
+            .sum()
+    };
+    let size_before = tables_size(temp_dir.path());
+    engine.compact()?;
+    assert!(tables_size(temp_dir.path()) < size_before);
+    check_lsm_contents(&engine, 4)?;
+    drop(engine);
+
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    check_lsm_contents(&engine, 4)?;
+    Ok(())
+}
+
+// Reads should not miss keys while the memtable is flushed and tables compacted
+// in the background
+#[test]
+fn lsm_engine_reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), small_lsm_options())?;
+    for key_id in 0..500 {
+        engine.set(format!("key{:04}", key_id), "value".to_owned())?;
+    }
+
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 0..20 {
+                for key_id in 0..500 {
+                    engine.set(format!("key{:04}", key_id), format!("value{}", round))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        assert_eq!(engine.scan(.., None)?.count(), 500);
+        assert!(engine.get("key0250".to_owned())?.is_some());
+    }
+    writer.join().expect("writer thread panicked")?;
+    assert_eq!(engine.get("key0499".to_owned())?, Some("value19".to_owned()));
+    Ok(())
+}
+
+// A write cut short by a crash should be dropped as a whole when the write-ahead
+// log is replayed
+#[test]
+fn lsm_engine_torn_wal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1b".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    engine.write_batch(batch)?;
+    drop(engine);
+
+    let wal = files_with_extension(temp_dir.path(), "wal").remove(0);
+    let len = fs::metadata(&wal)?.len();
+    OpenOptions::new().write(true).open(&wal)?.set_len(len - 3)?;
+
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A record of the write-ahead log whose length is damaged should fail the
+// replay rather than be taken for a torn tail
+#[test]
+fn lsm_engine_damaged_wal_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    let wal = files_with_extension(temp_dir.path(), "wal").remove(0);
+    let mut bytes = fs::read(&wal)?;
+    // the high byte of the length of the first record
+    bytes[7] ^= 0x01;
+    let len = bytes.len() as u64;
+    fs::write(&wal, bytes)?;
+
+    match LsmKvsEngine::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, 0),
+        result => panic!("expected a corruption, got {:?}", result.map(|_| ())),
+    }
+    assert_eq!(fs::metadata(&wal)?.len(), len);
+    Ok(())
+}
+
+// A bounded memory engine should evict the least recently used keys once its
+// keys and values outgrow the limit
+#[test]
+fn memory_engine_eviction() -> Result<()> {
+    // every key and value takes 10 bytes
+    let engine = MemoryKvsEngine::with_max_bytes(30);
+    for i in 0..3 {
+        engine.set(format!("key{}", i), format!("val{}", i))?;
+    }
+    // reading key0 makes key1 the least recently used
+    assert_eq!(engine.get("key0".to_owned())?, Some("val0".to_owned()));
+    engine.set("key3".to_owned(), "val3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    for key in ["key0", "key2", "key3"] {
+        assert!(engine.get(key.to_owned())?.is_some(), "{} was evicted", key);
+    }
+
+    // removed and overwritten keys free their bytes
+    engine.remove("key0".to_owned())?;
+    engine.set("key2".to_owned(), "v2".to_owned())?;
+    engine.set("key4".to_owned(), "val4".to_owned())?;
+    assert_eq!(collect_pairs(engine.scan(.., None)?)?.len(), 3);
+
+    // a value larger than the limit is kept on its own
+    engine.set("big".to_owned(), "x".repeat(100))?;
+    assert_eq!(collect_pairs(engine.scan(.., None)?)?, vec![("big".to_owned(), "x".repeat(100))]);
+    Ok(())
+}
+
+fn collect_pairs(pairs: kvs::ScanIter<'_>) -> Result<Vec<(String, String)>> {
+    pairs.collect()
+}
+
+// Should list key/value pairs in key order
+fn scan<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    for key in ["b", "a/2", "c", "a/1", "a", "a/3"] {
+        engine.set(key.to_owned(), format!("value of {}", key))?;
+    }
+    engine.remove("a/3".to_owned())?;
+    let pair = |key: &str| (key.to_owned(), format!("value of {}", key));
+
+    assert_eq!(
+        collect_pairs(engine.scan("a/1".to_owned().."b".to_owned(), None)?)?,
+        vec![pair("a/1"), pair("a/2")]
+    );
+    assert_eq!(
+        collect_pairs(engine.scan("a/2".to_owned()..="b".to_owned(), None)?)?,
+        vec![pair("a/2"), pair("b")]
+    );
+    assert_eq!(
+        collect_pairs(engine.scan(.., Some(3))?)?,
+        vec![pair("a"), pair("a/1"), pair("a/2")]
+    );
+    assert_eq!(
+        collect_pairs(engine.scan("d".to_owned().., None)?)?,
+        vec![]
+    );
+
+    assert_eq!(
+        collect_pairs(engine.scan_prefix("a/".to_owned(), None)?)?,
+        vec![pair("a/1"), pair("a/2")]
+    );
+    assert_eq!(
+        collect_pairs(engine.scan_prefix("a".to_owned(), Some(2))?)?,
+        vec![pair("a"), pair("a/1")]
+    );
+    assert_eq!(
+        collect_pairs(engine.scan_prefix("".to_owned(), None)?)?.len(),
+        5
+    );
+    Ok(())
+}
+
+// Test data correctness after compaction.
+fn compaction<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Writes should not wait for a compaction running in the background
+#[test]
+fn compaction_does_not_block_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let barrier = Arc::new(Barrier::new(2));
+    let options = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Disabled)
+        .compaction_barrier(barrier.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.set("key0".to_owned(), "stale".to_owned())?;
+
+    let compaction = thread::spawn({
+        let store = store.clone();
+        move || store.compact()
+    });
+    // the compaction is held halfway through copying the logs
+    barrier.wait();
+    let (done, writes) = mpsc::channel();
+    let writer = thread::spawn({
+        let store = store.clone();
+        move || {
+            let result = (0..10).try_for_each(|key_id| store.set(format!("key{}", key_id), "new".to_owned()));
+            let _ = done.send(());
+            result
+        }
+    });
+    let written = writes.recv_timeout(Duration::from_secs(10));
+    barrier.wait();
+    assert!(written.is_ok(), "writes waited for the compaction");
+    writer.join().unwrap()?;
+    compaction.join().unwrap()?;
+
+    // the writes made during the compaction are not undone by it
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("new".to_owned()));
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("new".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn logs_size(dir: &Path) -> u64 {
+    log_files(dir)
+        .iter()
+        .map(|path| fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0))
+        .sum()
+}
+
+// Should only compact on demand when automatic compaction is disabled
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Disabled);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for iter in 0..100 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let size_before = logs_size(temp_dir.path());
+
+    store.compact()?;
+    assert!(logs_size(temp_dir.path()) < size_before / 10);
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Removes leave stale data behind too and should trigger a compaction
+#[test]
+fn compaction_on_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::StaleBytes(64 * 1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let value = "v".repeat(1024);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    let size_before = logs_size(temp_dir.path());
+    // no stale data until the removes
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let started = Instant::now();
+    while logs_size(temp_dir.path()) >= size_before {
+        assert!(started.elapsed() < Duration::from_secs(10), "No compaction detected");
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+// Should compact once stale data outgrows live data by the given ratio
+#[test]
+fn compaction_on_stale_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::StaleRatio(2.0));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    // stale data is twice the live data after the second round of overwrites,
+    // which is not yet past the ratio
+    let mut size = logs_size(temp_dir.path());
+    for iter in 1..=2 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        let new_size = logs_size(temp_dir.path());
+        assert!(new_size > size, "compacted before the ratio was reached");
+        size = new_size;
+    }
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "3".to_owned())?;
+    }
+    let size = logs_size(temp_dir.path());
+
+    let started = Instant::now();
+    while logs_size(temp_dir.path()) >= size {
+        assert!(started.elapsed() < Duration::from_secs(10), "No compaction detected");
+        thread::sleep(Duration::from_millis(10));
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("3".to_owned()));
+    }
+    Ok(())
+}
+
+// Should compact once there are too many log files
+#[test]
+fn compaction_on_max_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::MaxGenerations(4));
+
+    // every open starts a new log
+    for iter in 0..10 {
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set(format!("key{}", iter), format!("{}", iter))?;
+    }
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let started = Instant::now();
+    while log_files(temp_dir.path()).len() > 4 {
+        assert!(started.elapsed() < Duration::from_secs(10), "No compaction detected");
+        thread::sleep(Duration::from_millis(10));
+    }
+    for iter in 0..10 {
+        assert_eq!(store.get(format!("key{}", iter))?, Some(format!("{}", iter)));
+    }
+    Ok(())
+}
+
+fn concurrent_set<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        handles.push(thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        }));
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data, once no thread holds the
+    // store open anymore
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(store);
+    let store = open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Concurrent writers share group commits, with a sync per group
+#[test]
+fn concurrent_set_every_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let start = Arc::new(Barrier::new(16));
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        let start = start.clone();
+        handles.push(thread::spawn(move || {
+            start.wait();
+            for i in 0..100 {
+                let key_id = thread_id * 100 + i;
+                store
+                    .set(format!("key{}", key_id), format!("value{}", key_id))
+                    .unwrap();
+            }
+            // removes of absent keys fail without failing the rest of the group
+            assert!(store.remove(format!("absent{}", thread_id)).is_err());
+            store.remove(format!("key{}", thread_id * 100)).unwrap();
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // 1616 writes were synced, in far fewer syncs than one each.
+    let stats = store.stats();
+    assert_eq!(stats.unsynced_writes, 0);
+    assert!(stats.syncs < 1600, "{} syncs for 1616 writes", stats.syncs);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..1600 {
+        let expected = if key_id % 100 == 0 { None } else { Some(format!("value{}", key_id)) };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+fn concurrent_get<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+fn check_read_cache(policy: ReadCachePolicy) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().read_cache(policy);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let read_all = |store: &KvStore| -> Result<()> {
+        for i in 0..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    };
+    read_all(&store)?;
+    read_all(&store)?;
+    assert_eq!(
+        store.stats(),
+        KvStoreStats {
+            cache_hits: 10,
+            cache_misses: 10,
+            cache_entries: 10,
+            cache_bytes: 100,
+            uncompressed_bytes: 60,
+            compressed_bytes: 60,
+            syncs: 0,
+            unsynced_writes: 10,
+        }
+    );
+
+    // writes invalidate the keys they touch
+    store.set("key0".to_owned(), "new value".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_millis(100))?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // a compaction moves the records, the cached values still hit
+    store.compact()?;
+    let hits = store.stats().cache_hits;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.stats().cache_hits, hits + 1);
+    drop(store);
+
+    // the cache is kept within its capacity
+    let options = options.read_cache(match policy {
+        ReadCachePolicy::Lru(_) => ReadCachePolicy::Lru(50),
+        _ => ReadCachePolicy::Clock(50),
+    });
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 3..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    let stats = store.stats();
+    assert_eq!(stats.cache_misses, 7);
+    assert_eq!((stats.cache_entries, stats.cache_bytes), (5, 50));
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.stats().cache_hits, 1);
+    Ok(())
+}
+
+// Cached values should be served until a write touches their key
+#[test]
+fn read_cache_lru() -> Result<()> {
+    check_read_cache(ReadCachePolicy::Lru(1024 * 1024))
+}
+
+// Cached values should be served until a write touches their key
+#[test]
+fn read_cache_clock() -> Result<()> {
+    check_read_cache(ReadCachePolicy::Clock(1024 * 1024))
+}
+
+// Readers should never get a value older than one they got before, while a
+// writer overwrites the values they cache
+#[test]
+fn read_cache_concurrent_writes() -> Result<()> {
+    for policy in [ReadCachePolicy::Lru(1024), ReadCachePolicy::Clock(1024)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().read_cache(policy))?;
+        store.set("counter".to_owned(), "0".to_owned())?;
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while last < 500 {
+                        let counter: u64 = store.get("counter".to_owned()).unwrap().unwrap().parse().unwrap();
+                        assert!(counter >= last, "read {} after {}", counter, last);
+                        last = counter;
+                    }
+                })
+            })
+            .collect();
+        for i in 1..=500 {
+            store.set("counter".to_owned(), i.to_string())?;
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(store.get("counter".to_owned())?, Some("500".to_owned()));
+    }
+    Ok(())
+}
+
+fn json_document(i: usize) -> String {
+    let items: Vec<String> = (0..20)
+        .map(|j| format!(r#"{{"id": {}, "name": "item {}", "tags": ["alpha", "beta"], "active": true}}"#, j, i))
+        .collect();
+    format!(r#"{{"document": {}, "items": [{}]}}"#, i, items.join(", "))
+}
+
+fn logs_len(dir: &Path) -> u64 {
+    log_files(dir)
+        .iter()
+        .map(|log| fs::metadata(log).expect("unable to read log metadata").len())
+        .sum()
+}
+
+fn check_compression(compression: Compression) -> Result<()> {
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compression(compression);
+    let plain = KvStore::open(plain_dir.path())?;
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for engine in [&plain, &store] {
+        for i in 0..100 {
+            engine.set(format!("key{}", i), json_document(i))?;
+        }
+        engine.set_with_ttl("expiring".to_owned(), json_document(100), Duration::from_secs(60))?;
+        let mut batch = WriteBatch::new();
+        batch.set("key0".to_owned(), json_document(1000));
+        batch.set("short".to_owned(), "value".to_owned());
+        batch.remove("key1".to_owned());
+        engine.write_batch(batch)?;
+        assert!(engine.compare_and_swap("key2".to_owned(), Some(json_document(2)), Some(json_document(2000)))?);
+    }
+
+    let stats = store.stats();
+    assert!(stats.compression_ratio() > 4.0, "ratio of {}", stats.compression_ratio());
+    assert_eq!(stats.uncompressed_bytes, plain.stats().uncompressed_bytes);
+    assert_eq!(plain.stats().compression_ratio(), 1.0);
+    assert!(logs_len(temp_dir.path()) * 4 < logs_len(plain_dir.path()));
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some(json_document(1000)));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some(json_document(2000)));
+        assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("expiring".to_owned())?, Some(json_document(100)));
+        for i in 3..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(json_document(i)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    // the records are replayed, then copied by a compaction, as they are
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    assert_eq!(store.stats().compression_ratio(), 1.0);
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// Values should be compressed with LZ4, and read back as they were
+#[test]
+fn compression_lz4() -> Result<()> {
+    check_compression(Compression::Lz4)
+}
+
+// Values should be compressed with zstd, and read back as they were
+#[test]
+fn compression_zstd() -> Result<()> {
+    check_compression(Compression::Zstd(3))
+}
+
+// A store should read the values of every codec, whatever the one it is
+// opened with, and compactions should rewrite them with it if asked to
+#[test]
+fn compression_mixed_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let codecs = [Compression::None, Compression::Lz4, Compression::Zstd(19)];
+    for (round, compression) in codecs.into_iter().enumerate() {
+        let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().compression(compression))?;
+        for i in 0..30 {
+            store.set(format!("key{}-{}", round, i), json_document(i))?;
+        }
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        for round in 0..codecs.len() {
+            for i in 0..30 {
+                assert_eq!(store.get(format!("key{}-{}", round, i))?, Some(json_document(i)));
+            }
+        }
+        Ok(())
+    };
+
+    for compression in codecs {
+        let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().compression(compression))?;
+        check(&store)?;
+    }
+
+    // decompressed, everything takes more room
+    let options = KvStoreOptions::new().recompress(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let compressed_len = logs_len(temp_dir.path());
+    store.compact()?;
+    check(&store)?;
+    let plain_len = logs_len(temp_dir.path());
+    assert!(plain_len > compressed_len * 2);
+    let stats = store.stats();
+    assert_eq!(stats.uncompressed_bytes, stats.compressed_bytes);
+    assert!(stats.uncompressed_bytes > 0);
+    drop(store);
+
+    // then recompressed with zstd
+    let options = KvStoreOptions::new().compression(Compression::Zstd(3)).recompress(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.compact()?;
+    check(&store)?;
+    assert!(logs_len(temp_dir.path()) * 4 < plain_len);
+    assert!(store.stats().compression_ratio() > 4.0);
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+fn logs_contain(dir: &Path, needle: &[u8]) -> bool {
+    log_files(dir).iter().any(|log| {
+        let bytes = fs::read(log).expect("unable to read log");
+        bytes.windows(needle.len()).any(|window| window == needle)
+    })
+}
+
+fn check_encryption(cipher: Cipher) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::new(cipher, [7; 32]);
+    let options = KvStoreOptions::new().encryption_key(key.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "secret value1".to_owned())?;
+    store.set_with_ttl("key2".to_owned(), "secret value2".to_owned(), Duration::from_secs(60))?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "secret value3".to_owned());
+    batch.remove("key1".to_owned());
+    store.write_batch(batch)?;
+    assert!(store.compare_and_swap("key3".to_owned(), Some("secret value3".to_owned()), Some("secret value4".to_owned()))?);
+    assert!(!logs_contain(temp_dir.path(), b"secret"));
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("secret value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("secret value4".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options.clone())?)?;
+
+    // without the key, or with another one, the logs cannot be replayed
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnknownEncryptionKey(_)) => {}
+        other => panic!("expected an unknown key error, got {:?}", other.err()),
+    }
+    let other_key = KvStoreOptions::new().encryption_key(EncryptionKey::new(cipher, [8; 32]));
+    match KvStore::open_with_options(temp_dir.path(), other_key.clone()) {
+        Err(KvsError::UnknownEncryptionKey(_)) => {}
+        other => panic!("expected an unknown key error, got {:?}", other.err()),
+    }
+
+    // the values are compressed before they are encrypted
+    let options = options.compression(Compression::Lz4);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key5".to_owned(), "secret ".repeat(100))?;
+    store.compact()?;
+    check(&store)?;
+    assert_eq!(store.get("key5".to_owned())?, Some("secret ".repeat(100)));
+    assert!(store.stats().compression_ratio() > 4.0);
+    drop(store);
+    assert!(!logs_contain(temp_dir.path(), b"secret"));
+
+    // the compacted log is opened from its hint file, and its values cannot be
+    // read without the key
+    let store = KvStore::open_with_options(temp_dir.path(), other_key)?;
+    match store.get("key2".to_owned()) {
+        Err(KvsError::UnknownEncryptionKey(_)) => {}
+        other => panic!("expected an unknown key error, got {:?}", other),
+    }
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+// Values should be encrypted with AES-GCM, and read back with the key only
+#[test]
+fn encryption_aes_gcm() -> Result<()> {
+    check_encryption(Cipher::Aes256Gcm)
+}
+
+// Values should be encrypted with ChaCha20-Poly1305, and read back with the key only
+#[test]
+fn encryption_chacha20_poly1305() -> Result<()> {
+    check_encryption(Cipher::ChaCha20Poly1305)
+}
+
+// A value tampered with should fail to authenticate, even with a valid checksum
+#[test]
+fn encryption_tampered_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::new(Cipher::Aes256Gcm, [7; 32]));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = log_files(temp_dir.path()).remove(0);
+    let mut bytes = fs::read(&log)?;
+    // the last byte of the tag of the value, then the checksum of the record
+    *bytes.last_mut().unwrap() ^= 0xff;
+    let crc = crc32fast::hash(&bytes[13 + 4..]);
+    bytes[13..13 + 4].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&log, bytes)?;
+
+    match KvStore::open_with_options(temp_dir.path(), options) {
+        Err(KvsError::AuthenticationFailed { offset, .. }) => assert_eq!(offset, 13),
+        other => panic!("expected an authentication error, got {:?}", other.err()),
+    }
+    Ok(())
+}
+
+// A compaction should encrypt the values read with old keys, or in plaintext,
+// with the current key
+#[test]
+fn encryption_key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = EncryptionKey::new(Cipher::Aes256Gcm, [1; 32]);
+    let new_key = EncryptionKey::new(Cipher::ChaCha20Poly1305, [2; 32]);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value0".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().encryption_key(old_key.clone()))?;
+    for i in 1..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("plain".to_owned())?, Some("value0".to_owned()));
+        for i in 1..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    };
+    let options = KvStoreOptions::new().encryption_key(new_key.clone()).old_key(old_key.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    assert!(!logs_contain(temp_dir.path(), b"value0"));
+
+    // the old key is no longer needed
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().encryption_key(new_key.clone()))?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().encryption_key(old_key))?;
+    match store.get("key1".to_owned()) {
+        Err(KvsError::UnknownEncryptionKey(_)) => {}
+        other => panic!("expected an unknown key error, got {:?}", other),
+    }
+    drop(store);
+
+    // and without a current key, a compaction decrypts everything
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().old_key(new_key))?;
+    store.compact()?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+fn large_value(i: usize, version: usize) -> String {
+    format!("value {} version {};", i, version).repeat(200)
+}
+
+fn value_logs_len(dir: &Path) -> u64 {
+    value_log_files(dir)
+        .iter()
+        .map(|log| fs::metadata(log).expect("unable to read value log metadata").len())
+        .sum()
+}
+
+fn separated_options() -> KvStoreOptions {
+    KvStoreOptions::new()
+        .value_separation(1024)
+        .value_log_size(64 * 1024)
+        .compaction_policy(CompactionPolicy::Disabled)
+        .value_log_gc(ValueLogGcPolicy::Disabled)
+}
+
+// Large values should be stored in value logs, which compactions do not copy,
+// and read back across restarts and compactions
+#[test]
+fn value_separation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), separated_options())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), large_value(i, 0))?;
+    }
+    store.set("short".to_owned(), "value".to_owned())?;
+    store.set_with_ttl("expiring".to_owned(), large_value(50, 0), Duration::from_secs(60))?;
+    let mut batch = WriteBatch::new();
+    batch.set("key0".to_owned(), large_value(0, 1));
+    batch.set("short2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    store.write_batch(batch)?;
+    assert!(store.compare_and_swap("key2".to_owned(), Some(large_value(2, 0)), Some(large_value(2, 1)))?);
+    assert!(!store.compare_and_swap("key3".to_owned(), Some(large_value(3, 1)), None)?);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some(large_value(0, 1)));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some(large_value(2, 1)));
+        for i in 3..50 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(large_value(i, 0)));
+        }
+        assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("short2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("expiring".to_owned())?, Some(large_value(50, 0)));
+        assert!(store.ttl("expiring".to_owned())?.is_some());
+        assert_eq!(collect_pairs(store.scan_prefix("key4".to_owned(), None)?)?.len(), 11);
+        Ok(())
+    };
+    check(&store)?;
+    // the values fill several value logs, and the logs only hold pointers
+    assert!(value_log_files(temp_dir.path()).len() > 2);
+    assert!(logs_len(temp_dir.path()) < 16 * 1024);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), separated_options())?;
+    check(&store)?;
+    let value_logs = value_logs_len(temp_dir.path());
+    store.compact()?;
+    check(&store)?;
+    assert!(logs_len(temp_dir.path()) < 16 * 1024);
+    assert_eq!(value_logs_len(temp_dir.path()), value_logs);
+    drop(store);
+
+    // the values in value logs are read without separating new ones
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.set("key4".to_owned(), large_value(4, 1))?;
+    assert_eq!(store.get("key4".to_owned())?, Some(large_value(4, 1)));
+    Ok(())
+}
+
+// The garbage collection of the value logs should reclaim the values
+// overwritten and removed, and leave the others readable across restarts
+#[test]
+fn value_log_gc() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), separated_options())?;
+    for version in 0..5 {
+        for i in 0..20 {
+            store.set(format!("key{}", i), large_value(i, version))?;
+        }
+    }
+    for i in 0..5 {
+        store.remove(format!("key{}", i))?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..5 {
+            assert_eq!(store.get(format!("key{}", i))?, None);
+        }
+        for i in 5..20 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(large_value(i, 4)));
+        }
+        Ok(())
+    };
+    let before = value_logs_len(temp_dir.path());
+    store.collect_value_logs()?;
+    let after = value_logs_len(temp_dir.path());
+    // 15 of the 100 values written are left
+    assert!(after * 4 < before, "{} bytes of value logs left of {}", after, before);
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), separated_options())?;
+    check(&store)?;
+    store.collect_value_logs()?;
+    check(&store)?;
+    assert!(value_logs_len(temp_dir.path()) <= after);
+    store.compact()?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), separated_options())?)
+}
+
+// Keys overwritten while the value logs are collected should read their last
+// value, never the copy of an older one
+#[test]
+fn value_log_gc_concurrent_overwrites() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = separated_options()
+        .value_log_size(32 * 1024)
+        .value_log_gc(ValueLogGcPolicy::GarbageRatio { ratio: 0.2, interval: Duration::from_millis(5) });
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), large_value(i, 0))?;
+    }
+
+    let writers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for version in 1..=40 {
+                    for i in thread_id * 5..thread_id * 5 + 5 {
+                        store.set(format!("key{}", i), large_value(i, version))?;
+                        assert_eq!(store.get(format!("key{}", i))?, Some(large_value(i, version)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    let collector = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..20 {
+                store.collect_value_logs()?;
+                for i in 0..20 {
+                    let value = store.get(format!("key{}", i))?.expect("key not found");
+                    assert!(value.starts_with(&format!("value {} version", i)));
+                }
+            }
+            Ok(())
+        })
+    };
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    collector.join().unwrap()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..20 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(large_value(i, 40)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.collect_value_logs()?;
+    check(&store)?;
+    // only the last values are left
+    assert!(value_logs_len(temp_dir.path()) < 2 * 20 * large_value(0, 40).len() as u64);
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+// Snapshots and past versions should read the values the garbage collection of
+// the value logs moved, or left for them
+#[test]
+fn value_log_gc_snapshot_and_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = separated_options().retention(RetentionPolicy::LastVersions(2));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), large_value(i, 0))?;
+    }
+    let snapshot = store.snapshot()?;
+    for version in 1..3 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), large_value(i, version))?;
+        }
+    }
+
+    let check_history = |store: &KvStore| -> Result<()> {
+        for i in 0..10 {
+            let history = store.history(format!("key{}", i))?;
+            let values: Vec<_> = history.iter().map(|(_, value)| value.clone()).collect();
+            assert_eq!(values, vec![Some(large_value(i, 1)), Some(large_value(i, 2))]);
+            assert_eq!(store.get_at(format!("key{}", i), history[0].0)?, Some(large_value(i, 1)));
+        }
+        Ok(())
+    };
+    store.collect_value_logs()?;
+    check_history(&store)?;
+    for i in 0..10 {
+        assert_eq!(snapshot.get(format!("key{}", i))?, Some(large_value(i, 0)));
+    }
+    let pinned = value_log_files(temp_dir.path()).len();
+    drop(snapshot);
+    assert!(value_log_files(temp_dir.path()).len() < pinned);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check_history(&store)?;
+    store.compact()?;
+    check_history(&store)
+}
+
+// The value logs should be collected on their own once enough of them is garbage
+#[test]
+fn value_log_gc_in_background() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = separated_options()
+        .value_log_gc(ValueLogGcPolicy::GarbageRatio { ratio: 0.5, interval: Duration::from_millis(10) });
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for version in 0..10 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), large_value(i, version))?;
+        }
+    }
+    let written = value_logs_len(temp_dir.path());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while value_logs_len(temp_dir.path()) * 2 > written {
+        assert!(Instant::now() < deadline, "value logs were not collected");
+        thread::sleep(Duration::from_millis(10));
+    }
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(large_value(i, 9)));
+    }
+    Ok(())
+}
+
+// Sealed logs should be mapped once for all the clones of a store, so that the
+// files it keeps open do not grow with the number of threads reading it
+#[cfg(target_os = "linux")]
+#[test]
+fn shared_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Disabled);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    // sealed by the compaction, then overwritten in part in the active log
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("sealed{}", i))?;
+    }
+    store.compact()?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("active{}", i))?;
+    }
+
+    let dir = temp_dir.path().canonicalize()?;
+    let open_files = || {
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|fd| fs::read_link(fd.unwrap().path()).ok())
+            .filter(|target| target.starts_with(&dir))
+            .count()
+    };
+    let open_files_while_reading = |threads: usize| {
+        let barrier = Arc::new(Barrier::new(threads + 1));
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let expected = if i < 50 { format!("active{}", i) } else { format!("sealed{}", i) };
+                        assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(expected));
+                    }
+                    // the clones are kept until the files are counted
+                    barrier.wait();
+                    barrier.wait();
+                })
+            })
+            .collect();
+        barrier.wait();
+        let count = open_files();
+        barrier.wait();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        count
+    };
+    assert_eq!(open_files_while_reading(1), open_files_while_reading(32));
+    Ok(())
+}
+
+// Should open and upgrade a log written as JSON by older versions
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy_log = temp_dir.path().join("1.log");
+    fs::write(
+        &legacy_log,
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // the legacy log is rewritten in the binary format
+    assert!(!fs::read(&legacy_log)?.starts_with(b"{"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should number the records of a log written as JSON by older versions in log order
+#[test]
+fn open_legacy_json_log_numbers_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        concat!(
+            r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key1","value":"value2"}}"#,
+            r#"{"Rm":{"key":"key2"}}{"Set":{"key":"key2","value":"value1"}}"#,
+        ),
+    )?;
+
+    let options = KvStoreOptions::new().retention(RetentionPolicy::LastVersions(2));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        store.history("key1".to_owned())?,
+        vec![(1, Some("value1".to_owned())), (2, Some("value2".to_owned()))]
+    );
+    assert_eq!(store.history("key2".to_owned())?, vec![(3, None), (4, Some("value1".to_owned()))]);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.history("key1".to_owned())?[1], (5, Some("value3".to_owned())));
+
+    Ok(())
+}
+
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut logs: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("unable to list the store directory")
+        .map(|entry| entry.expect("unable to read directory entry").path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    logs.sort();
+    logs
+}
+
+fn value_log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut logs: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("unable to list the store directory")
+        .map(|entry| entry.expect("unable to read directory entry").path())
+        .filter(|path| path.extension() == Some("vlog".as_ref()))
+        .collect();
+    logs.sort();
+    logs
+}
+
+fn hint_files(dir: &Path) -> Vec<PathBuf> {
+    let mut hints: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("unable to list the store directory")
+        .map(|entry| entry.expect("unable to read directory entry").path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    hints.sort();
+    hints
+}
+
+fn compacted_store(dir: &Path) -> Result<()> {
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Disabled);
+    let store = KvStore::open_with_options(dir, options)?;
+    for iter in 0..2 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.compact()?;
+    Ok(())
+}
+
+// Open should read the positions from the hint file of a compacted log instead
+// of replaying it
+#[test]
+fn open_with_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compacted_store(temp_dir.path())?;
+
+    let hints = hint_files(temp_dir.path());
+    assert_eq!(hints.len(), 1);
+    // the compacted log holds the keys in order, "key0" first; damage its value,
+    // which a replay would refuse but the hint file does not look at.
+    let log = hints[0].with_extension("log");
+    let mut bytes = fs::read(&log)?;
+    bytes[13 + 4 + 25 + 4 + 5] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.get("key0".to_owned()),
+        Err(KvsError::Corruption { .. })
+    ));
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// Open should fall back to replaying the log if its hint file is damaged or missing
+#[test]
+fn open_with_bad_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compacted_store(temp_dir.path())?;
+
+    let hint = hint_files(temp_dir.path()).remove(0);
+    let mut bytes = fs::read(&hint)?;
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    fs::write(&hint, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value1".to_owned()));
+    }
+    drop(store);
+
+    fs::remove_file(&hint)?;
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// A half-written record at the end of a log should be truncated on open
+#[test]
+fn open_with_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = log_files(temp_dir.path()).remove(0);
+    let len = fs::metadata(&log)?.len();
+    // cut the last record in half
+    OpenOptions::new().write(true).open(&log)?.set_len(len - 5)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A damaged record in the middle of a log should fail the open
+#[test]
+fn open_with_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = log_files(temp_dir.path()).remove(0);
+    let mut bytes = fs::read(&log)?;
+    // the last byte of the first value
+    bytes[13 + 4 + 25 + 4 + 5] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, 13),
+        other => panic!("expected a corruption error, got {:?}", other.err()),
+    }
+
+    Ok(())
+}
+
+// Lists the ranges that `store` skipped when it was opened, by log.
+fn skipped_ranges(store: &KvStore) -> Vec<(u64, Range<u64>)> {
+    let skipped = &store.recovery_report().skipped;
+    skipped.iter().flat_map(|(&gen, ranges)| ranges.iter().map(move |range| (gen, range.clone()))).collect()
+}
+
+// Writes three records of 39 bytes each, at offsets 13, 52 and 91 of the first
+// log, then damages the log with `damage`.
+fn damaged_store(damage: impl FnOnce(&mut Vec<u8>)) -> Result<(TempDir, PathBuf)> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = log_files(temp_dir.path()).remove(0);
+    let mut bytes = fs::read(&log)?;
+    damage(&mut bytes);
+    fs::write(&log, bytes)?;
+    Ok((temp_dir, log))
+}
+
+// Should refuse to open a log whose record lengths are damaged rather than
+// take the rest of it for a torn tail
+#[test]
+fn open_with_damaged_record_length() -> Result<()> {
+    // the high byte of the value length of the first record
+    let (temp_dir, log) = damaged_store(|bytes| bytes[13 + 4 + 8] ^= 0x01)?;
+    let len = fs::metadata(&log)?.len();
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen, offset }) => assert_eq!((gen, offset), (1, 13)),
+        result => panic!("expected a corruption, got {:?}", result.map(|_| ())),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    Ok(())
+}
+
+// A damaged record should be truncated away with the records after it, and
+// reported, with `RecoveryMode::TruncateTail`
+#[test]
+fn recovery_truncate_tail() -> Result<()> {
+    // the last byte of the second value
+    let (temp_dir, log) = damaged_store(|bytes| bytes[90] ^= 0xff)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let options = KvStoreOptions::new().recovery_mode(RecoveryMode::TruncateTail);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(skipped_ranges(&store), [(1, 52..130)]);
+    assert_eq!(store.recovery_report().skipped_bytes(), 78);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(fs::metadata(&log)?.len(), 52);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    // the log is whole again
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().is_clean());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// A damaged record should be skipped, and reported, with
+// `RecoveryMode::SkipCorrupted`, the records after it being replayed
+#[test]
+fn recovery_skip_corrupted() -> Result<()> {
+    let options = KvStoreOptions::new().recovery_mode(RecoveryMode::SkipCorrupted);
+    // the last byte of the second value, then the high byte of its value length,
+    // which makes it look longer than the log
+    for damaged in [90, 64] {
+        let (temp_dir, _) = damaged_store(|bytes| bytes[damaged] ^= 0xff)?;
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(skipped_ranges(&store), [(1, 52..91)]);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        drop(store);
+
+        // the damaged record is left in the log until a compaction drops it
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.compact()?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(store.recovery_report().is_clean());
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// A record whose checksum matches but that does not decode should be left to
+// the recovery mode like a damaged one
+#[test]
+fn recovery_unknown_record_kind() -> Result<()> {
+    // an unknown kind for the second record, with its checksum updated
+    let (temp_dir, _) = damaged_store(|bytes| {
+        bytes[52 + 4] = 0x0f;
+        let crc = crc32fast::hash(&bytes[52 + 4..91]);
+        bytes[52..52 + 4].copy_from_slice(&crc.to_le_bytes());
+    })?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen, offset }) => assert_eq!((gen, offset), (1, 52)),
+        result => panic!("expected a corruption, got {:?}", result.map(|_| ())),
+    }
+
+    let options = KvStoreOptions::new().recovery_mode(RecoveryMode::SkipCorrupted);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(skipped_ranges(&store), [(1, 52..91)]);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A damaged batch should be skipped as a whole, its records included, and a
+// torn tail truncated away, with `RecoveryMode::SkipCorrupted`
+#[test]
+fn recovery_skip_corrupted_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key5".to_owned(), "value5".to_owned());
+    batch.set("key6".to_owned(), "value6".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log = log_files(temp_dir.path()).remove(0);
+    let mut bytes = fs::read(&log)?;
+    // the first batch starts at 52 with a header of 29 bytes, and its first
+    // record ends with its value.
+    let batch_end = 52 + 29 + 2 * 39;
+    bytes[52 + 29 + 38] ^= 0xff;
+    // cut the second batch short after its first record, which is whole
+    let torn = batch_end + 39 + 29 + 39;
+    bytes.truncate(torn as usize);
+    fs::write(&log, bytes)?;
+
+    let options = KvStoreOptions::new().recovery_mode(RecoveryMode::SkipCorrupted);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(skipped_ranges(&store), [(1, 52..batch_end), (1, batch_end + 39..torn)]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.get("key6".to_owned())?, None);
+    Ok(())
+}
+
+// A torn tail should be reported whatever the recovery mode
+#[test]
+fn recovery_report_torn_tail() -> Result<()> {
+    for mode in [RecoveryMode::Strict, RecoveryMode::TruncateTail, RecoveryMode::SkipCorrupted] {
+        let (temp_dir, _) = damaged_store(|bytes| bytes.truncate(120))?;
+        let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().recovery_mode(mode))?;
+        assert_eq!(skipped_ranges(&store), [(1, 91..120)]);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        drop(store);
+        assert!(KvStore::open(temp_dir.path())?.recovery_report().is_clean());
+    }
+    Ok(())
+}
+
+// A store should lock its directory until its last clone is dropped
+#[test]
+fn open_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked { pid }) => assert_eq!(pid, Some(std::process::id())),
+        result => panic!("expected the store to be locked, got {:?}", result.map(|_| ())),
+    }
+
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+// An LSM engine should lock its directory until its last clone is dropped
+#[test]
+fn lsm_open_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    let clone = engine.clone();
+    drop(engine);
+    match LsmKvsEngine::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked { pid }) => assert_eq!(pid, Some(std::process::id())),
+        result => panic!("expected the engine to be locked, got {:?}", result.map(|_| ())),
+    }
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::StoreLocked { .. })));
+
+    drop(clone);
+    let engine = LsmKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+// A read-only store should open next to a writer, and read the store as it was
+// when opened
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = KvStoreOptions::new().read_only(true);
+    assert!(KvStore::open_with_options(temp_dir.path().join("missing"), read_only.clone()).is_err());
+    assert!(!temp_dir.path().join("missing").exists());
+
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().compaction_policy(CompactionPolicy::Disabled))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let reader = KvStore::open_with_options(temp_dir.path(), read_only.clone())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(reader.set("key1".to_owned(), "value".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly)));
+    reader.sync()?;
+    let snapshot = reader.snapshot()?;
+
+    // the logs the reader opened are compacted away
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(collect_pairs(reader.scan(.., None)?)?.len(), 2);
+
+    let reader = KvStore::open_with_options(temp_dir.path(), read_only)?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A read-only store should leave a torn tail in place, as a write in progress
+#[test]
+fn open_read_only_torn_tail() -> Result<()> {
+    let (temp_dir, log) = damaged_store(|bytes| bytes.truncate(120))?;
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(skipped_ranges(&store), [(1, 91..120)]);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(fs::metadata(&log)?.len(), 120);
+    assert_eq!(log_files(temp_dir.path()).len(), 1);
+    Ok(())
+}
+
+// A read-only store should fail the reads of a log truncated under it, as the
+// writer of the directory does to a torn tail on open, rather than fault
+#[test]
+fn open_read_only_truncated_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let reader = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    let log = log_files(temp_dir.path()).remove(0);
+    OpenOptions::new().write(true).open(&log)?.set_len(0)?;
+    assert!(matches!(reader.get("key1".to_owned()), Err(KvsError::Corruption { .. })));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should refuse to return a value whose record fails its checksum
+#[test]
+fn get_corrupted_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let log = log_files(temp_dir.path()).remove(0);
+    let mut bytes = fs::read(&log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::Corruption { .. })
+    ));
+
+    Ok(())
+}
+
+// Writes 101 records with the given sync policy, without syncing explicitly, and
+// checks the syncs with `check_stats` before reopening the store.
+fn check_sync_policy(sync_policy: SyncPolicy, check_stats: impl Fn(&KvStore) -> Result<()>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(sync_policy);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    check_stats(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Waits for the sync thread to sync the writes of `store`.
+fn wait_for_interval_sync(store: &KvStore) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.stats().unsynced_writes > 0 {
+        assert!(Instant::now() < deadline, "the writes were never synced");
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(store.stats().syncs > 0);
+    Ok(())
+}
+
+#[test]
+fn sync_never() -> Result<()> {
+    check_sync_policy(SyncPolicy::Never, |store| {
+        assert_eq!((store.stats().syncs, store.stats().unsynced_writes), (0, 101));
+        // an explicit sync still syncs
+        store.sync()?;
+        assert_eq!((store.stats().syncs, store.stats().unsynced_writes), (1, 0));
+        Ok(())
+    })
+}
+
+#[test]
+fn sync_every_write() -> Result<()> {
+    check_sync_policy(SyncPolicy::EveryWrite, |store| {
+        assert_eq!((store.stats().syncs, store.stats().unsynced_writes), (101, 0));
+        Ok(())
+    })
+}
+
+#[test]
+fn sync_interval() -> Result<()> {
+    check_sync_policy(SyncPolicy::Interval(Duration::from_millis(10)), wait_for_interval_sync)?;
+    // a zero interval does not spin, but syncs all the same
+    check_sync_policy(SyncPolicy::Interval(Duration::ZERO), wait_for_interval_sync)
+}
+
+#[test]
+fn sync_batch() -> Result<()> {
+    check_sync_policy(SyncPolicy::Batch(16), |store| {
+        assert_eq!((store.stats().syncs, store.stats().unsynced_writes), (6, 5));
+        Ok(())
+    })
+}